@@ -0,0 +1,19 @@
+#!/bin/bash
+# Load tests a local server with headless bots: ./run-bots.sh [number of bots] [bot args...]
+# Extra server arguments can be given in SERVER_ARGS, e.g. SERVER_ARGS="--map assets/mymap.map"
+BOTS=${1:-32}
+[ $# -gt 0 ] && shift
+cargo build --release --no-default-features --bin server --bin bot || exit 1
+echo "Starting server for $BOTS players"
+SERVER_LOG=$(mktemp)
+cargo run --release --no-default-features --bin server -- --players "$BOTS" $SERVER_ARGS \
+    2> >(tee "$SERVER_LOG" >&2) &
+SERVER=$!
+trap 'kill $SERVER; rm -f "$SERVER_LOG"' EXIT
+# Waits until the server is listening however long the build and start take
+until grep -q "Server listening" "$SERVER_LOG"; do
+    kill -0 $SERVER 2>/dev/null || { echo "The server failed to start"; exit 1; }
+    sleep 0.2
+done
+echo "Starting $BOTS bots"
+cargo run --release --no-default-features --bin bot -- --bots "$BOTS" "$@"
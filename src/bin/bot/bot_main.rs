@@ -0,0 +1,385 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use laminar::{Config, Packet, SocketEvent};
+use legion::*;
+use log::{debug, error, info, warn};
+use mimalloc::MiMalloc;
+use structopt::StructOpt;
+use unnamed_rts::{
+    bot::{BotReport, OrderScript, TrafficStats},
+    clock_sync::ClockSync,
+    commands::CommandBatcher,
+    components::{Owner, PlayerId},
+    lockstep::NetworkMode,
+    resources::{
+        BadPacketLog, ClientRole, ClientUpdate, Command, NetworkSerialization, NetworkSocket,
+        ServerNotice, ServerUpdate, SessionToken, DEFAULT_SERVER_ADDR, DEFAULT_SERVER_PORT,
+    },
+    transfer::IncomingTransfers,
+};
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+/// Time between pings sent to the server, same as the client
+const PING_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bot",
+    about = "Headless bot clients for load testing the server"
+)]
+struct BotArgs {
+    /// Address of the server to connect to
+    #[structopt(long)]
+    server: Option<IpAddr>,
+    /// Port of the server to connect to
+    #[structopt(long)]
+    port: Option<u16>,
+    /// Number of bots to connect, each one is a separate player
+    #[structopt(long, default_value = "1")]
+    bots: u32,
+    /// Move orders issued by each bot per second
+    #[structopt(long, default_value = "1.0")]
+    order_rate: f32,
+    /// File with one `x z` move target per line that the bots cycle through,
+    /// the bots pick random targets without it
+    #[structopt(long, parse(from_os_str))]
+    script: Option<PathBuf>,
+    /// Seed of the random targets, each bot adds its index to it
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+    /// Seconds between the reported statistics
+    #[structopt(long, default_value = "5")]
+    report_interval: u64,
+    /// Stop the bots after this many seconds in game, runs until the server disconnects otherwise
+    #[structopt(long)]
+    duration: Option<u64>,
+}
+
+/// The game as seen by a bot after the handshake
+struct JoinedGame {
+    world: World,
+    player: PlayerId,
+    token: SessionToken,
+}
+
+struct Bot {
+    name: String,
+    socket: NetworkSocket,
+    server_addr: SocketAddr,
+    net_serialization: NetworkSerialization,
+    /// Given by the server in the Welcome, sent with every update after it
+    token: Option<SessionToken>,
+    bad_packets: BadPacketLog,
+    stats: TrafficStats,
+}
+
+impl Bot {
+    fn connect(name: String, server_addr: SocketAddr) -> Self {
+        let socket = NetworkSocket::bind_for_remote_with_config(
+            server_addr,
+            Config {
+                heartbeat_interval: Some(Duration::from_millis(1000)),
+                ..Default::default()
+            },
+            None,
+        );
+        Bot {
+            name,
+            socket,
+            server_addr,
+            net_serialization: NetworkSerialization::default(),
+            token: None,
+            bad_packets: BadPacketLog::default(),
+            stats: TrafficStats::new(Instant::now()),
+        }
+    }
+
+    fn send(&mut self, update: &ClientUpdate, reliable: bool) {
+        let payload = match self.token {
+            Some(token) => self
+                .net_serialization
+                .serialize_session_update(token, update),
+            None => self.net_serialization.serialize_client_update(update),
+        };
+        self.send_payload(payload, reliable);
+    }
+
+    fn send_payload(&mut self, payload: Vec<u8>, reliable: bool) {
+        self.stats.record_sent(payload.len());
+        let packet = if reliable {
+            Packet::reliable_unordered(self.server_addr, payload)
+        } else {
+            Packet::unreliable(self.server_addr, payload)
+        };
+        self.socket.sender.send(packet).unwrap();
+    }
+
+    /// Same handshake as the client, except that the map is never loaded
+    /// so it's reported as ready right away. Waits until the world has arrived.
+    fn join(&mut self) -> Result<JoinedGame> {
+        let start_game = ClientUpdate::StartGame {
+            name: self.name.clone(),
+            role: ClientRole::Player,
+        };
+        self.send(&start_game, true);
+        let mut player = None;
+        let mut world = None;
+        let mut transfers = IncomingTransfers::default();
+        loop {
+            if let (Some(token), Some(player), Some(world)) = (self.token, player, world.take()) {
+                return Ok(JoinedGame {
+                    world,
+                    player,
+                    token,
+                });
+            }
+            let packet = match self.socket.receiver.recv()? {
+                SocketEvent::Packet(packet) => packet,
+                SocketEvent::Timeout(_) | SocketEvent::Disconnect(_) => {
+                    return Err(anyhow!("Lost the connection to the server"))
+                }
+                SocketEvent::Connect(_) => continue,
+            };
+            self.stats.record_received(packet.payload().len());
+            match self
+                .net_serialization
+                .deserialize_server_update(packet.payload())
+            {
+                Ok(ServerUpdate::Welcome { token }) => self.token = Some(token),
+                Ok(ServerUpdate::MapInfo { name, .. }) => {
+                    debug!("{} skips loading map {}", self.name, name);
+                    self.send(&ClientUpdate::MapReady, true);
+                }
+                Ok(ServerUpdate::InitialState {
+                    player: id,
+                    network_mode,
+                }) => {
+                    if network_mode != NetworkMode::Snapshots {
+                        return Err(anyhow!("Bots can't play in {:?} mode", network_mode));
+                    }
+                    player =
+                        Some(id.ok_or_else(|| anyhow!("The server made the bot a spectator"))?);
+                }
+                Ok(ServerUpdate::Transfer(chunk)) => {
+                    let (ack, data) = transfers.receive(chunk)?;
+                    if let Some(ack) = ack {
+                        self.send(&ClientUpdate::TransferAck(ack), true);
+                    }
+                    if let Some(data) = data {
+                        match self.net_serialization.deserialize_server_update(&data)? {
+                            ServerUpdate::World { world: bytes } => {
+                                world = Some(self.net_serialization.deserialize_new_world(&bytes)?)
+                            }
+                            update => {
+                                warn!("{} got an unexpected transfer: {:?}", self.name, update)
+                            }
+                        }
+                    }
+                }
+                // The game is already running while the world arrives
+                Ok(ServerUpdate::State { .. } | ServerUpdate::Pong { .. }) => {}
+                Ok(ServerUpdate::Notice(ServerNotice::Kicked { reason })) => {
+                    return Err(anyhow!("Refused by the server: {}", reason))
+                }
+                Ok(update) => warn!(
+                    "{} got an unexpected server update: {:?}",
+                    self.name, update
+                ),
+                Err(err) => self.bad_packets.report(packet.addr(), &err),
+            }
+        }
+    }
+
+    /// Orders the bot's units around until the server disconnects or the deadline passes
+    fn play(
+        &mut self,
+        game: JoinedGame,
+        mut script: OrderScript,
+        args: &BotArgs,
+        reports: &Sender<BotReport>,
+    ) -> Result<()> {
+        let units: Vec<Entity> = <(Entity, &Owner)>::query()
+            .iter(&game.world)
+            .filter(|(_, owner)| owner.player == game.player)
+            .map(|(entity, _)| *entity)
+            .collect();
+        info!(
+            "{} plays as player {} with {} units",
+            self.name,
+            game.player,
+            units.len()
+        );
+        let start = Instant::now();
+        let deadline = args
+            .duration
+            .map(|duration| start + Duration::from_secs(duration));
+        let order_interval = Duration::from_secs_f32(1.0 / args.order_rate.max(0.001));
+        let report_interval = Duration::from_secs(args.report_interval.max(1));
+        let mut clock_sync = ClockSync::default();
+        let mut command_batcher = CommandBatcher::default();
+        let (mut next_ping, mut next_order, mut next_report) =
+            (start, start + order_interval, start + report_interval);
+        self.stats = TrafficStats::new(start);
+        loop {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Ok(());
+            }
+            match self.socket.receiver.recv_timeout(Duration::from_millis(1)) {
+                Ok(SocketEvent::Packet(packet)) => {
+                    self.stats.record_received(packet.payload().len());
+                    match self
+                        .net_serialization
+                        .deserialize_server_update(packet.payload())
+                    {
+                        Ok(ServerUpdate::State { tick, .. }) => {
+                            self.stats.record_snapshot();
+                            command_batcher.set_server_tick(tick);
+                        }
+                        Ok(ServerUpdate::Pong {
+                            sequence,
+                            server_time,
+                        }) => clock_sync.receive_pong(sequence, server_time, now),
+                        Ok(ServerUpdate::CommandsAck { sequence }) => {
+                            command_batcher.acknowledge(sequence)
+                        }
+                        Ok(ServerUpdate::Notice(ServerNotice::Kicked { reason })) => {
+                            return Err(anyhow!("Kicked: {}", reason))
+                        }
+                        Ok(ServerUpdate::Notice(ServerNotice::ShuttingDown)) => {
+                            info!("{} stops, the server is shutting down", self.name);
+                            return Ok(());
+                        }
+                        Ok(ServerUpdate::Notice(ServerNotice::MatchEnded)) => {
+                            info!("{} stops, the match has ended", self.name);
+                            return Ok(());
+                        }
+                        Ok(ServerUpdate::Notice(notice)) => info!("{}: {:?}", self.name, notice),
+                        Ok(
+                            ServerUpdate::Spawned { .. }
+                            | ServerUpdate::Chat { .. }
+                            | ServerUpdate::MapPing { .. },
+                        ) => {}
+                        Ok(update) => {
+                            warn!(
+                                "{} got an unexpected server update: {:?}",
+                                self.name, update
+                            )
+                        }
+                        Err(err) => self.bad_packets.report(packet.addr(), &err),
+                    }
+                }
+                Ok(SocketEvent::Timeout(_) | SocketEvent::Disconnect(_))
+                | Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Lost the connection to the server"))
+                }
+                Ok(SocketEvent::Connect(_)) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if now >= next_ping {
+                self.send(&clock_sync.ping(now), false);
+                next_ping = now + PING_INTERVAL;
+            }
+            if now >= next_order {
+                let target = script.next_target();
+                units.iter().for_each(|entity| {
+                    command_batcher.push(Command::Move {
+                        entity: *entity,
+                        target,
+                    })
+                });
+                self.stats.record_order();
+                next_order += order_interval;
+            }
+            let net_serialization = &self.net_serialization;
+            let payloads = command_batcher.flush(now, |batch| {
+                net_serialization.serialize_session_update(game.token, batch)
+            });
+            payloads
+                .into_iter()
+                .for_each(|payload| self.send_payload(payload, false));
+            if now >= next_report {
+                let rtt = clock_sync.is_synchronized().then_some(clock_sync.rtt);
+                let report = self.stats.report(now, rtt);
+                debug!("{}: {}", self.name, report);
+                // The main thread is gone when shutting down
+                let _ = reports.send(report);
+                next_report += report_interval;
+            }
+        }
+    }
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = BotArgs::from_args();
+    let script = args.script.as_ref().map(|path| {
+        std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| OrderScript::parse(&contents))
+    });
+    let script = match script.transpose() {
+        Ok(script) => script,
+        Err(err) => {
+            error!("Invalid order script: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let server_addr = SocketAddr::new(
+        args.server.unwrap_or(DEFAULT_SERVER_ADDR),
+        args.port.unwrap_or(DEFAULT_SERVER_PORT),
+    );
+    info!("Connecting {} bots to {}", args.bots, server_addr);
+    let args = std::sync::Arc::new(args);
+    let (report_sender, reports) = crossbeam_channel::unbounded();
+    let bots: Vec<_> = (0..args.bots)
+        .map(|index| {
+            let args = args.clone();
+            let script = script
+                .clone()
+                .unwrap_or_else(|| OrderScript::random(args.seed + index as u64));
+            let report_sender = report_sender.clone();
+            std::thread::spawn(move || {
+                let mut bot = Bot::connect(format!("Bot {}", index), server_addr);
+                let result = bot
+                    .join()
+                    .and_then(|game| bot.play(game, script, &args, &report_sender));
+                if let Err(err) = result {
+                    error!("{} stopped: {:#}", bot.name, err);
+                }
+            })
+        })
+        .collect();
+    drop(report_sender);
+
+    // Every bot reports once per interval, the summary of the reports is printed
+    // half an interval later to give all bots time to report
+    let report_interval = Duration::from_secs(args.report_interval.max(1));
+    let mut next_summary = Instant::now() + report_interval + report_interval / 2;
+    let mut round = Vec::with_capacity(args.bots as usize);
+    loop {
+        match reports.recv_deadline(next_summary) {
+            Ok(report) => round.push(report),
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(summary) = BotReport::aggregate(&round) {
+                    info!("{} bots: {}", round.len(), summary);
+                }
+                round.clear();
+                next_summary += report_interval;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    bots.into_iter().for_each(|bot| {
+        let _ = bot.join();
+    });
+}
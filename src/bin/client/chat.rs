@@ -0,0 +1,225 @@
+use std::{collections::VecDeque, time::Instant};
+
+use glam::Vec3A;
+use laminar::Packet;
+use legion::*;
+use unnamed_rts::{
+    chat::{ChatChannel, PingKind, MAX_CHAT_MESSAGE_LENGTH, PING_DURATION},
+    components::PlayerId,
+    input::{CursorPosition, MouseButtonState},
+    rendering::{camera::Camera, ui::ui_resources::UiContext},
+    resources::{ClientUpdate, NetworkSerialization, NetworkSocket, WindowSize, CHAT_STREAM},
+};
+use winit::event::MouseButton;
+
+use crate::{
+    client_network::{LocalPlayer, ServerConnection},
+    client_systems::ground_intersection,
+};
+
+/// Number of chat lines kept in the chat panel
+const CHAT_HISTORY: usize = 50;
+
+#[derive(Debug)]
+struct ChatLine {
+    sender: String,
+    player: Option<PlayerId>,
+    channel: ChatChannel,
+    text: String,
+}
+
+#[derive(Debug)]
+struct MapMarker {
+    sender: String,
+    position: Vec3A,
+    kind: PingKind,
+    received: Instant,
+}
+
+/// Chat messages and pings received from the server and the message being typed
+#[derive(Debug)]
+pub struct Chat {
+    lines: VecDeque<ChatLine>,
+    markers: Vec<MapMarker>,
+    input: String,
+    channel: ChatChannel,
+    ping_kind: PingKind,
+}
+
+impl Default for Chat {
+    fn default() -> Self {
+        Chat {
+            lines: VecDeque::with_capacity(CHAT_HISTORY),
+            markers: Vec::new(),
+            input: String::new(),
+            channel: ChatChannel::All,
+            ping_kind: PingKind::Attention,
+        }
+    }
+}
+
+impl Chat {
+    pub fn receive_message(
+        &mut self,
+        sender: String,
+        player: Option<PlayerId>,
+        channel: ChatChannel,
+        text: String,
+    ) {
+        if self.lines.len() == CHAT_HISTORY {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(ChatLine {
+            sender,
+            player,
+            channel,
+            text,
+        });
+    }
+
+    pub fn receive_ping(&mut self, sender: String, position: Vec3A, kind: PingKind) {
+        self.markers.push(MapMarker {
+            sender,
+            position,
+            kind,
+            received: Instant::now(),
+        });
+    }
+}
+
+fn ping_color(kind: PingKind) -> egui::Color32 {
+    match kind {
+        PingKind::Attention => egui::Color32::YELLOW,
+        PingKind::Attack => egui::Color32::RED,
+        PingKind::Defend => egui::Color32::LIGHT_BLUE,
+        PingKind::Danger => egui::Color32::from_rgb(255, 140, 0),
+    }
+}
+
+fn send_to_server(
+    network: &NetworkSocket,
+    server: &ServerConnection,
+    net_serialization: &NetworkSerialization,
+    update: &ClientUpdate,
+) {
+    let payload = net_serialization.serialize_session_update(server.token, update);
+    network
+        .sender
+        .send(Packet::reliable_ordered(
+            server.addr,
+            payload,
+            Some(CHAT_STREAM),
+        ))
+        .unwrap();
+}
+
+/// Chat panel with the received messages, sends the typed message on enter.
+/// Middle clicking the map pings the position for the team.
+#[system]
+#[allow(clippy::too_many_arguments)]
+pub fn chat_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] chat: &mut Chat,
+    #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
+    #[resource] net_serialization: &NetworkSerialization,
+    #[resource] local_player: &LocalPlayer,
+    #[resource] camera: &Camera,
+    #[resource] mouse_button_state: &MouseButtonState,
+    #[resource] mouse_pos: &CursorPosition,
+    #[resource] window_size: &WindowSize,
+) {
+    let mut send = false;
+    egui::Window::new("Chat")
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::Vec2::new(10.0, -10.0))
+        .resizable(false)
+        .collapsible(true)
+        .show(ui_context.context(), |ui| {
+            egui::ScrollArea::auto_sized().show(ui, |ui| {
+                for line in &chat.lines {
+                    let sender = match line.player {
+                        Some(player) => format!("{} (player {})", line.sender, player),
+                        None => format!("{} (spectator)", line.sender),
+                    };
+                    let channel = match line.channel {
+                        ChatChannel::All => "",
+                        ChatChannel::Team => "[Team] ",
+                    };
+                    ui.label(format!("{}{}: {}", channel, sender, line.text));
+                }
+            });
+            ui.horizontal(|ui| {
+                // Spectators only reach other spectators whatever the channel
+                if local_player.id.is_some() {
+                    ui.selectable_value(&mut chat.channel, ChatChannel::All, "All");
+                    ui.selectable_value(&mut chat.channel, ChatChannel::Team, "Team");
+                }
+                let response = ui.text_edit_singleline(&mut chat.input);
+                // The server cuts longer messages anyway
+                if let Some((end, _)) = chat.input.char_indices().nth(MAX_CHAT_MESSAGE_LENGTH) {
+                    chat.input.truncate(end);
+                }
+                send = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            });
+            let ping_kind = &mut chat.ping_kind;
+            egui::ComboBox::from_label("Middle click ping")
+                .selected_text(format!("{:?}", ping_kind))
+                .show_ui(ui, |ui| {
+                    for kind in PingKind::ALL.iter() {
+                        ui.selectable_value(ping_kind, *kind, format!("{:?}", kind));
+                    }
+                });
+        });
+    if send && !chat.input.trim().is_empty() {
+        let chat_message = ClientUpdate::Chat {
+            channel: chat.channel,
+            text: std::mem::take(&mut chat.input),
+        };
+        send_to_server(network, server, net_serialization, &chat_message);
+    }
+    if mouse_button_state.pressed_current_frame(&MouseButton::Middle) {
+        if let Some(position) = ground_intersection(camera, mouse_pos, window_size) {
+            let ping = ClientUpdate::MapPing {
+                position,
+                kind: chat.ping_kind,
+            };
+            send_to_server(network, server, net_serialization, &ping);
+        }
+    }
+}
+
+/// Marks the pinged positions on the map, the markers shrink and fade until they expire
+#[system]
+pub fn draw_ping_markers(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] chat: &mut Chat,
+    #[resource] camera: &Camera,
+    #[resource] window_size: &WindowSize,
+) {
+    chat.markers
+        .retain(|marker| marker.received.elapsed() < PING_DURATION);
+    let painter = ui_context
+        .context()
+        .layer_painter(egui::LayerId::background());
+    for marker in &chat.markers {
+        // The camera projects to physical pixels while egui paints in logical points
+        let screen_pos = match camera.project(marker.position, window_size) {
+            Some(screen_pos) => egui::pos2(
+                screen_pos.x as f32 / window_size.scale_factor,
+                screen_pos.y as f32 / window_size.scale_factor,
+            ),
+            None => continue,
+        };
+        let remaining = 1.0 - marker.received.elapsed().as_secs_f32() / PING_DURATION.as_secs_f32();
+        let color = ping_color(marker.kind).linear_multiply(remaining.max(0.2));
+        painter.circle_stroke(screen_pos, 8.0 + 16.0 * remaining, (2.0, color));
+        painter.circle_filled(screen_pos, 3.0, color);
+        painter.text(
+            screen_pos + egui::vec2(0.0, -28.0),
+            egui::Align2::CENTER_BOTTOM,
+            format!("{}: {:?}", marker.sender, marker.kind),
+            egui::TextStyle::Body,
+            color,
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use structopt::StructOpt;
+use unnamed_rts::{
+    link_conditioner::{LinkConditioner, LinkConditionerArgs},
+    resources::{ClientRole, DEFAULT_SERVER_ADDR, DEFAULT_SERVER_PORT},
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "client", about = "Unnamed rts game client")]
+struct ClientArgs {
+    /// Toml config file, arguments given on the command line takes precedence over it
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address of the server to connect to
+    #[structopt(long)]
+    server: Option<IpAddr>,
+    /// Port of the server to connect to
+    #[structopt(long)]
+    port: Option<u16>,
+    /// Name shown to the other players
+    #[structopt(long)]
+    name: Option<String>,
+    /// Watch the match as a spectator instead of playing
+    #[structopt(long)]
+    spectate: bool,
+    #[structopt(flatten)]
+    link_conditioner: LinkConditionerArgs,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server: IpAddr,
+    pub port: u16,
+    pub name: String,
+    /// Watch the match as a spectator instead of playing
+    pub spectate: bool,
+    /// Simulates a bad network connection, used for testing
+    pub link_conditioner: Option<LinkConditioner>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server: DEFAULT_SERVER_ADDR,
+            port: DEFAULT_SERVER_PORT,
+            name: "Player".to_string(),
+            spectate: false,
+            link_conditioner: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Loads the config from the command line arguments and the optional config file
+    pub fn load() -> Result<Self> {
+        let args = ClientArgs::from_args();
+        let mut config = match &args.config {
+            Some(path) => ClientConfig::from_file(path)?,
+            None => ClientConfig::default(),
+        };
+        if let Some(server) = args.server {
+            config.server = server;
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(name) = args.name {
+            config.name = name;
+        }
+        config.spectate |= args.spectate;
+        config.link_conditioner = args.link_conditioner.apply(config.link_conditioner)?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    #[inline]
+    pub fn role(&self) -> ClientRole {
+        if self.spectate {
+            ClientRole::Spectator
+        } else {
+            ClientRole::Player
+        }
+    }
+
+    #[inline]
+    pub fn server_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server, self.port)
+    }
+}
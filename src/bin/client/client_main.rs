@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate log;
 
+use client_config::ClientConfig;
 use futures::executor::block_on;
 use game_state::GameState;
 use mimalloc::MiMalloc;
@@ -11,9 +12,16 @@ use winit::{
     window::WindowBuilder,
 };
 
+mod chat;
+mod client_config;
 mod client_network;
 mod client_systems;
 mod game_state;
+mod lockstep;
+mod map_download;
+mod network_panel;
+mod prediction;
+mod spectator;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -22,6 +30,13 @@ fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
+    let config = match ClientConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid client configuration: {:#}", err);
+            std::process::exit(1);
+        }
+    };
     let event_loop = EventLoop::new();
 
     let window = WindowBuilder::new()
@@ -29,7 +44,7 @@ fn main() {
         .build(&event_loop)
         .expect("Failed to create window");
     let mut app = block_on(Engine::new(&window));
-    app.push_state(Box::new(GameState {}) as Box<dyn State>);
+    app.push_state(Box::new(GameState::new(config)) as Box<dyn State>);
     event_loop.run(move |event, _, control_flow| {
         if !app.event_handler(&event) {
             match event {
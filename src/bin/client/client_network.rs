@@ -1,47 +1,301 @@
+use anyhow::{anyhow, Context, Result};
 use glam::Vec3;
 use laminar::{Config, Packet, SocketEvent};
 use legion::{systems::CommandBuffer, world::SubWorld, EntityStore, *};
 use log::{error, info, warn};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use unnamed_rts::{
-    components::{EntityType, Selectable, Transform},
+    clock_sync::ClockSync,
+    commands::CommandBatcher,
+    components::{EntityType, Hidden, Owner, PlayerId, Selectable, Transform, Velocity},
+    desync::{self, EntityDiff, ReplicatedState},
+    game_time::GameTime,
     resources::{
-        ClientUpdate, NetworkSerialization, NetworkSocket, ServerUpdate, SERVER_ADDR, SERVER_PORT,
+        BadPacketLog, ClientUpdate, NetworkSerialization, NetworkSocket, ServerNotice,
+        ServerUpdate, SessionToken,
     },
+    transfer::{IncomingTransfers, TransferChunk},
+    transform_encoding::MapBounds,
 };
 
+use crate::{
+    chat::Chat,
+    client_config::ClientConfig,
+    lockstep::LockstepQueue,
+    map_download::{find_local_map, MapDownload},
+    prediction::Predicted,
+};
 use unnamed_rts::{
     assets::Handle,
     rendering::{gltf::GltfModel, lights::PointLight},
 };
 
-pub fn connect_to_server(world: &mut World, resources: &mut Resources) {
-    let socket = NetworkSocket::bind_any_with_config(Config {
-        heartbeat_interval: Some(Duration::from_millis(1000)),
-        ..Default::default()
-    });
-    // Tell server to start the game
-    let serialized = bincode::serialize(&ClientUpdate::StartGame {
-        ip: socket.ip,
-        port: socket.port,
-    })
-    .expect("Serilization to work");
-    let packet =
-        Packet::reliable_unordered(SocketAddr::new(SERVER_ADDR.into(), SERVER_PORT), serialized);
+/// The player controlled by this client
+#[derive(Debug, Clone, Copy)]
+pub struct LocalPlayer {
+    /// None when spectating
+    pub id: Option<PlayerId>,
+}
+
+/// The server the client is connected to
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConnection {
+    pub addr: SocketAddr,
+    /// Given by the server in the Welcome, sent with every update
+    pub token: SessionToken,
+}
+
+/// What the server has told the player about its state, see `ServerNotice`
+#[derive(Debug, Default)]
+pub struct ServerStatus {
+    pub paused: bool,
+    /// Set once the server has kicked the player or shut down
+    pub disconnect_reason: Option<String>,
+}
+
+impl ServerStatus {
+    fn receive(&mut self, notice: ServerNotice) {
+        match notice {
+            ServerNotice::Paused => {
+                info!("The server paused the game");
+                self.paused = true;
+            }
+            ServerNotice::Resumed => {
+                info!("The server resumed the game");
+                self.paused = false;
+            }
+            ServerNotice::Kicked { reason } => {
+                warn!("Kicked from the server: {}", reason);
+                self.disconnect_reason = Some(format!("Kicked from the server: {}", reason));
+            }
+            ServerNotice::MatchEnded => {
+                info!("The match has ended");
+                self.disconnect_reason = Some("The match has ended".to_string());
+            }
+            ServerNotice::ShuttingDown => {
+                warn!("The server is shutting down");
+                self.disconnect_reason = Some("The server has shut down".to_string());
+            }
+        }
+    }
+}
+
+/// Large updates the server sends in chunks. The world arrives this way once the match
+/// has started, nothing is simulated until it has been loaded.
+#[derive(Debug, Default)]
+pub struct ServerTransfers {
+    incoming: IncomingTransfers,
+    world_loaded: bool,
+}
+
+impl ServerTransfers {
+    /// Adds the chunk and acknowledges it when it's time to,
+    /// returns the transferred update once it's complete
+    fn receive(
+        &mut self,
+        chunk: TransferChunk,
+        network: &NetworkSocket,
+        server: &ServerConnection,
+        net_serialization: &NetworkSerialization,
+    ) -> Result<Option<ServerUpdate>> {
+        let (ack, data) = self.incoming.receive(chunk)?;
+        if let Some(ack) = ack {
+            let payload = net_serialization
+                .serialize_session_update(server.token, &ClientUpdate::TransferAck(ack));
+            network
+                .sender
+                .send(Packet::reliable_unordered(server.addr, payload))
+                .unwrap();
+        }
+        data.map(|data| net_serialization.deserialize_server_update(&data))
+            .transpose()
+    }
+
+    #[inline]
+    pub fn is_world_loaded(&self) -> bool {
+        self.world_loaded
+    }
+
+    /// Fraction of the transfer in progress that has been received
+    #[inline]
+    pub fn progress(&self) -> Option<f32> {
+        self.incoming.progress()
+    }
+}
+
+/// Adds the world sent by the server, returns the added entities
+fn load_world(
+    world: &mut World,
+    net_serialization: &NetworkSerialization,
+    world_bytes: &[u8],
+) -> Result<Vec<Entity>> {
+    let mut new_world = net_serialization.deserialize_new_world(world_bytes)?;
+    let entities = <Entity>::query().iter(&new_world).copied().collect();
+    world.move_from(&mut new_world, &any());
+    Ok(entities)
+}
+
+/// Connects to the server and waits for the game to start. Downloads the map from the server
+/// if it's missing locally. Returns the path to the map relative to the asset directory, or
+/// why the client couldn't join.
+pub fn connect_to_server(
+    world: &mut World,
+    resources: &mut Resources,
+    config: &ClientConfig,
+) -> Result<PathBuf> {
+    let server_addr = config.server_addr();
+    info!(
+        "Connecting to server at {} as {} ({:?})",
+        server_addr,
+        config.name,
+        config.role()
+    );
+    let socket = NetworkSocket::bind_for_remote_with_config(
+        server_addr,
+        Config {
+            heartbeat_interval: Some(Duration::from_millis(1000)),
+            ..Default::default()
+        },
+        config.link_conditioner,
+    );
     let net_serialization = resources.get::<NetworkSerialization>().unwrap();
-    socket.sender.send(packet).unwrap();
-    // wait for initial game state
+    let send_to_server = |token: Option<SessionToken>, update: &ClientUpdate| {
+        let payload = match token {
+            Some(token) => net_serialization.serialize_session_update(token, update),
+            None => net_serialization.serialize_client_update(update),
+        };
+        socket
+            .sender
+            .send(Packet::reliable_unordered(server_addr, payload))
+            .unwrap();
+    };
+    // Tell server to start the game
+    send_to_server(
+        None,
+        &ClientUpdate::StartGame {
+            name: config.name.clone(),
+            role: config.role(),
+        },
+    );
+    // Everything after the StartGame is sent with the token from the Welcome
+    let missing_token = || anyhow!("The server didn't send the session token first");
+    let mut bad_packets = BadPacketLog::default();
+    let mut map_download: Option<MapDownload> = None;
+    let mut map_path = None;
+    let mut token = None;
+    let mut local_player = None;
+    let mut network_mode = None;
+    // The world may start arriving before the initial state since it's on another stream
+    let mut transfers = ServerTransfers::default();
+    // wait for the map and the initial game state
     for event in socket.receiver.iter() {
         match event {
             SocketEvent::Packet(packet) => {
-                if let Ok(mut initial_state) =
-                    net_serialization.deserialize_new_world(packet.payload())
-                {
-                    world.move_from(&mut initial_state, &any());
-                    break;
-                } else {
-                    warn!("Unexpected server packet: Expected initial state")
+                match net_serialization.deserialize_server_update(packet.payload()) {
+                    Ok(ServerUpdate::Transfer(chunk)) => {
+                        let server = ServerConnection {
+                            addr: server_addr,
+                            token: token.ok_or_else(missing_token)?,
+                        };
+                        match transfers.receive(chunk, &socket, &server, &net_serialization) {
+                            Ok(Some(ServerUpdate::World { world: world_bytes })) => {
+                                let entities = load_world(world, &net_serialization, &world_bytes)
+                                    .context("Failed to load the world sent by the server")?;
+                                info!("Loaded the world with {} entities", entities.len());
+                                transfers.world_loaded = true;
+                            }
+                            Ok(Some(update)) => warn!("Unexpected transfer: {:?}", update),
+                            Ok(None) => {}
+                            Err(err) => bad_packets.report(packet.addr(), &err),
+                        }
+                    }
+                    Ok(ServerUpdate::Welcome {
+                        token: welcome_token,
+                    }) => {
+                        token = Some(welcome_token);
+                    }
+                    Ok(ServerUpdate::MapInfo { name, hash, size }) => {
+                        let token = Some(token.ok_or_else(missing_token)?);
+                        if let Some(path) = find_local_map(&name, hash) {
+                            info!("Found map {} locally at: {}", name, path.display());
+                            map_path = Some(path);
+                            send_to_server(token, &ClientUpdate::MapReady);
+                        } else {
+                            info!("Map {} is missing, downloading {} bytes", name, size);
+                            let mut download = MapDownload::new(hash, size);
+                            if let Some(request) = download.next_request() {
+                                send_to_server(token, &request);
+                            }
+                            map_download = Some(download);
+                        }
+                    }
+                    Ok(ServerUpdate::MapChunk { index, bytes }) => {
+                        let download = match map_download.as_mut() {
+                            Some(download) => download,
+                            None => {
+                                warn!("Unexpected map chunk, no map download in progress");
+                                continue;
+                            }
+                        };
+                        // The chunks arrive in order, the download can't recover from a bad one
+                        download
+                            .push_chunk(index, &bytes)
+                            .context("Map download failed")?;
+                        if download.is_complete() {
+                            let path = map_download
+                                .take()
+                                .unwrap()
+                                .finish()
+                                .context("Failed to store the downloaded map")?;
+                            info!("Map downloaded to: {}", path.display());
+                            map_path = Some(path);
+                            send_to_server(token, &ClientUpdate::MapReady);
+                        } else if let Some(request) = download.next_request() {
+                            send_to_server(token, &request);
+                        }
+                    }
+                    Ok(ServerUpdate::InitialState {
+                        player,
+                        network_mode: mode,
+                    }) => {
+                        match player {
+                            Some(player) => {
+                                info!("Playing as player {} in {:?} mode", player, mode)
+                            }
+                            None => info!("Spectating in {:?} mode", mode),
+                        }
+                        network_mode = Some(mode);
+                        local_player = Some(LocalPlayer { id: player });
+                        // The game shows the progress while the rest of the world arrives
+                        break;
+                    }
+                    Ok(ServerUpdate::Notice(ServerNotice::Kicked { reason })) => {
+                        return Err(anyhow!(
+                            "The server refused to let the client join: {}",
+                            reason
+                        ));
+                    }
+                    Ok(
+                        ServerUpdate::State { .. }
+                        | ServerUpdate::Pong { .. }
+                        | ServerUpdate::Turn { .. }
+                        | ServerUpdate::Resync { .. }
+                        | ServerUpdate::World { .. }
+                        | ServerUpdate::CommandsAck { .. }
+                        | ServerUpdate::Notice(_)
+                        | ServerUpdate::Spawned { .. }
+                        | ServerUpdate::Chat { .. }
+                        | ServerUpdate::MapPing { .. },
+                    ) => {
+                        warn!("Unexpected server packet: Expected initial state")
+                    }
+                    Err(err) => bad_packets.report(packet.addr(), &err),
                 }
             }
             SocketEvent::Connect(addr) => {
@@ -52,6 +306,20 @@ pub fn connect_to_server(world: &mut World, resources: &mut Resources) {
     }
     drop(net_serialization);
     resources.insert(socket);
+    resources.insert(bad_packets);
+    let token = token.ok_or_else(missing_token)?;
+    resources.insert(ServerConnection {
+        addr: server_addr,
+        token,
+    });
+    resources.insert(ClockSync::default());
+    resources.insert(CommandBatcher::default());
+    resources.insert(ServerStatus::default());
+    resources.insert(GameTime::default());
+    resources.insert(transfers);
+    resources.insert(local_player.expect("The game started without a player id"));
+    resources.insert(network_mode.expect("The game started without a network mode"));
+    Ok(map_path.expect("The game started before the map was synced"))
 }
 
 pub fn add_client_components(
@@ -59,6 +327,8 @@ pub fn add_client_components(
     resources: &mut Resources,
     model: &Handle<GltfModel>,
 ) {
+    resources.insert(UnitModel(*model));
+    resources.insert(ResyncState::default());
     let mut query = <(Entity, Read<EntityType>)>::query();
     let mut command_buffer = CommandBuffer::new(world);
     for (entity, _entity_type) in query.iter(world) {
@@ -84,32 +354,291 @@ pub fn add_client_components(
     command_buffer.flush(world, resources);
 }
 
+/// Whether the client has asked the server for its full state
+#[derive(Debug, Default)]
+pub struct ResyncState {
+    requested: bool,
+}
+
+/// The model used for units created during a resync
+#[derive(Debug, Clone, Copy)]
+pub struct UnitModel(pub Handle<GltfModel>);
+
+/// The client's replicated state of the entity. Predicted units use the latest
+/// server transform since their own transform runs ahead of the server.
+fn client_state(world: &SubWorld, entity: Entity) -> Option<ReplicatedState> {
+    let entry = world.entry_ref(entity).ok()?;
+    let transform = match entry.get_component::<Predicted>() {
+        Ok(predicted) => predicted.server_transform(),
+        Err(_) => *entry.get_component::<Transform>().ok()?,
+    };
+    ReplicatedState::read(world, entity, transform)
+}
+
+/// Overwrites the client's state with the server's where they differ
+fn apply_resync(world: &mut SubWorld, command_buffer: &mut CommandBuffer, diffs: Vec<EntityDiff>) {
+    for diff in diffs {
+        match diff {
+            EntityDiff::Mismatch { server, .. } => {
+                let mut entry = match world.entry_mut(server.entity) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if let Ok(predicted) = entry.get_component_mut::<Predicted>() {
+                    predicted.set_authoritative(server.transform);
+                } else if let Ok(transform) = entry.get_component_mut::<Transform>() {
+                    *transform = server.transform;
+                }
+                command_buffer.add_component(server.entity, server.entity_type);
+                match server.owner {
+                    Some(owner) => command_buffer.add_component(server.entity, owner),
+                    None => command_buffer.remove_component::<Owner>(server.entity),
+                }
+            }
+            EntityDiff::Missing(server) => spawn_replicated(command_buffer, server),
+        }
+    }
+}
+
+/// Creates an entity the server replicates but the client doesn't have yet
+fn spawn_replicated(command_buffer: &mut CommandBuffer, server: ReplicatedState) {
+    // The entity id was reserved when its network id was deserialized
+    command_buffer.exec_mut(move |world, resources| {
+        world.push_with_id(
+            server.entity,
+            (
+                server.entity_type,
+                server.transform,
+                Velocity {
+                    velocity: Vec3::ZERO,
+                },
+                Selectable::default(),
+            ),
+        );
+        let mut entry = world.entry(server.entity).unwrap();
+        if let Some(owner) = server.owner {
+            entry.add_component(owner);
+        }
+        if let Some(model) = resources.get::<UnitModel>() {
+            entry.add_component(model.0);
+        }
+    });
+}
+
 // If this ever leads to problems (SubWorld for example not including all the necessary entities)
 // Then revert to taking entire world and resources as args instead of having this as a system. Then put
 // it on_foreground tick instead
 #[system]
+#[read_component(Owner)]
+#[allow(clippy::too_many_arguments)]
 pub fn server_update(
     world: &mut SubWorld,
     #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
     #[resource] net_serialization: &NetworkSerialization,
-    _query: &mut Query<&mut Transform>,
+    #[resource] bad_packets: &mut BadPacketLog,
+    #[resource] clock_sync: &mut ClockSync,
+    #[resource] lockstep_queue: &mut LockstepQueue,
+    #[resource] resync: &mut ResyncState,
+    #[resource] command_batcher: &mut CommandBatcher,
+    #[resource] server_status: &mut ServerStatus,
+    #[resource] game_time: &mut GameTime,
+    #[resource] local_player: &LocalPlayer,
+    #[resource] chat: &mut Chat,
+    #[resource] transfers: &mut ServerTransfers,
+    #[resource] map_bounds: &MapBounds,
+    command_buffer: &mut CommandBuffer,
+    _query: &mut Query<(&mut Transform, &mut Predicted)>,
+    replicated: &mut Query<(
+        Entity,
+        &EntityType,
+        Option<&Hidden>,
+        Option<&mut Selectable>,
+    )>,
 ) {
     for event in network.receiver.try_iter() {
         match event {
             SocketEvent::Packet(packet) => {
-                let ServerUpdate::State { transforms } =
-                    net_serialization.deserialize_server_update(packet.payload());
-                // Safety: there must be a unique entity id per element in the update which is currently
-                // guarenteed by the server query that creates the transform vec
-                transforms
-                    .into_par_iter()
-                    .for_each(|(entity, new_transform)| {
-                        let entry = world.entry_ref(entity).unwrap();
-                        unsafe {
-                            let transform = entry.get_component_unchecked::<Transform>().unwrap();
-                            *transform = new_transform;
+                let update = match net_serialization.deserialize_server_update(packet.payload()) {
+                    Ok(ServerUpdate::Transfer(chunk)) => {
+                        match transfers.receive(chunk, network, server, net_serialization) {
+                            Ok(Some(update)) => Ok(update),
+                            Ok(None) => continue,
+                            Err(err) => Err(err),
+                        }
+                    }
+                    update => update,
+                };
+                match update {
+                    // The entities are unknown until the world has been loaded, the
+                    // checksums request a resync if anything was missed in the meantime
+                    Ok(ServerUpdate::State { .. } | ServerUpdate::Spawned { .. })
+                        if !transfers.world_loaded => {}
+                    Ok(ServerUpdate::World { world: world_bytes }) => {
+                        command_buffer.exec_mut(move |world, resources| {
+                            let net_serialization =
+                                resources.get::<NetworkSerialization>().unwrap();
+                            let entities = match load_world(world, &net_serialization, &world_bytes)
+                            {
+                                Ok(entities) => entities,
+                                Err(err) => {
+                                    error!("Failed to load the world: {}", err);
+                                    // Nothing can be shown without the world
+                                    resources
+                                        .get_mut::<ServerStatus>()
+                                        .unwrap()
+                                        .disconnect_reason =
+                                        Some("The world sent by the server is broken".to_string());
+                                    return;
+                                }
+                            };
+                            info!("Loaded the world with {} entities", entities.len());
+                            let model = resources.get::<UnitModel>().unwrap().0;
+                            for entity in entities {
+                                let mut entry = world.entry(entity).unwrap();
+                                entry.add_component(model);
+                                entry.add_component(Selectable::default());
+                            }
+                            resources.get_mut::<ServerTransfers>().unwrap().world_loaded = true;
+                        });
+                    }
+                    Ok(ServerUpdate::State {
+                        tick,
+                        game_time: server_time,
+                        transforms,
+                        checksum,
+                    }) => {
+                        let transforms =
+                            match net_serialization.decode_transforms(&transforms, map_bounds) {
+                                Ok(transforms) => transforms,
+                                Err(err) => {
+                                    bad_packets.report(packet.addr(), &err);
+                                    continue;
+                                }
+                            };
+                        command_batcher.set_server_tick(tick);
+                        game_time.synchronize(&server_time);
+                        let relevant: HashSet<Entity> =
+                            transforms.iter().map(|(entity, _)| *entity).collect();
+                        // Safety: there must be a unique entity id per element in the update which is currently
+                        // guarenteed by the server query that creates the transform vec
+                        transforms
+                            .into_par_iter()
+                            .for_each(|(entity, new_transform)| {
+                                let entry = match world.entry_ref(entity) {
+                                    Ok(entry) => entry,
+                                    Err(err) => {
+                                        warn!("Ignoring state of {:?}: {}", entity, err);
+                                        return;
+                                    }
+                                };
+                                unsafe {
+                                    // Predicted units are reconciled with the authoritative state instead
+                                    if let Ok(predicted) =
+                                        entry.get_component_unchecked::<Predicted>()
+                                    {
+                                        predicted.set_authoritative(new_transform);
+                                    } else {
+                                        let transform =
+                                            entry.get_component_unchecked::<Transform>().unwrap();
+                                        *transform = new_transform;
+                                    }
+                                }
+                            });
+                        // The server only sends the entities relevant to this client,
+                        // hide the rest until they become relevant again. Spectators get
+                        // everything, what they see is up to their perspective.
+                        if local_player.id.is_some() {
+                            replicated.for_each_mut(
+                                world,
+                                |(entity, _, hidden, selectable)| match (
+                                    relevant.contains(entity),
+                                    hidden.is_some(),
+                                ) {
+                                    (true, true) => {
+                                        command_buffer.remove_component::<Hidden>(*entity)
+                                    }
+                                    (false, false) => {
+                                        command_buffer.add_component(*entity, Hidden);
+                                        if let Some(selectable) = selectable {
+                                            selectable.is_selected = false;
+                                        }
+                                    }
+                                    _ => {}
+                                },
+                            );
+                        }
+                        let matches_server = checksum.is_none_or(|checksum| {
+                            checksum
+                                == desync::state_checksum(relevant.iter().filter_map(|entity| {
+                                    client_state(world, *entity).map(|state| {
+                                        state.hash(net_serialization.network_id(*entity))
+                                    })
+                                }))
+                        });
+                        if !matches_server && !resync.requested {
+                            warn!(
+                                "State checksum mismatch after tick {}, requesting a resync",
+                                tick
+                            );
+                            let payload = net_serialization.serialize_session_update(
+                                server.token,
+                                &ClientUpdate::RequestResync,
+                            );
+                            network
+                                .sender
+                                .send(Packet::reliable_unordered(server.addr, payload))
+                                .unwrap();
+                            resync.requested = true;
                         }
-                    });
+                    }
+                    Ok(ServerUpdate::Resync { tick, entities }) => {
+                        resync.requested = false;
+                        let diffs = desync::diff(&entities, |entity| client_state(world, entity));
+                        if diffs.is_empty() {
+                            info!("Resynced after tick {} without differences", tick);
+                        } else {
+                            warn!("Resynced after tick {}, differences:", tick);
+                            diffs.iter().for_each(|diff| warn!("  {}", diff));
+                        }
+                        apply_resync(world, command_buffer, diffs);
+                    }
+                    Ok(ServerUpdate::Pong {
+                        sequence,
+                        server_time,
+                    }) => clock_sync.receive_pong(sequence, server_time, Instant::now()),
+                    Ok(ServerUpdate::Turn { turn, commands }) => {
+                        lockstep_queue.push_turn(turn, commands)
+                    }
+                    Ok(ServerUpdate::CommandsAck { sequence }) => {
+                        command_batcher.acknowledge(sequence)
+                    }
+                    Ok(ServerUpdate::Spawned { entities }) => {
+                        for state in entities {
+                            if world.entry_ref(state.entity).is_err() {
+                                spawn_replicated(command_buffer, state);
+                            }
+                        }
+                    }
+                    Ok(ServerUpdate::Notice(notice)) => {
+                        server_status.receive(notice);
+                        game_time.set_paused(server_status.paused);
+                    }
+                    Ok(ServerUpdate::Chat {
+                        sender,
+                        player,
+                        channel,
+                        text,
+                    }) => chat.receive_message(sender, player, channel, text),
+                    Ok(ServerUpdate::MapPing {
+                        sender,
+                        position,
+                        kind,
+                        ..
+                    }) => chat.receive_ping(sender, position, kind),
+                    Ok(update) => warn!("Unexpected server update: {:?}", update),
+                    Err(err) => bad_packets.report(packet.addr(), &err),
+                }
             }
             SocketEvent::Connect(addr) => {
                 info!("Connected to server at: {}", addr);
@@ -123,3 +652,46 @@ pub fn server_update(
         }
     }
 }
+
+/// Time between pings sent to the server
+const PING_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Pings the server to keep the round trip time and clock offset estimates up to date
+#[system]
+pub fn ping(
+    #[state] last_ping: &mut Option<Instant>,
+    #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
+    #[resource] net_serialization: &NetworkSerialization,
+    #[resource] clock_sync: &mut ClockSync,
+) {
+    let now = Instant::now();
+    if last_ping.is_some_and(|last_ping| now - last_ping < PING_INTERVAL) {
+        return;
+    }
+    let payload = net_serialization.serialize_session_update(server.token, &clock_sync.ping(now));
+    network
+        .sender
+        .send(Packet::unreliable(server.addr, payload))
+        .unwrap();
+    *last_ping = Some(now);
+}
+
+/// Sends the commands issued this frame and resends the ones the server hasn't acknowledged
+#[system]
+pub fn send_commands(
+    #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
+    #[resource] net_serialization: &NetworkSerialization,
+    #[resource] command_batcher: &mut CommandBatcher,
+) {
+    let payloads = command_batcher.flush(Instant::now(), |batch| {
+        net_serialization.serialize_session_update(server.token, batch)
+    });
+    for payload in payloads {
+        network
+            .sender
+            .send(Packet::unreliable(server.addr, payload))
+            .unwrap();
+    }
+}
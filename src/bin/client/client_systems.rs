@@ -1,9 +1,21 @@
-use std::net::SocketAddr;
-
+use crate::{
+    client_network::{LocalPlayer, ServerConnection, ServerStatus, ServerTransfers},
+    lockstep::LockstepQueue,
+    prediction::{Predicted, Prediction},
+};
 use glam::*;
-use legion::{world::SubWorld, *};
-use unnamed_rts::components::Selectable;
+use legion::{systems::CommandBuffer, world::SubWorld, *};
+use std::time::{Duration, Instant};
+use unnamed_rts::clock_sync::ClockSync;
+use unnamed_rts::commands::CommandBatcher;
+use unnamed_rts::components::{Owner, Selectable, Transform};
+use unnamed_rts::game_time::GameTime;
+use unnamed_rts::lockstep::NetworkMode;
+use unnamed_rts::map_chunk::ChunkIndex;
+use unnamed_rts::navigation::FlowField;
+use unnamed_rts::relevancy::AreaOfInterest;
 use unnamed_rts::resources::*;
+use unnamed_rts::tilemap::TileMap;
 use unnamed_rts::{
     input::{CursorPosition, MouseButtonState},
     rendering::{camera::Camera, ui::ui_resources::UiContext},
@@ -26,52 +38,247 @@ pub fn draw_debug_ui(
                 "Show bounding boxes",
             );
             ui.checkbox(&mut debug_settings.show_grid, "Show debug grid");
+            ui.checkbox(
+                &mut debug_settings.show_prediction_corrections,
+                "Show prediction corrections",
+            );
+            ui.checkbox(&mut debug_settings.show_network_stats, "Show network stats");
             for selectable in query.iter(world) {
                 ui.label(format!("Selected: {}", selectable.is_selected));
             }
         });
 }
 
+/// Shown below the fps stats from `common_systems::fps_ui`
+#[system]
+pub fn network_stats_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] clock_sync: &ClockSync,
+) {
+    egui::Area::new("Network stats")
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(0.0, 20.0))
+        .show(ui_context.context(), |ui| {
+            if !clock_sync.is_synchronized() {
+                ui.colored_label(egui::Color32::WHITE, "RTT: -");
+                return;
+            }
+            ui.colored_label(
+                egui::Color32::WHITE,
+                format!("RTT: {}ms", clock_sync.rtt.as_millis()),
+            );
+            ui.colored_label(
+                egui::Color32::WHITE,
+                format!("Jitter: {}ms", clock_sync.jitter.as_millis()),
+            );
+            ui.colored_label(
+                egui::Color32::WHITE,
+                format!("Packet loss: {:.0}%", clock_sync.packet_loss * 100.0),
+            );
+        });
+}
+
+/// Shows the match clock replicated from the server
+#[system]
+pub fn match_clock_ui(#[resource] ui_context: &mut UiContext, #[resource] game_time: &GameTime) {
+    let seconds = game_time.elapsed().as_secs();
+    let mut clock = format!("{:02}:{:02}", seconds / 60, seconds % 60);
+    if (game_time.scale() - 1.0).abs() > f32::EPSILON {
+        clock.push_str(&format!(" ({}x)", game_time.scale()));
+    }
+    if game_time.is_paused() {
+        clock.push_str(" paused");
+    }
+    egui::Area::new("Match clock")
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::ZERO)
+        .show(ui_context.context(), |ui| {
+            ui.colored_label(egui::Color32::WHITE, clock);
+        });
+}
+
+/// Tells the player when the server has paused the game or disconnected them
+#[system]
+pub fn server_status_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] server_status: &ServerStatus,
+) {
+    let message = match (&server_status.disconnect_reason, server_status.paused) {
+        (Some(reason), _) => reason.as_str(),
+        (None, true) => "The server has paused the game",
+        (None, false) => return,
+    };
+    egui::Area::new("Server status")
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 20.0))
+        .show(ui_context.context(), |ui| {
+            ui.colored_label(egui::Color32::YELLOW, message);
+        });
+}
+
+/// Progress bar shown while the world is being loaded or a large resync is received
+#[system]
+pub fn transfer_progress_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] transfers: &ServerTransfers,
+) {
+    let (title, progress) = match (transfers.is_world_loaded(), transfers.progress()) {
+        (false, progress) => ("Loading the world", progress.unwrap_or(0.0)),
+        (true, Some(progress)) => ("Receiving the server state", progress),
+        (true, None) => return,
+    };
+    egui::Area::new("Transfer progress")
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ui_context.context(), |ui| {
+            ui.label(title);
+            ui.add(
+                egui::ProgressBar::new(progress)
+                    .show_percentage()
+                    .desired_width(300.0),
+            );
+        });
+}
+
+/// Where the ray through the given screen position hits the ground plane
+pub fn ground_intersection(
+    camera: &Camera,
+    screen_pos: &CursorPosition,
+    window_size: &WindowSize,
+) -> Option<Vec3A> {
+    let ray = camera.raycast(screen_pos, window_size);
+    // check intersection with the regular ground plan
+    let normal = Vec3A::Y;
+    let denominator = normal.dot(ray.direction);
+    if denominator.abs() > 0.0001 {
+        // it isn't parallel to the plane
+        // (camera can still theoretically be within the plane but don't care about that)
+        let t = -(normal.dot(ray.origin)) / denominator;
+        if t >= 0.0 {
+            // there was an intersection
+            return Some((t * ray.direction) + ray.origin);
+        }
+    }
+    None
+}
+
 #[system]
 #[allow(clippy::too_many_arguments)]
 pub fn move_action(
     world: &mut SubWorld,
+    command_buffer: &mut CommandBuffer,
     #[resource] camera: &Camera,
     #[resource] mouse_button_state: &MouseButtonState,
     #[resource] mouse_pos: &CursorPosition,
+    #[resource] window_size: &WindowSize,
+    #[resource] local_player: &LocalPlayer,
+    #[resource] tilemap: &TileMap,
+    #[resource] prediction: &mut Prediction,
+    #[resource] network_mode: &NetworkMode,
+    #[resource] lockstep_queue: &mut LockstepQueue,
+    #[resource] command_batcher: &mut CommandBatcher,
+    query: &mut Query<(Entity, &Selectable, &Owner, &Transform, Option<&Predicted>)>,
+) {
+    // Spectators can't give orders
+    if !mouse_button_state.pressed_current_frame(&MouseButton::Right) || local_player.id.is_none() {
+        return;
+    }
+    let target = match ground_intersection(camera, mouse_pos, window_size) {
+        Some(target) => target,
+        None => return,
+    };
+    query.for_each(
+        world,
+        |(entity, selectable, owner, transform, predicted)| {
+            // Only the player's own units can be ordered around
+            if !selectable.is_selected || Some(owner.player) != local_player.id {
+                return;
+            }
+            let move_order = Command::Move {
+                entity: *entity,
+                target,
+            };
+            // In lockstep the order is run locally once the server sends out its turn
+            if *network_mode != NetworkMode::Snapshots {
+                lockstep_queue.push_command(move_order);
+                return;
+            }
+            command_batcher.push(move_order);
+            // Same flow field as the server will use when it applies the order
+            if let Ok(target) = ChunkIndex::new(target.x as i32, target.z as i32) {
+                prediction.predict_move(
+                    command_buffer,
+                    *entity,
+                    transform,
+                    predicted,
+                    FlowField::new(target, &tilemap.chunk),
+                );
+            }
+        },
+    );
+}
+
+/// Min time between area of interest updates
+const AREA_OF_INTEREST_INTERVAL: Duration = Duration::from_millis(100);
+/// The area is resent after this long even if it hasn't changed since the updates may be lost
+const AREA_OF_INTEREST_REFRESH: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct AreaOfInterestState {
+    last_sent: Option<AreaOfInterest>,
+    last_sent_at: Instant,
+}
+
+impl Default for AreaOfInterestState {
+    fn default() -> Self {
+        AreaOfInterestState {
+            last_sent: None,
+            last_sent_at: Instant::now(),
+        }
+    }
+}
+
+/// Tells the server which part of the map the camera is looking at
+/// so it can send the entities within it
+#[system]
+pub fn send_area_of_interest(
+    #[state] state: &mut AreaOfInterestState,
+    #[resource] camera: &Camera,
     #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
     #[resource] net_serilization: &NetworkSerialization,
     #[resource] window_size: &WindowSize,
-    query: &mut Query<(Entity, &Selectable)>,
 ) {
-    if mouse_button_state.pressed_current_frame(&MouseButton::Right) {
-        query.par_for_each(world, |(entity, selectable)| {
-            if selectable.is_selected {
-                let ray = camera.raycast(mouse_pos, window_size);
-                // check intersection with the regular ground plan
-                let normal = Vec3A::Y;
-                let denominator = normal.dot(ray.direction);
-                if denominator.abs() > 0.0001 {
-                    // it isn't parallel to the plane
-                    // (camera can still theoretically be within the plane but don't care about that)
-                    let t = -(normal.dot(ray.origin)) / denominator;
-                    if t >= 0.0 {
-                        // there was an intersection
-                        let target = (t * ray.direction) + ray.origin;
-                        let payload =
-                            net_serilization.serialize_client_update(&ClientUpdate::Move {
-                                entity: *entity,
-                                target,
-                            });
-
-                        let packet = laminar::Packet::reliable_unordered(
-                            SocketAddr::new(SERVER_ADDR.into(), SERVER_PORT),
-                            payload,
-                        );
-                        network.sender.send(packet).unwrap();
-                    }
-                }
-            }
-        });
+    if state.last_sent_at.elapsed() < AREA_OF_INTEREST_INTERVAL {
+        return;
+    }
+    // Same screen size calculation as Camera::raycast
+    let width = window_size.physical_width as f64 * window_size.scale_factor as f64;
+    let height = window_size.physical_height as f64 * window_size.scale_factor as f64;
+    let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)];
+    // Corners above the horizon don't hit the ground, the camera position
+    // is included to still cover what's visible in that case
+    let area_of_interest = AreaOfInterest::from_points(
+        corners
+            .iter()
+            .filter_map(|&(x, y)| {
+                ground_intersection(camera, &CursorPosition { x, y }, window_size)
+            })
+            .chain(std::iter::once(camera.get_position()))
+            .map(|point| point.xz()),
+    );
+    if area_of_interest != state.last_sent
+        || state.last_sent_at.elapsed() >= AREA_OF_INTEREST_REFRESH
+    {
+        if let Some(area_of_interest) = area_of_interest {
+            let payload = net_serilization.serialize_session_update(
+                server.token,
+                &ClientUpdate::AreaOfInterest(area_of_interest),
+            );
+            let packet = laminar::Packet::unreliable_sequenced(
+                server.addr,
+                payload,
+                Some(AREA_OF_INTEREST_STREAM),
+            );
+            network.sender.send(packet).unwrap();
+        }
+        state.last_sent = area_of_interest;
+        state.last_sent_at = Instant::now();
     }
 }
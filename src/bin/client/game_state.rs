@@ -1,7 +1,13 @@
 #![allow(dead_code)]
 use crate::{
+    chat::{self, Chat},
+    client_config::ClientConfig,
     client_network::{self, add_client_components, connect_to_server},
     client_systems,
+    lockstep::{self, LockstepQueue, LockstepSimulation},
+    network_panel::{self, NetworkHistory},
+    prediction::{self, Prediction},
+    spectator::{self, Perspective},
 };
 use core::fmt::Debug;
 use crossbeam_channel::Receiver;
@@ -11,6 +17,7 @@ use std::{path::Path, time::Instant};
 use unnamed_rts::{
     assets::{self, Assets},
     common_systems,
+    lockstep::NetworkMode,
     rendering::{
         camera::{self, Camera},
         common::DepthTexture,
@@ -25,6 +32,8 @@ use unnamed_rts::{
     },
     resources::{DebugRenderSettings, FpsStats},
     states::State,
+    tilemap::LoadableMap,
+    transform_encoding::MapBounds,
 };
 use unnamed_rts::{
     rendering::drawable_tilemap::DrawableTileMap,
@@ -76,7 +85,15 @@ fn setup_render_resources(
 }
 
 #[derive(Debug)]
-pub struct GameState;
+pub struct GameState {
+    config: ClientConfig,
+}
+
+impl GameState {
+    pub fn new(config: ClientConfig) -> Self {
+        GameState { config }
+    }
+}
 
 impl State for GameState {
     fn on_init(
@@ -101,9 +118,6 @@ impl State for GameState {
             size.physical_width,
             size.physical_height,
         );
-        // TODO: This must be synced with the server
-        let mut map_assets = Assets::<DrawableTileMap>::default();
-        let map_handle = map_assets.load(Path::new("Tilemap.map")).unwrap();
         let mut model_assets = Assets::<GltfModel>::default();
         let suit = model_assets.load("Toon.glb").unwrap();
 
@@ -111,19 +125,46 @@ impl State for GameState {
         drop(queue);
         resources.insert(Assets::<UiTexture>::default());
         resources.insert(model_assets);
-        resources.insert(map_handle);
-        resources.insert(map_assets);
         resources.insert(FpsStats::default());
         resources.insert(BoundingBoxMap::default());
         resources.insert(NetworkSerialization::default());
 
         // Set up network and connect to server
-        connect_to_server(world, resources);
+        let map_path = match connect_to_server(world, resources, &self.config) {
+            Ok(map_path) => map_path,
+            Err(err) => {
+                error!("Failed to join the server: {:#}", err);
+                std::process::exit(1);
+            }
+        };
         add_client_components(world, resources, &suit);
 
+        // The tilemap is also needed outside of rendering to predict movement
+        let tilemap = LoadableMap::load(&Path::new("assets").join(&map_path))
+            .expect("Failed to load map")
+            .map
+            .into_owned();
+        let network_mode = *resources.get::<NetworkMode>().unwrap();
+        if let NetworkMode::Lockstep { tick_rate } = network_mode {
+            resources.insert(LockstepSimulation::new(tick_rate, tilemap.clone()));
+        }
+        resources.insert(LockstepQueue::default());
+        resources.insert(MapBounds::of(&tilemap));
+        resources.insert(tilemap);
+        resources.insert(Prediction::default());
+        resources.insert(Perspective::default());
+        resources.insert(Chat::default());
+        resources.insert(NetworkHistory::default());
+        let mut map_assets = Assets::<DrawableTileMap>::default();
+        let map_handle = map_assets.load(map_path).unwrap();
+        resources.insert(map_handle);
+        resources.insert(map_assets);
+
         resources.insert(DebugRenderSettings {
             show_grid: true,
             show_bounding_boxes: true,
+            show_prediction_corrections: false,
+            show_network_stats: false,
         });
         resources.insert(camera);
     }
@@ -137,7 +178,8 @@ impl State for GameState {
     }
 
     fn foreground_schedule(&self) -> Schedule {
-        Schedule::builder()
+        let mut builder = Schedule::builder();
+        builder
             .add_system(common_systems::fps_system())
             .add_system(assets::asset_load_system::<GltfModel>())
             .add_system(assets::asset_load_system::<DrawableTileMap>())
@@ -154,8 +196,29 @@ impl State for GameState {
             .add_system(debug_lines_pass::draw_system())
             .add_system(client_systems::draw_debug_ui_system())
             .add_system(common_systems::fps_ui_system())
+            .add_system(client_systems::network_stats_ui_system())
+            .add_system(network_panel::network_panel_ui_system())
+            .add_system(client_systems::match_clock_ui_system())
+            .add_system(client_systems::server_status_ui_system())
+            .add_system(client_systems::transfer_progress_ui_system())
+            .add_system(chat::chat_ui_system())
+            .add_system(chat::draw_ping_markers_system())
             .add_system(client_systems::move_action_system())
+            .add_system(client_network::send_commands_system())
+            .add_system(client_systems::send_area_of_interest_system(
+                client_systems::AreaOfInterestState::default(),
+            ))
+            .add_system(client_network::ping_system(None))
             .add_system(client_network::server_update_system())
-            .build()
+            .add_thread_local_fn(lockstep::run_turns)
+            .add_system(prediction::reconcile_system())
+            .add_system(prediction::predict_movement_system())
+            .add_system(prediction::draw_prediction_overlay_system());
+        if self.config.spectate {
+            builder
+                .add_system(spectator::perspective_ui_system())
+                .add_system(spectator::perspective_visibility_system());
+        }
+        builder.build()
     }
 }
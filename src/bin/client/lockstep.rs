@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+
+use laminar::Packet;
+use legion::*;
+use unnamed_rts::{
+    game_time::GameTime,
+    lockstep::{state_checksum, INPUT_DELAY_TURNS},
+    resources::{ClientUpdate, Command, NetworkSerialization, NetworkSocket, LOCKSTEP_STREAM},
+    simulation::{add_simulation_systems, Orders, SimulationStats},
+    tilemap::TileMap,
+    timestep::FixedTimestep,
+};
+
+use crate::client_network::{ServerConnection, ServerTransfers};
+
+/// Turns received from the server and the local commands waiting to be sent in lockstep mode
+#[derive(Debug, Default)]
+pub struct LockstepQueue {
+    turns: VecDeque<(u64, Vec<Command>)>,
+    commands: Vec<Command>,
+}
+
+impl LockstepQueue {
+    #[inline]
+    pub fn push_turn(&mut self, turn: u64, commands: Vec<Command>) {
+        self.turns.push_back((turn, commands));
+    }
+
+    /// Queues a command to be sent with the next batch of commands
+    #[inline]
+    pub fn push_command(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+}
+
+/// The client's copy of the simulation, only present in lockstep mode. It has its own
+/// resources since the simulation must not see the frame time.
+pub struct LockstepSimulation {
+    schedule: Schedule,
+    resources: Resources,
+    delta_time: f32,
+    last_turn: u64,
+}
+
+impl LockstepSimulation {
+    pub fn new(tick_rate: u32, tilemap: TileMap) -> Self {
+        let mut resources = Resources::default();
+        resources.insert(tilemap);
+        resources.insert(GameTime::default());
+        resources.insert(Orders::default());
+        resources.insert(SimulationStats::default());
+        LockstepSimulation {
+            schedule: add_simulation_systems(&mut Schedule::builder()).build(),
+            resources,
+            // Same delta time as the server ticks
+            delta_time: FixedTimestep::new(tick_rate, 1, std::time::Instant::now())
+                .tick_duration()
+                .as_secs_f32(),
+            last_turn: 0,
+        }
+    }
+
+    fn run_turn(&mut self, world: &mut World, commands: Vec<Command>) {
+        self.resources
+            .get_mut::<GameTime>()
+            .unwrap()
+            .advance(self.delta_time);
+        self.resources.insert(Orders { commands });
+        self.schedule.execute(world, &mut self.resources);
+    }
+}
+
+/// Runs the turns received from the server, reports the state checksums and sends the
+/// queued commands. Does nothing unless the game is played in lockstep mode.
+pub fn run_turns(world: &mut World, resources: &mut Resources) {
+    // The turns wait in the queue until the world they run on has been loaded
+    if !resources
+        .get::<ServerTransfers>()
+        .unwrap()
+        .is_world_loaded()
+    {
+        return;
+    }
+    let mut simulation = match resources.remove::<LockstepSimulation>() {
+        Some(simulation) => simulation,
+        None => return,
+    };
+    {
+        let mut queue = resources.get_mut::<LockstepQueue>().unwrap();
+        let network = resources.get::<NetworkSocket>().unwrap();
+        let server = resources.get::<ServerConnection>().unwrap();
+        let net_serialization = resources.get::<NetworkSerialization>().unwrap();
+        while let Some((turn, commands)) = queue.turns.pop_front() {
+            if turn != simulation.last_turn + 1 {
+                error!(
+                    "Expected lockstep turn {} but got {}",
+                    simulation.last_turn + 1,
+                    turn
+                );
+            }
+            simulation.run_turn(world, commands);
+            simulation.last_turn = turn;
+            let payload = net_serialization.serialize_session_update(
+                server.token,
+                &ClientUpdate::TurnChecksum {
+                    turn,
+                    checksum: state_checksum(world),
+                },
+            );
+            // Sent reliably since unreliable packets don't carry acks for the turns
+            network
+                .sender
+                .send(Packet::reliable_unordered(server.addr, payload))
+                .unwrap();
+        }
+        // The clock of the simulation is the match clock shown to the player
+        resources
+            .get_mut::<GameTime>()
+            .unwrap()
+            .synchronize(&simulation.resources.get::<GameTime>().unwrap());
+        if !queue.commands.is_empty() {
+            let payload = net_serialization.serialize_session_update(
+                server.token,
+                &ClientUpdate::Commands {
+                    turn: simulation.last_turn + INPUT_DELAY_TURNS,
+                    commands: std::mem::take(&mut queue.commands),
+                },
+            );
+            network
+                .sender
+                .send(Packet::reliable_ordered(
+                    server.addr,
+                    payload,
+                    Some(LOCKSTEP_STREAM),
+                ))
+                .unwrap();
+        }
+    }
+    resources.insert(simulation);
+}
@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use unnamed_rts::{
+    resources::{ClientUpdate, MAP_CHUNK_SIZE, MAP_DOWNLOAD_WINDOW},
+    tilemap::map_hash,
+};
+
+const ASSET_DIR: &str = "assets";
+const MAP_CACHE_DIR: &str = "map_cache";
+
+/// Returns the path (relative to the asset directory) of a local map with the given
+/// name or a previously downloaded map, as long as the content matches the hash
+pub fn find_local_map(name: &str, hash: u64) -> Option<PathBuf> {
+    // Only the file name is used to avoid reading arbitrary files
+    let local_map = Path::new(name).file_name().map(PathBuf::from);
+    local_map
+        .into_iter()
+        .chain(std::iter::once(cached_map_path(hash)))
+        .find(|path| {
+            std::fs::read(Path::new(ASSET_DIR).join(path))
+                .map(|bytes| map_hash(&bytes) == hash)
+                .unwrap_or(false)
+        })
+}
+
+fn cached_map_path(hash: u64) -> PathBuf {
+    Path::new(MAP_CACHE_DIR).join(format!("{:016x}.map", hash))
+}
+
+/// Keeps track of a map that's being downloaded from the server.
+/// The chunks are expected to arrive in order since they are sent over
+/// a reliable ordered stream.
+#[derive(Debug)]
+pub struct MapDownload {
+    hash: u64,
+    size: usize,
+    bytes: Vec<u8>,
+    requested_chunks: u32,
+}
+
+impl MapDownload {
+    pub fn new(hash: u64, size: u64) -> Self {
+        MapDownload {
+            hash,
+            size: size as usize,
+            bytes: Vec::with_capacity(size as usize),
+            requested_chunks: 0,
+        }
+    }
+
+    #[inline]
+    fn total_chunks(&self) -> u32 {
+        self.size.div_ceil(MAP_CHUNK_SIZE) as u32
+    }
+
+    #[inline]
+    fn received_chunks(&self) -> u32 {
+        self.bytes.len().div_ceil(MAP_CHUNK_SIZE) as u32
+    }
+
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.bytes.len() >= self.size
+    }
+
+    /// Returns a request for the next window of chunks if all previously
+    /// requested chunks have been received
+    pub fn next_request(&mut self) -> Option<ClientUpdate> {
+        if self.is_complete() || self.received_chunks() < self.requested_chunks {
+            return None;
+        }
+        let start = self.requested_chunks;
+        let count = MAP_DOWNLOAD_WINDOW.min(self.total_chunks() - start);
+        self.requested_chunks += count;
+        Some(ClientUpdate::RequestMapChunks { start, count })
+    }
+
+    pub fn push_chunk(&mut self, index: u32, bytes: &[u8]) -> Result<()> {
+        if index != self.received_chunks() {
+            return Err(anyhow!(
+                "Unexpected map chunk: {}, expected: {}",
+                index,
+                self.received_chunks()
+            ));
+        }
+        if self.bytes.len() + bytes.len() > self.size {
+            return Err(anyhow!("Map chunk exceeds the announced map size"));
+        }
+        self.bytes.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Verifies the downloaded map and stores it in the map cache.
+    /// Returns the path to the map relative to the asset directory.
+    pub fn finish(self) -> Result<PathBuf> {
+        if map_hash(&self.bytes) != self.hash {
+            return Err(anyhow!("Downloaded map doesn't match the announced hash"));
+        }
+        let path = cached_map_path(self.hash);
+        std::fs::create_dir_all(Path::new(ASSET_DIR).join(MAP_CACHE_DIR))?;
+        std::fs::write(Path::new(ASSET_DIR).join(&path), &self.bytes)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use egui::plot::{Line, Plot, Value, Values};
+use legion::*;
+use unnamed_rts::{
+    network_stats::{stream_name, PeerStats},
+    rendering::ui::ui_resources::UiContext,
+    resources::{DebugRenderSettings, NetworkSocket},
+};
+
+use crate::client_network::ServerConnection;
+
+/// Time between the samples of the traffic graphs
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of samples shown in the graphs
+const HISTORY_LENGTH: usize = 60;
+
+/// Traffic exchanged with the server during one sample interval
+#[derive(Debug, Clone, Copy)]
+struct TrafficSample {
+    sent_bytes: u64,
+    received_bytes: u64,
+    resent: u64,
+}
+
+/// The traffic exchanged with the server over the last HISTORY_LENGTH sample intervals
+#[derive(Debug)]
+pub struct NetworkHistory {
+    samples: VecDeque<TrafficSample>,
+    sampled_stats: PeerStats,
+    sampled_at: Instant,
+}
+
+impl Default for NetworkHistory {
+    fn default() -> Self {
+        NetworkHistory {
+            samples: VecDeque::with_capacity(HISTORY_LENGTH),
+            sampled_stats: PeerStats::default(),
+            sampled_at: Instant::now(),
+        }
+    }
+}
+
+impl NetworkHistory {
+    fn sample(&mut self, stats: &PeerStats) {
+        if self.sampled_at.elapsed() < SAMPLE_INTERVAL {
+            return;
+        }
+        self.sampled_at = Instant::now();
+        let since = stats.since(&self.sampled_stats);
+        if self.samples.len() == HISTORY_LENGTH {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(TrafficSample {
+            sent_bytes: since.datagrams_sent.bytes,
+            received_bytes: since.datagrams_received.bytes,
+            resent: since.resent.packets,
+        });
+        self.sampled_stats = stats.clone();
+    }
+
+    fn line(&self, value: impl Fn(&TrafficSample) -> f64) -> Line {
+        let secs_per_sample = SAMPLE_INTERVAL.as_secs_f64();
+        let start = self.samples.len() as f64;
+        Line::new(Values::from_values_iter(
+            self.samples
+                .iter()
+                .enumerate()
+                .map(|(i, sample)| Value::new((i as f64 - start) * secs_per_sample, value(sample))),
+        ))
+    }
+}
+
+/// Panel with the traffic exchanged with the server per stream and graphs of the last minute,
+/// toggled in the debug menu
+#[system]
+pub fn network_panel_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] debug_settings: &mut DebugRenderSettings,
+    #[resource] history: &mut NetworkHistory,
+    #[resource] network: &NetworkSocket,
+    #[resource] server: &ServerConnection,
+) {
+    let stats = match network.stats.peer(server.addr) {
+        Some(stats) => stats,
+        None => return,
+    };
+    history.sample(&stats);
+    egui::Window::new("Network")
+        .open(&mut debug_settings.show_network_stats)
+        .resizable(false)
+        .show(ui_context.context(), |ui| {
+            let (sent, received) = (stats.total_sent(), stats.total_received());
+            ui.label(format!(
+                "Sent: {} messages, {:.1} kB on the wire",
+                sent.packets,
+                stats.datagrams_sent.bytes as f32 / 1000.0
+            ));
+            ui.label(format!(
+                "Received: {} messages, {:.1} kB on the wire",
+                received.packets,
+                stats.datagrams_received.bytes as f32 / 1000.0
+            ));
+            ui.label(format!(
+                "Resent: {} datagrams, estimated loss: {:.1}%",
+                stats.resent.packets,
+                stats.loss_estimate() * 100.0
+            ));
+            ui.separator();
+            egui::Grid::new("Network streams")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("Stream");
+                    ui.label("Sent");
+                    ui.label("Avg size");
+                    ui.label("Received");
+                    ui.label("Avg size");
+                    ui.end_row();
+                    let mut streams: Vec<_> =
+                        stats.sent.keys().chain(stats.received.keys()).collect();
+                    streams.sort();
+                    streams.dedup();
+                    for stream in streams {
+                        let sent = stats.sent.get(stream).copied().unwrap_or_default();
+                        let received = stats.received.get(stream).copied().unwrap_or_default();
+                        ui.label(stream_name(*stream));
+                        ui.label(sent.packets.to_string());
+                        ui.label(format!("{:.0} B", sent.average_size()));
+                        ui.label(received.packets.to_string());
+                        ui.label(format!("{:.0} B", received.average_size()));
+                        ui.end_row();
+                    }
+                });
+            ui.separator();
+            let to_kilobytes = |bytes: u64| bytes as f64 / 1000.0 / SAMPLE_INTERVAL.as_secs_f64();
+            ui.label("Traffic in kB/s");
+            ui.add(
+                Plot::new("Network traffic")
+                    .line(
+                        history
+                            .line(|sample| to_kilobytes(sample.sent_bytes))
+                            .name("Sent"),
+                    )
+                    .line(
+                        history
+                            .line(|sample| to_kilobytes(sample.received_bytes))
+                            .name("Received"),
+                    )
+                    .include_y(0.0)
+                    .height(120.0)
+                    .allow_drag(false)
+                    .allow_zoom(false),
+            );
+            ui.label("Resent datagrams");
+            ui.add(
+                Plot::new("Network resends")
+                    .line(history.line(|sample| sample.resent as f64))
+                    .include_y(0.0)
+                    .height(60.0)
+                    .allow_drag(false)
+                    .allow_zoom(false),
+            );
+        });
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use glam::Vec3A;
+use legion::{systems::CommandBuffer, world::SubWorld, *};
+use unnamed_rts::{
+    components::{Transform, Velocity},
+    game_time::GameTime,
+    navigation::{movement_impl, FlowField},
+    rendering::ui::ui_resources::UiContext,
+    resources::{DebugRenderSettings, Time},
+    tilemap::TileMap,
+};
+
+/// How long predicted positions are kept to compare against the authoritative state
+const HISTORY_LENGTH: Duration = Duration::from_secs(2);
+/// Rate at which the visual correction offset is blended out, per second
+const CORRECTION_BLEND_RATE: f32 = 8.0;
+/// Errors smaller than this means the prediction matches the server
+const CORRECTION_THRESHOLD: f32 = 0.05;
+/// Errors larger than this are snapped to directly instead of blended out
+const MAX_BLENDED_CORRECTION: f32 = 4.0;
+/// Orders that haven't shown up in the authoritative state after this long aren't used to
+/// measure the prediction lag
+const PENDING_ORDER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of corrections shown in the debug overlay
+const CORRECTION_LOG_SIZE: usize = 10;
+
+/// Added to the player's own units while their movement is predicted. The Transform
+/// of the entity is the simulated transform plus the correction offset.
+#[derive(Debug)]
+pub struct Predicted {
+    simulated: Transform,
+    /// Simulated positions, oldest first
+    history: VecDeque<(Instant, Vec3A)>,
+    /// Visual offset from the simulated position which is blended out over time
+    correction_offset: Vec3A,
+    /// Latest authoritative state that hasn't been reconciled yet
+    authoritative: Option<Transform>,
+    /// Latest authoritative state, kept after it has been reconciled
+    server_transform: Transform,
+}
+
+impl Predicted {
+    pub fn new(transform: Transform) -> Self {
+        Predicted {
+            simulated: transform,
+            history: VecDeque::new(),
+            correction_offset: Vec3A::ZERO,
+            authoritative: None,
+            server_transform: transform,
+        }
+    }
+
+    /// Stores an authoritative state received from the server to be reconciled
+    #[inline]
+    pub fn set_authoritative(&mut self, transform: Transform) {
+        self.authoritative = Some(transform);
+        self.server_transform = transform;
+    }
+
+    /// The latest transform received from the server
+    #[inline]
+    pub fn server_transform(&self) -> Transform {
+        self.server_transform
+    }
+
+    /// The simulated position at the given time or the oldest one if the history is shorter
+    fn position_at(&self, time: Instant) -> Vec3A {
+        self.history
+            .iter()
+            .rev()
+            .find(|(sample_time, _)| *sample_time <= time)
+            .or_else(|| self.history.front())
+            .map(|(_, position)| *position)
+            .unwrap_or(self.simulated.matrix.translation)
+    }
+}
+
+#[derive(Debug)]
+struct PendingOrder {
+    entity: Entity,
+    sent_at: Instant,
+    start_position: Vec3A,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Correction {
+    pub entity: Entity,
+    pub size: f32,
+    pub snapped: bool,
+}
+
+/// Client resource keeping track of how far ahead of the server the prediction is running
+#[derive(Debug)]
+pub struct Prediction {
+    /// Time between an order being predicted and it showing up in the authoritative state
+    pub lag: Duration,
+    pending_orders: Vec<PendingOrder>,
+    /// Most recent corrections, newest last
+    pub corrections: VecDeque<Correction>,
+}
+
+impl Default for Prediction {
+    fn default() -> Self {
+        Prediction {
+            lag: Duration::from_millis(100),
+            pending_orders: Vec::new(),
+            corrections: VecDeque::with_capacity(CORRECTION_LOG_SIZE),
+        }
+    }
+}
+
+impl Prediction {
+    /// Starts predicting a move order sent to the server
+    pub fn predict_move(
+        &mut self,
+        command_buffer: &mut CommandBuffer,
+        entity: Entity,
+        transform: &Transform,
+        predicted: Option<&Predicted>,
+        flow_field: FlowField,
+    ) {
+        // Measuring the lag only works for units standing still since the order
+        // is seen to be applied once the authoritative position changes
+        if predicted.is_none() {
+            self.pending_orders.push(PendingOrder {
+                entity,
+                sent_at: Instant::now(),
+                start_position: transform.matrix.translation,
+            });
+            command_buffer.add_component(entity, Predicted::new(*transform));
+        }
+        command_buffer.add_component(entity, flow_field);
+    }
+
+    fn measure_lag(&mut self, entity: Entity, authoritative: Vec3A) {
+        let now = Instant::now();
+        let mut sample = None;
+        self.pending_orders.retain(|order| {
+            if order.entity == entity
+                && order.start_position.distance(authoritative) > CORRECTION_THRESHOLD
+            {
+                sample = Some(now - order.sent_at);
+                return false;
+            }
+            now - order.sent_at < PENDING_ORDER_TIMEOUT
+        });
+        if let Some(sample) = sample {
+            // Smoothed to avoid jumps from single late packets
+            self.lag = self.lag.mul_f32(0.8) + sample.mul_f32(0.2);
+        }
+    }
+
+    fn log_correction(&mut self, correction: Correction) {
+        if self.corrections.len() == CORRECTION_LOG_SIZE {
+            self.corrections.pop_front();
+        }
+        self.corrections.push_back(correction);
+    }
+}
+
+/// Runs the same movement as the server for the predicted units, at the speed of the
+/// server's match clock
+#[system(for_each)]
+pub fn predict_movement(
+    transform: &mut Transform,
+    velocity: &mut Velocity,
+    predicted: &mut Predicted,
+    flow_field: &FlowField,
+    #[resource] tilemap: &TileMap,
+    #[resource] time: &Time,
+    #[resource] game_time: &GameTime,
+) {
+    movement_impl(
+        &tilemap.chunk,
+        flow_field,
+        &mut predicted.simulated,
+        velocity,
+        game_time.scaled(time.delta_time()),
+    );
+    let now = *time.current_time();
+    predicted
+        .history
+        .push_back((now, predicted.simulated.matrix.translation));
+    while predicted
+        .history
+        .front()
+        .is_some_and(|(sample_time, _)| now - *sample_time > HISTORY_LENGTH)
+    {
+        predicted.history.pop_front();
+    }
+    predicted.correction_offset *= (-CORRECTION_BLEND_RATE * time.delta_time()).exp();
+    *transform = predicted.simulated;
+    transform.matrix.translation += predicted.correction_offset;
+}
+
+/// Compares the authoritative state with what was predicted when the server simulated it.
+/// The error is applied to the simulation directly and blended out visually.
+#[system]
+#[write_component(Predicted)]
+#[write_component(Transform)]
+#[read_component(Velocity)]
+pub fn reconcile(
+    world: &mut SubWorld,
+    command_buffer: &mut CommandBuffer,
+    #[resource] prediction: &mut Prediction,
+) {
+    let mut query = <(Entity, &mut Transform, &mut Predicted, &Velocity)>::query();
+    for (entity, transform, predicted, velocity) in query.iter_mut(world) {
+        let authoritative = match predicted.authoritative.take() {
+            Some(authoritative) => authoritative,
+            None => continue,
+        };
+        let authoritative_position = authoritative.matrix.translation;
+        prediction.measure_lag(*entity, authoritative_position);
+        let predicted_position = predicted.position_at(
+            Instant::now()
+                .checked_sub(prediction.lag)
+                .unwrap_or_else(Instant::now),
+        );
+        let error = authoritative_position - predicted_position;
+        let size = error.length();
+        if size > CORRECTION_THRESHOLD {
+            let snapped = size > MAX_BLENDED_CORRECTION;
+            predicted.simulated.matrix.translation += error;
+            predicted
+                .history
+                .iter_mut()
+                .for_each(|(_, position)| *position += error);
+            predicted.correction_offset = if snapped {
+                Vec3A::ZERO
+            } else {
+                predicted.correction_offset - error
+            };
+            prediction.log_correction(Correction {
+                entity: *entity,
+                size,
+                snapped,
+            });
+        }
+        // Hand the unit back to the server state once the server has caught up
+        // and the unit has stopped where the server says it is
+        let arrived = velocity.velocity.length_squared() == 0.0
+            && authoritative_position.distance(predicted.simulated.matrix.translation)
+                <= CORRECTION_THRESHOLD
+            && predicted.correction_offset.length() <= CORRECTION_THRESHOLD;
+        if arrived {
+            *transform = authoritative;
+            command_buffer.remove_component::<Predicted>(*entity);
+            command_buffer.remove_component::<FlowField>(*entity);
+        }
+    }
+}
+
+#[system]
+pub fn draw_prediction_overlay(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] debug_settings: &DebugRenderSettings,
+    #[resource] prediction: &Prediction,
+) {
+    if !debug_settings.show_prediction_corrections {
+        return;
+    }
+    egui::Window::new("Prediction")
+        .resizable(false)
+        .show(ui_context.context(), |ui| {
+            ui.label(format!("Lag: {}ms", prediction.lag.as_millis()));
+            for correction in prediction.corrections.iter().rev() {
+                ui.label(format!(
+                    "{:?}: {:.2}{}",
+                    correction.entity,
+                    correction.size,
+                    if correction.snapped { " (snapped)" } else { "" }
+                ));
+            }
+        });
+}
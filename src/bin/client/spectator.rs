@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use glam::Vec3Swizzles;
+use legion::{systems::CommandBuffer, world::SubWorld, *};
+use unnamed_rts::{
+    components::{Hidden, Owner, PlayerId, Selectable, Transform},
+    input::KeyboardState,
+    relevancy::{DefaultRelevancy, RelevancyFilter, Viewer},
+    rendering::ui::ui_resources::UiContext,
+};
+use winit::event::VirtualKeyCode;
+
+/// Whose view a spectator watches the match from, None shows every entity
+#[derive(Debug, Default)]
+pub struct Perspective {
+    pub player: Option<PlayerId>,
+}
+
+impl Perspective {
+    /// Cycles through showing everything followed by each of the players
+    fn next(&mut self, players: &BTreeSet<PlayerId>) {
+        self.player = match self.player {
+            None => players.iter().next().copied(),
+            Some(current) => players.range(current + 1..).next().copied(),
+        };
+    }
+}
+
+/// Lets spectators pick the player perspective with buttons or cycle through them with tab
+#[system]
+pub fn perspective_ui(
+    #[resource] ui_context: &mut UiContext,
+    #[resource] keyboard_state: &KeyboardState,
+    #[resource] perspective: &mut Perspective,
+    query: &mut Query<&Owner>,
+    world: &SubWorld,
+) {
+    let players: BTreeSet<PlayerId> = query.iter(world).map(|owner| owner.player).collect();
+    if keyboard_state.pressed_current_frame(VirtualKeyCode::Tab) {
+        perspective.next(&players);
+    }
+    egui::Area::new("Spectator perspective")
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::Vec2::new(0.0, -20.0))
+        .show(ui_context.context(), |ui| {
+            ui.horizontal(|ui| {
+                ui.colored_label(egui::Color32::WHITE, "Spectating:");
+                ui.selectable_value(&mut perspective.player, None, "Everything");
+                for player in players {
+                    ui.selectable_value(
+                        &mut perspective.player,
+                        Some(player),
+                        format!("Player {}", player),
+                    );
+                }
+            });
+        });
+}
+
+/// Hides the entities the player of the spectator's perspective wouldn't receive from the
+/// server, ignoring the area the player is looking at
+#[system]
+#[allow(clippy::type_complexity)]
+pub fn perspective_visibility(
+    world: &mut SubWorld,
+    command_buffer: &mut CommandBuffer,
+    #[resource] perspective: &Perspective,
+    units: &mut Query<(&Transform, &Owner)>,
+    entities: &mut Query<(
+        Entity,
+        &Transform,
+        Option<&Owner>,
+        Option<&Hidden>,
+        Option<&mut Selectable>,
+    )>,
+) {
+    let unit_positions: Vec<_> = match perspective.player {
+        Some(player) => units
+            .iter(world)
+            .filter(|(_, owner)| owner.player == player)
+            .map(|(transform, _)| transform.matrix.translation.xz())
+            .collect(),
+        None => Vec::new(),
+    };
+    let relevancy = DefaultRelevancy::default();
+    entities.for_each_mut(world, |(entity, transform, owner, hidden, selectable)| {
+        let visible = perspective.player.is_none_or(|player| {
+            let viewer = Viewer {
+                player,
+                area_of_interest: None,
+                unit_positions: &unit_positions,
+            };
+            relevancy.is_relevant(&viewer, owner, transform.matrix.translation.xz())
+        });
+        match (visible, hidden.is_some()) {
+            (true, true) => command_buffer.remove_component::<Hidden>(*entity),
+            (false, false) => {
+                command_buffer.add_component(*entity, Hidden);
+                if let Some(selectable) = selectable {
+                    selectable.is_selected = false;
+                }
+            }
+            _ => {}
+        }
+    });
+}
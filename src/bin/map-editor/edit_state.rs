@@ -141,6 +141,8 @@ impl State for EditState {
         resources.insert(DebugRenderSettings {
             show_grid: false,
             show_bounding_boxes: true,
+            show_prediction_corrections: false,
+            show_network_stats: false,
         });
         let editor_settings = EditorSettings::default();
         resources.insert(editor_settings);
@@ -172,6 +172,12 @@ pub fn movement(
             debug_draw_flow_field(command_buffer, flow_field, tilemap.tile_grid(), redraw_flow);
         }
         // Movement along the flow field
-        navigation::movement_impl(tilemap.tile_grid(), flow_field, transform, velocity, time);
+        navigation::movement_impl(
+            tilemap.tile_grid(),
+            flow_field,
+            transform,
+            velocity,
+            time.delta_time(),
+        );
     });
 }
@@ -0,0 +1,168 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use legion::*;
+use log::{error, info, warn};
+use mimalloc::MiMalloc;
+use structopt::StructOpt;
+use unnamed_rts::{
+    components::{EntityType, Transform},
+    replay::{Replay, ReplayPlayer},
+    tilemap::{map_hash, LoadableMap},
+};
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "replay", about = "Headless replay player")]
+struct ReplayArgs {
+    /// The replay file to play
+    #[structopt(parse(from_os_str))]
+    replay: PathBuf,
+    /// The map the replay was recorded on
+    #[structopt(long, parse(from_os_str))]
+    map: PathBuf,
+    /// Playback speed multiplier
+    #[structopt(long, default_value = "1.0")]
+    speed: f32,
+    /// Simulate the entire replay as fast as possible, print the final state and exit
+    #[structopt(long)]
+    run_to_end: bool,
+}
+
+#[derive(Debug)]
+enum PlaybackCommand {
+    Pause,
+    Play,
+    Speed(f32),
+    Seek(usize),
+    Status,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Option<PlaybackCommand> {
+    let mut parts = line.split_whitespace();
+    let command = match (parts.next()?, parts.next()) {
+        ("pause", None) => PlaybackCommand::Pause,
+        ("play", None) => PlaybackCommand::Play,
+        ("speed", Some(speed)) => PlaybackCommand::Speed(speed.parse().ok()?),
+        ("seek", Some(tick)) => PlaybackCommand::Seek(tick.parse().ok()?),
+        ("status", None) => PlaybackCommand::Status,
+        ("quit", None) | ("exit", None) => PlaybackCommand::Quit,
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn spawn_command_reader() -> Receiver<PlaybackCommand> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while stdin
+            .read_line(&mut line)
+            .map(|read| read > 0)
+            .unwrap_or(false)
+        {
+            match parse_command(&line) {
+                Some(command) => {
+                    if sender.send(command).is_err() {
+                        break;
+                    }
+                }
+                None => warn!(
+                    "Unknown command: {}, expected one of: pause, play, speed <x>, seek <tick>, status, quit",
+                    line.trim()
+                ),
+            }
+            line.clear();
+        }
+    });
+    receiver
+}
+
+fn print_status(player: &ReplayPlayer) {
+    info!("Tick {}/{}", player.current_tick(), player.len());
+    let mut query = <(Entity, Read<EntityType>, Read<Transform>)>::query();
+    for (entity, entity_type, transform) in query.iter(player.world()) {
+        info!(
+            "{:?} {:?} at: {}",
+            entity, entity_type, transform.matrix.translation
+        );
+    }
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = ReplayArgs::from_args();
+    let replay = Replay::load(&args.replay).expect("Failed to load replay");
+    let map_bytes = std::fs::read(&args.map).expect("Failed to read map");
+    if map_hash(&map_bytes) != replay.header.map_hash {
+        warn!("The map doesn't match the one the replay was recorded on");
+    }
+    let tilemap = LoadableMap::from_bytes(&map_bytes)
+        .expect("Failed to load map")
+        .map
+        .into_owned();
+    let mut player = ReplayPlayer::new(replay, tilemap).expect("Failed to start replay");
+    info!("Loaded replay with {} ticks", player.len());
+
+    if args.run_to_end {
+        let start = Instant::now();
+        player
+            .seek(player.len())
+            .expect("Failed to simulate replay");
+        info!("Simulated replay in {}s", start.elapsed().as_secs_f32());
+        print_status(&player);
+        return;
+    }
+
+    let commands = spawn_command_reader();
+    let mut speed = args.speed;
+    let mut paused = false;
+    // Replay time that has passed but hasn't been simulated yet
+    let mut accumulator = 0.0;
+    let mut last_update = Instant::now();
+    loop {
+        match commands.recv_timeout(Duration::from_millis(1)) {
+            Ok(PlaybackCommand::Pause) => paused = true,
+            Ok(PlaybackCommand::Play) => paused = false,
+            Ok(PlaybackCommand::Speed(new_speed)) => speed = new_speed.max(0.0),
+            Ok(PlaybackCommand::Seek(tick)) => {
+                if let Err(err) = player.seek(tick) {
+                    error!("Failed to seek: {}", err);
+                }
+                accumulator = 0.0;
+                print_status(&player);
+            }
+            Ok(PlaybackCommand::Status) => print_status(&player),
+            Ok(PlaybackCommand::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        let now = Instant::now();
+        if !paused {
+            accumulator += (now - last_update).as_secs_f32() * speed;
+        }
+        last_update = now;
+        while let Some(delta_time) = player.next_delta_time() {
+            if accumulator < delta_time {
+                break;
+            }
+            accumulator -= delta_time;
+            if let Err(err) = player.step() {
+                error!("Failed to simulate tick: {}", err);
+                return;
+            }
+            if player.current_tick() == player.len() {
+                info!("Replay finished");
+                print_status(&player);
+            }
+        }
+    }
+}
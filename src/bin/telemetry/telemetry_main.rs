@@ -0,0 +1,55 @@
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf};
+
+use mimalloc::MiMalloc;
+use structopt::StructOpt;
+use unnamed_rts::telemetry::{MapSummary, MatchSummary};
+
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "telemetry",
+    about = "Summarises the match logs written by the server"
+)]
+struct TelemetryArgs {
+    /// The match logs to summarise
+    #[structopt(parse(from_os_str), required = true)]
+    logs: Vec<PathBuf>,
+    /// Only print the totals per map
+    #[structopt(long)]
+    totals_only: bool,
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = TelemetryArgs::from_args();
+    let mut maps: BTreeMap<(String, String), MapSummary> = BTreeMap::new();
+    for path in &args.logs {
+        let name = path.display().to_string();
+        let summary = match File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| MatchSummary::read(BufReader::new(file), &name))
+        {
+            Ok(summary) => summary,
+            Err(err) => {
+                log::error!("Skipping {}: {:#}", name, err);
+                continue;
+            }
+        };
+        if !args.totals_only {
+            println!("{}", name);
+            println!("{}", summary);
+        }
+        maps.entry((summary.map.clone(), summary.map_hash.clone()))
+            .or_default()
+            .add(&summary);
+    }
+    // Maps are kept apart by hash so an edited map doesn't mix with its older versions
+    for ((map, hash), summary) in &maps {
+        println!("Map {} ({})", map, hash);
+        println!("{}", summary);
+    }
+}
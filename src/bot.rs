@@ -0,0 +1,239 @@
+//! Order generation and traffic statistics of the headless bot clients used to load test the server
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use glam::Vec3A;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::transform_encoding::MapBounds;
+
+/// Decides where a bot orders its units to move next. The bots don't download the map, so
+/// targets are within the bounds of a map with the default transform.
+#[derive(Debug, Clone)]
+pub enum OrderScript {
+    /// Random targets anywhere on the map
+    Random(Box<StdRng>),
+    /// Cycles through the targets in order
+    Scripted { targets: Vec<Vec3A>, next: usize },
+}
+
+impl OrderScript {
+    pub fn random(seed: u64) -> Self {
+        OrderScript::Random(Box::new(StdRng::seed_from_u64(seed)))
+    }
+
+    /// Parses a script with one `x z` target per line, empty lines and lines
+    /// starting with # are ignored
+    pub fn parse(script: &str) -> Result<Self> {
+        let bounds = MapBounds::default();
+        let targets = script
+            .lines()
+            .enumerate()
+            .map(|(line_number, line)| (line_number + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| {
+                let coordinates = line
+                    .split_whitespace()
+                    .map(str::parse::<f32>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| anyhow!("Line {}: {}", line_number, err))?;
+                match coordinates[..] {
+                    [x, z] if bounds.contains(Vec3A::new(x, 0.0, z)) => Ok(Vec3A::new(x, 0.0, z)),
+                    [_, _] => Err(anyhow!("Line {}: target is outside the map", line_number)),
+                    _ => Err(anyhow!("Line {}: expected `x z`", line_number)),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if targets.is_empty() {
+            return Err(anyhow!("The script contains no targets"));
+        }
+        Ok(OrderScript::Scripted { targets, next: 0 })
+    }
+
+    pub fn next_target(&mut self) -> Vec3A {
+        match self {
+            OrderScript::Random(rng) => {
+                let bounds = MapBounds::default();
+                let (min, max) = (bounds.min, bounds.max());
+                Vec3A::new(
+                    rng.gen_range(min.x + 0.5..max.x - 0.5),
+                    0.0,
+                    rng.gen_range(min.y + 0.5..max.y - 0.5),
+                )
+            }
+            OrderScript::Scripted { targets, next } => {
+                let target = targets[*next];
+                *next = (*next + 1) % targets.len();
+                target
+            }
+        }
+    }
+}
+
+/// Traffic counted by a bot since its last report
+#[derive(Debug)]
+pub struct TrafficStats {
+    window_start: Instant,
+    snapshots: u32,
+    orders: u32,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+impl TrafficStats {
+    pub fn new(now: Instant) -> Self {
+        TrafficStats {
+            window_start: now,
+            snapshots: 0,
+            orders: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record_snapshot(&mut self) {
+        self.snapshots += 1;
+    }
+
+    #[inline]
+    pub fn record_order(&mut self) {
+        self.orders += 1;
+    }
+
+    #[inline]
+    pub fn record_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+    }
+
+    #[inline]
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+    }
+
+    /// Rates since the last report, starts a new window
+    pub fn report(&mut self, now: Instant, rtt: Option<Duration>) -> BotReport {
+        let seconds = now
+            .saturating_duration_since(self.window_start)
+            .as_secs_f32()
+            .max(f32::EPSILON);
+        let report = BotReport {
+            snapshot_rate: self.snapshots as f32 / seconds,
+            order_rate: self.orders as f32 / seconds,
+            rtt,
+            received_per_second: self.bytes_received as f32 / seconds,
+            sent_per_second: self.bytes_sent as f32 / seconds,
+        };
+        *self = TrafficStats::new(now);
+        report
+    }
+}
+
+/// Rates measured by a bot over a report interval. Bandwidth only counts the
+/// message payloads, not the laminar and UDP headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BotReport {
+    pub snapshot_rate: f32,
+    pub order_rate: f32,
+    /// None until the clock sync has enough samples
+    pub rtt: Option<Duration>,
+    pub received_per_second: f32,
+    pub sent_per_second: f32,
+}
+
+impl BotReport {
+    /// Mean rates and RTT of the bots, the bandwidth is the total of all bots
+    pub fn aggregate(reports: &[BotReport]) -> Option<BotReport> {
+        if reports.is_empty() {
+            return None;
+        }
+        let count = reports.len() as f32;
+        let rtts: Vec<Duration> = reports.iter().filter_map(|report| report.rtt).collect();
+        Some(BotReport {
+            snapshot_rate: reports.iter().map(|r| r.snapshot_rate).sum::<f32>() / count,
+            order_rate: reports.iter().map(|r| r.order_rate).sum::<f32>() / count,
+            rtt: (!rtts.is_empty()).then(|| rtts.iter().sum::<Duration>() / rtts.len() as u32),
+            received_per_second: reports.iter().map(|r| r.received_per_second).sum(),
+            sent_per_second: reports.iter().map(|r| r.sent_per_second).sum(),
+        })
+    }
+}
+
+impl fmt::Display for BotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "snapshots: {:.1}/s, orders: {:.1}/s, rtt: ",
+            self.snapshot_rate, self.order_rate
+        )?;
+        match self.rtt {
+            Some(rtt) => write!(f, "{}ms", rtt.as_millis())?,
+            None => write!(f, "-")?,
+        }
+        write!(
+            f,
+            ", down: {:.1} KiB/s, up: {:.1} KiB/s",
+            self.received_per_second / 1024.0,
+            self.sent_per_second / 1024.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_targets_loop() {
+        let mut script = OrderScript::parse("# patrol\n1 2\n\n 3.5 4 \n").unwrap();
+        assert_eq!(script.next_target(), Vec3A::new(1.0, 0.0, 2.0));
+        assert_eq!(script.next_target(), Vec3A::new(3.5, 0.0, 4.0));
+        assert_eq!(script.next_target(), Vec3A::new(1.0, 0.0, 2.0));
+        assert!(OrderScript::parse("# nothing").is_err());
+        assert!(OrderScript::parse("1 2 3").is_err());
+        assert!(OrderScript::parse("1 x").is_err());
+        let size = MapBounds::default().size;
+        assert!(OrderScript::parse(&format!("1 {}", size.y)).is_err());
+    }
+
+    #[test]
+    fn random_targets_are_on_the_map() {
+        let mut script = OrderScript::random(7);
+        for _ in 0..1000 {
+            let target = script.next_target();
+            assert!(MapBounds::default().contains(target));
+        }
+    }
+
+    #[test]
+    fn reports_rates_over_the_window() {
+        let start = Instant::now();
+        let mut stats = TrafficStats::new(start);
+        (0..60).for_each(|_| stats.record_snapshot());
+        stats.record_order();
+        stats.record_received(4096);
+        stats.record_sent(1024);
+        let report = stats.report(start + Duration::from_secs(2), None);
+        assert_eq!(report.snapshot_rate, 30.0);
+        assert_eq!(report.order_rate, 0.5);
+        assert_eq!(report.received_per_second, 2048.0);
+        assert_eq!(report.sent_per_second, 512.0);
+        // The next window starts empty
+        let next = stats.report(start + Duration::from_secs(3), None);
+        assert_eq!(next.snapshot_rate, 0.0);
+
+        let other = BotReport {
+            snapshot_rate: 20.0,
+            rtt: Some(Duration::from_millis(40)),
+            ..report
+        };
+        let aggregate = BotReport::aggregate(&[report, other]).unwrap();
+        assert_eq!(aggregate.snapshot_rate, 25.0);
+        assert_eq!(aggregate.rtt, Some(Duration::from_millis(40)));
+        assert_eq!(aggregate.received_per_second, 4096.0);
+        assert!(BotReport::aggregate(&[]).is_none());
+    }
+}
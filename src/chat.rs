@@ -0,0 +1,190 @@
+//! Chat messages and map pings sent between players through the server
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::components::PlayerId;
+
+/// Max number of characters in a chat message, longer messages are cut off
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 200;
+/// Max number of characters in a player name, longer names are cut off
+pub const MAX_NAME_LENGTH: usize = 32;
+/// Number of chat messages and pings a client can send back to back
+pub const CHAT_BURST: u32 = 5;
+/// Time it takes to be able to send another message once the burst is used up
+pub const CHAT_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a ping stays marked on the map
+pub const PING_DURATION: Duration = Duration::from_secs(5);
+
+/// Players on the same team share the team channel
+pub type TeamId = u8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatChannel {
+    /// Everyone in the match
+    All,
+    /// The sender's team
+    Team,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingKind {
+    Attention,
+    Attack,
+    Defend,
+    Danger,
+}
+
+impl PingKind {
+    pub const ALL: [PingKind; 4] = [
+        PingKind::Attention,
+        PingKind::Attack,
+        PingKind::Defend,
+        PingKind::Danger,
+    ];
+}
+
+/// The team of the player when playing with the given number of teams, the players are
+/// assigned in turns. Without teams every player is on its own team.
+#[inline]
+pub fn team_of(player: PlayerId, teams: u8) -> TeamId {
+    if teams == 0 {
+        player
+    } else {
+        player % teams
+    }
+}
+
+/// Whether a message or ping sent to the channel reaches the receiver, the teams are None
+/// for spectators. Spectators only talk to other spectators so they can't give away anything
+/// to the players, pings are always sent to the team channel.
+pub fn is_recipient(
+    channel: ChatChannel,
+    sender: Option<TeamId>,
+    receiver: Option<TeamId>,
+) -> bool {
+    match (channel, sender) {
+        (_, None) => receiver.is_none(),
+        (ChatChannel::All, Some(_)) => true,
+        (ChatChannel::Team, Some(team)) => receiver == Some(team),
+    }
+}
+
+/// Trims the message, drops control characters and cuts it to MAX_CHAT_MESSAGE_LENGTH.
+/// None if nothing is left to send.
+pub fn sanitize_message(text: &str) -> Option<String> {
+    sanitize(text, MAX_CHAT_MESSAGE_LENGTH)
+}
+
+/// Cleans up the name a client joins with like a chat message, cut to MAX_NAME_LENGTH.
+/// None if nothing is left to call the client by.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    sanitize(name, MAX_NAME_LENGTH)
+}
+
+fn sanitize(text: &str, max_length: usize) -> Option<String> {
+    let text: String = text
+        .trim()
+        .chars()
+        .filter(|char| !char.is_control())
+        .take(max_length)
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Token bucket limiting how often a client can send chat messages and pings
+#[derive(Debug)]
+pub struct ChatRateLimiter {
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl ChatRateLimiter {
+    pub fn new(now: Instant) -> Self {
+        ChatRateLimiter {
+            tokens: CHAT_BURST,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token if there is one left, false if the message should be dropped
+    pub fn try_send(&mut self, now: Instant) -> bool {
+        let refills = (now.saturating_duration_since(self.last_refill).as_nanos()
+            / CHAT_REFILL_INTERVAL.as_nanos()) as u32;
+        if refills > 0 {
+            self.tokens = (self.tokens + refills).min(CHAT_BURST);
+            self.last_refill += CHAT_REFILL_INTERVAL * refills;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_sanitized() {
+        assert_eq!(sanitize_message("  gg\n"), Some("gg".to_string()));
+        assert_eq!(sanitize_message("a\u{7}b"), Some("ab".to_string()));
+        assert_eq!(sanitize_message(" \t "), None);
+        let long = "å".repeat(MAX_CHAT_MESSAGE_LENGTH * 2);
+        assert_eq!(
+            sanitize_message(&long).unwrap().chars().count(),
+            MAX_CHAT_MESSAGE_LENGTH
+        );
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(
+            sanitize_name(" Player\u{1b}[31m "),
+            Some("Player[31m".to_string())
+        );
+        assert_eq!(sanitize_name("\n"), None);
+        assert_eq!(
+            sanitize_name(&"x".repeat(100)).unwrap().len(),
+            MAX_NAME_LENGTH
+        );
+    }
+
+    #[test]
+    fn channels_reach_the_right_players() {
+        assert!(is_recipient(ChatChannel::All, Some(0), Some(1)));
+        assert!(is_recipient(ChatChannel::All, Some(0), None));
+        assert!(is_recipient(ChatChannel::Team, Some(1), Some(1)));
+        assert!(!is_recipient(ChatChannel::Team, Some(0), Some(1)));
+        assert!(!is_recipient(ChatChannel::Team, Some(0), None));
+        // Spectators stay among themselves
+        assert!(!is_recipient(ChatChannel::All, None, Some(0)));
+        assert!(is_recipient(ChatChannel::Team, None, None));
+
+        assert_eq!(team_of(3, 0), 3);
+        assert_eq!(team_of(3, 2), 1);
+    }
+
+    #[test]
+    fn rate_limiter_refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = ChatRateLimiter::new(start);
+        assert!((0..CHAT_BURST).all(|_| limiter.try_send(start)));
+        assert!(!limiter.try_send(start));
+        let later = start + CHAT_REFILL_INTERVAL + CHAT_REFILL_INTERVAL / 2;
+        assert!(limiter.try_send(later));
+        assert!(!limiter.try_send(later));
+        // The partial interval counts towards the next token
+        assert!(limiter.try_send(start + CHAT_REFILL_INTERVAL * 2));
+        // Never more than the burst
+        let much_later = start + CHAT_REFILL_INTERVAL * 100;
+        assert_eq!(
+            (0..CHAT_BURST * 2)
+                .filter(|_| limiter.try_send(much_later))
+                .count() as u32,
+            CHAT_BURST
+        );
+    }
+}
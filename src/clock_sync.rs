@@ -0,0 +1,273 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::resources::ClientUpdate;
+
+/// Number of ping samples the estimates are filtered over
+const SAMPLE_WINDOW: usize = 8;
+/// Number of pings the packet loss is calculated over
+const LOSS_WINDOW: usize = 32;
+/// Pings that haven't been answered after this long are counted as lost
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time since the server started, sent in pongs so clients can estimate the server clock
+#[derive(Debug, Clone, Copy)]
+pub struct ServerClock {
+    start: Instant,
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        ServerClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl ServerClock {
+    #[inline]
+    pub fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    rtt: Duration,
+    /// Server time minus client time in seconds
+    offset: f64,
+}
+
+/// Client resource estimating the round trip time to the server and the offset
+/// between the server and client clocks from ping/pong exchanges.
+///
+/// Like NTP, the offset is taken from the sample with the lowest round trip time
+/// within the sample window since it's the least affected by queuing delays.
+#[derive(Debug)]
+pub struct ClockSync {
+    epoch: Instant,
+    next_sequence: u32,
+    /// Pings waiting for a pong, oldest first
+    in_flight: VecDeque<(u32, Instant)>,
+    samples: VecDeque<ClockSample>,
+    /// Whether each of the most recent pings was answered, oldest first
+    results: VecDeque<bool>,
+    /// Mean round trip time over the sample window
+    pub rtt: Duration,
+    /// Mean difference between consecutive round trip times
+    pub jitter: Duration,
+    /// Fraction of recent pings that weren't answered
+    pub packet_loss: f32,
+    /// Server time minus client time in seconds
+    pub offset: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        ClockSync {
+            epoch: Instant::now(),
+            next_sequence: 0,
+            in_flight: VecDeque::new(),
+            samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+            results: VecDeque::with_capacity(LOSS_WINDOW),
+            rtt: Duration::ZERO,
+            jitter: Duration::ZERO,
+            packet_loss: 0.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl ClockSync {
+    /// Whether any pong has been received yet. The estimates are meaningless before that.
+    #[inline]
+    pub fn is_synchronized(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Estimated server clock at the given client time
+    pub fn server_time(&self, now: Instant) -> Duration {
+        let client_time = (now - self.epoch).as_secs_f64();
+        Duration::from_secs_f64((client_time + self.offset).max(0.0))
+    }
+
+    /// Creates the next ping to send to the server
+    pub fn ping(&mut self, now: Instant) -> ClientUpdate {
+        self.expire_pings(now);
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.in_flight.push_back((sequence, now));
+        ClientUpdate::Ping { sequence }
+    }
+
+    /// Updates the estimates from a pong received at `now`
+    pub fn receive_pong(&mut self, sequence: u32, server_time: Duration, now: Instant) {
+        self.expire_pings(now);
+        // Duplicated or expired pongs are ignored
+        let sent_at = match self.in_flight.iter().position(|(seq, _)| *seq == sequence) {
+            Some(index) => self.in_flight.remove(index).unwrap().1,
+            None => return,
+        };
+        self.push_result(true);
+        let rtt = now - sent_at;
+        // Assumes the ping and pong took equally long which is the best guess without
+        // knowing the one way delays
+        let midpoint = (sent_at - self.epoch).as_secs_f64() + rtt.as_secs_f64() / 2.0;
+        let offset = server_time.as_secs_f64() - midpoint;
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample { rtt, offset });
+        self.update_estimates();
+    }
+
+    fn update_estimates(&mut self) {
+        let count = self.samples.len() as u32;
+        self.rtt = self
+            .samples
+            .iter()
+            .map(|sample| sample.rtt)
+            .sum::<Duration>()
+            / count;
+        self.jitter = if count > 1 {
+            self.samples
+                .iter()
+                .zip(self.samples.iter().skip(1))
+                .map(|(a, b)| a.rtt.abs_diff(b.rtt))
+                .sum::<Duration>()
+                / (count - 1)
+        } else {
+            Duration::ZERO
+        };
+        if let Some(best) = self.samples.iter().min_by_key(|sample| sample.rtt) {
+            self.offset = best.offset;
+        }
+    }
+
+    fn expire_pings(&mut self, now: Instant) {
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|(_, sent_at)| now - *sent_at > PING_TIMEOUT)
+        {
+            self.in_flight.pop_front();
+            self.push_result(false);
+        }
+    }
+
+    fn push_result(&mut self, answered: bool) {
+        if self.results.len() == LOSS_WINDOW {
+            self.results.pop_front();
+        }
+        self.results.push_back(answered);
+        let lost = self.results.iter().filter(|answered| !**answered).count();
+        self.packet_loss = lost as f32 / self.results.len() as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pings a simulated server whose clock is `offset` ahead of the client
+    fn exchange(
+        clock_sync: &mut ClockSync,
+        now: &mut Instant,
+        offset: Duration,
+        to_server: Duration,
+        to_client: Duration,
+    ) {
+        let sequence = match clock_sync.ping(*now) {
+            ClientUpdate::Ping { sequence } => sequence,
+            _ => unreachable!(),
+        };
+        *now += to_server;
+        let server_time = (*now - clock_sync.epoch) + offset;
+        *now += to_client;
+        clock_sync.receive_pong(sequence, server_time, *now);
+    }
+
+    #[test]
+    fn estimates_symmetric_link() {
+        let mut clock_sync = ClockSync::default();
+        let mut now = clock_sync.epoch;
+        let offset = Duration::from_secs(5);
+        for _ in 0..4 {
+            exchange(
+                &mut clock_sync,
+                &mut now,
+                offset,
+                Duration::from_millis(50),
+                Duration::from_millis(50),
+            );
+        }
+        assert!(clock_sync.is_synchronized());
+        assert_eq!(clock_sync.rtt, Duration::from_millis(100));
+        assert_eq!(clock_sync.jitter, Duration::ZERO);
+        assert_eq!(clock_sync.packet_loss, 0.0);
+        assert!((clock_sync.offset - offset.as_secs_f64()).abs() < 1e-6);
+        let server_time = clock_sync.server_time(now);
+        assert!(
+            (server_time.as_secs_f64() - ((now - clock_sync.epoch) + offset).as_secs_f64()).abs()
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn offset_uses_fastest_sample() {
+        let mut clock_sync = ClockSync::default();
+        let mut now = clock_sync.epoch;
+        let offset = Duration::from_secs(1);
+        // Queuing delays on the way back skew the offset of the slow samples
+        exchange(
+            &mut clock_sync,
+            &mut now,
+            offset,
+            Duration::from_millis(20),
+            Duration::from_millis(300),
+        );
+        exchange(
+            &mut clock_sync,
+            &mut now,
+            offset,
+            Duration::from_millis(20),
+            Duration::from_millis(20),
+        );
+        exchange(
+            &mut clock_sync,
+            &mut now,
+            offset,
+            Duration::from_millis(20),
+            Duration::from_millis(220),
+        );
+        assert!((clock_sync.offset - offset.as_secs_f64()).abs() < 1e-6);
+        assert_eq!(clock_sync.rtt, Duration::from_millis(200));
+        assert_eq!(clock_sync.jitter, Duration::from_millis(240));
+    }
+
+    #[test]
+    fn counts_lost_pings() {
+        let mut clock_sync = ClockSync::default();
+        let mut now = clock_sync.epoch;
+        let lost = clock_sync.ping(now);
+        let answered = match clock_sync.ping(now) {
+            ClientUpdate::Ping { sequence } => sequence,
+            _ => unreachable!(),
+        };
+        now += Duration::from_millis(100);
+        clock_sync.receive_pong(answered, Duration::ZERO, now);
+        // A duplicated pong is ignored
+        clock_sync.receive_pong(answered, Duration::ZERO, now);
+        assert_eq!(clock_sync.packet_loss, 0.0);
+        now += PING_TIMEOUT;
+        clock_sync.ping(now);
+        assert_eq!(clock_sync.packet_loss, 0.5);
+        // A pong arriving after the timeout doesn't count
+        if let ClientUpdate::Ping { sequence } = lost {
+            clock_sync.receive_pong(sequence, Duration::ZERO, now);
+        }
+        assert_eq!(clock_sync.packet_loss, 0.5);
+    }
+}
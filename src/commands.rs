@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::resources::{ClientUpdate, Command};
+
+/// Max size in bytes of a command batch payload, small enough to fit a single packet
+/// without fragmentation
+pub const MAX_COMMAND_PAYLOAD_SIZE: usize = 1024;
+/// Batches that haven't been acknowledged after this long are sent again
+pub const COMMAND_RESEND_INTERVAL: Duration = Duration::from_millis(200);
+/// Max number of batches the server buffers ahead of the next one it expects
+pub const MAX_PENDING_BATCHES: u32 = 256;
+
+/// Client side collection of the commands issued during a frame into ClientCommands batches.
+/// The batches are sent unreliably and resent until the server acknowledges them, laminar
+/// only notices a lost reliable packet after many later packets have been acked which
+/// rarely happens with the few packets sent for commands.
+#[derive(Debug, Default)]
+pub struct CommandBatcher {
+    /// The last server tick received
+    server_tick: u64,
+    next_sequence: u32,
+    queued: Vec<Command>,
+    /// Serialized batches waiting for an ack with the time they were last sent
+    unacknowledged: VecDeque<(u32, Vec<u8>, Instant)>,
+}
+
+impl CommandBatcher {
+    #[inline]
+    pub fn set_server_tick(&mut self, tick: u64) {
+        self.server_tick = self.server_tick.max(tick);
+    }
+
+    /// Queues a command to be sent with the batch of the current frame
+    #[inline]
+    pub fn push(&mut self, command: Command) {
+        self.queued.push(command);
+    }
+
+    /// Number of batches sent but not acknowledged yet
+    #[inline]
+    pub fn unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    /// The server has received all batches up to and including the sequence
+    pub fn acknowledge(&mut self, sequence: u32) {
+        while self
+            .unacknowledged
+            .front()
+            .is_some_and(|(unacknowledged, _, _)| *unacknowledged <= sequence)
+        {
+            self.unacknowledged.pop_front();
+        }
+    }
+
+    /// Batches the queued commands and returns the payloads to send now, the new batches
+    /// followed by the ones that are due to be resent. `serialize` serializes a batch
+    /// with the client's token.
+    pub fn flush(
+        &mut self,
+        now: Instant,
+        serialize: impl Fn(&ClientUpdate) -> Vec<u8>,
+    ) -> Vec<Vec<u8>> {
+        let mut payloads: Vec<Vec<u8>> = self
+            .unacknowledged
+            .iter_mut()
+            .filter(|(_, _, sent)| now.saturating_duration_since(*sent) >= COMMAND_RESEND_INTERVAL)
+            .map(|(_, payload, sent)| {
+                *sent = now;
+                payload.clone()
+            })
+            .collect();
+        let commands = std::mem::take(&mut self.queued);
+        if commands.is_empty() {
+            return payloads;
+        }
+        // The commands are encoded one after the other so their sizes add up, which avoids
+        // serializing the growing batch again for every command
+        let empty_size = serialize(&self.batch(Vec::new())).len();
+        let mut batch: Vec<Command> = Vec::new();
+        let mut batch_size = empty_size;
+        for command in commands {
+            let command_size = serialize(&self.batch(vec![command.clone()])).len() - empty_size;
+            if batch_size + command_size > MAX_COMMAND_PAYLOAD_SIZE && !batch.is_empty() {
+                // Doesn't fit anymore, the command starts the next batch
+                let payload = serialize(&self.batch(std::mem::take(&mut batch)));
+                self.push_batch(payload, now, &mut payloads);
+                batch_size = empty_size;
+            }
+            batch.push(command);
+            batch_size += command_size;
+        }
+        let payload = serialize(&self.batch(batch));
+        self.push_batch(payload, now, &mut payloads);
+        payloads
+    }
+
+    fn batch(&self, commands: Vec<Command>) -> ClientUpdate {
+        ClientUpdate::ClientCommands {
+            tick: self.server_tick,
+            sequence: self.next_sequence,
+            commands,
+        }
+    }
+
+    fn push_batch(&mut self, payload: Vec<u8>, now: Instant, payloads: &mut Vec<Vec<u8>>) {
+        self.unacknowledged
+            .push_back((self.next_sequence, payload.clone(), now));
+        self.next_sequence += 1;
+        payloads.push(payload);
+    }
+}
+
+/// Server side ordering and deduplication of the command batches of a client
+#[derive(Debug, Default)]
+pub struct CommandSequencer {
+    next_sequence: u32,
+    pending: BTreeMap<u32, Vec<Command>>,
+}
+
+impl CommandSequencer {
+    /// Receives a batch and returns the commands that can be applied now in the order they
+    /// were issued. Duplicates yield no commands. None if the batch is too far ahead.
+    pub fn receive(&mut self, sequence: u32, commands: Vec<Command>) -> Option<Vec<Command>> {
+        if sequence >= self.next_sequence.saturating_add(MAX_PENDING_BATCHES) {
+            return None;
+        }
+        if sequence >= self.next_sequence {
+            self.pending.entry(sequence).or_insert(commands);
+        }
+        let mut ready = Vec::new();
+        while let Some(commands) = self.pending.remove(&self.next_sequence) {
+            ready.extend(commands);
+            self.next_sequence += 1;
+        }
+        Some(ready)
+    }
+
+    /// The last sequence of the batches that have all been received
+    #[inline]
+    pub fn acknowledged(&self) -> Option<u32> {
+        self.next_sequence.checked_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{components::EntityType, resources::NetworkSerialization};
+    use glam::Vec3A;
+    use legion::{Entity, IntoQuery, World};
+
+    /// Orders for units the server has replicated to the client
+    fn move_orders(
+        count: usize,
+        server: &NetworkSerialization,
+        client: &NetworkSerialization,
+    ) -> Vec<Command> {
+        let mut world = World::default();
+        world.extend((0..count).map(|_| (EntityType::BasicUnit,)));
+        let client_world = client
+            .deserialize_new_world(&server.serialize_world(&world, legion::any()))
+            .unwrap();
+        <Entity>::query()
+            .iter(&client_world)
+            .map(|entity| Command::Move {
+                entity: *entity,
+                target: Vec3A::new(1.0, 0.0, 2.0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_are_split_and_applied_in_order() {
+        let client_serialization = NetworkSerialization::default();
+        let server_serialization = NetworkSerialization::authoritative();
+        let orders = move_orders(100, &server_serialization, &client_serialization);
+        let mut batcher = CommandBatcher::default();
+        batcher.set_server_tick(42);
+        orders.iter().cloned().for_each(|order| batcher.push(order));
+        let now = Instant::now();
+        let payloads = batcher.flush(now, |batch| {
+            client_serialization.serialize_session_update(7, batch)
+        });
+        assert!(payloads.len() > 1);
+        assert!(payloads
+            .iter()
+            .all(|payload| payload.len() <= MAX_COMMAND_PAYLOAD_SIZE));
+        // Only the last batch has room for another command
+        let batch_size = |count: usize| {
+            let mut batcher = CommandBatcher::default();
+            orders[..count]
+                .iter()
+                .for_each(|order| batcher.push(order.clone()));
+            batcher.flush(now, |batch| {
+                client_serialization.serialize_session_update(7, batch)
+            })[0]
+                .len()
+        };
+        let command_size = batch_size(2) - batch_size(1);
+        assert!(payloads[..payloads.len() - 1]
+            .iter()
+            .all(|payload| payload.len() + command_size > MAX_COMMAND_PAYLOAD_SIZE));
+        assert_eq!(batcher.unacknowledged(), payloads.len());
+
+        let mut sequencer = CommandSequencer::default();
+        let mut received = Vec::new();
+        // Delivered in reverse with a duplicate
+        for payload in payloads.iter().rev().chain(payloads.first()) {
+            let message = server_serialization
+                .deserialize_client_update(payload)
+                .unwrap();
+            assert_eq!(message.token, Some(7));
+            match message.update {
+                ClientUpdate::ClientCommands {
+                    tick,
+                    sequence,
+                    commands,
+                } => {
+                    assert_eq!(tick, 42);
+                    received.extend(sequencer.receive(sequence, commands).unwrap());
+                }
+                update => panic!("Unexpected update: {:?}", update),
+            }
+        }
+        assert_eq!(received.len(), orders.len());
+        // The server sees the client's entities by their network ids
+        let network_ids = |commands: &[Command], serialization: &NetworkSerialization| {
+            commands
+                .iter()
+                .map(|command| match command {
+                    Command::Move { entity, .. } => serialization.network_id(*entity),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            network_ids(&received, &server_serialization),
+            network_ids(&orders, &client_serialization)
+        );
+        assert_eq!(sequencer.acknowledged(), Some(payloads.len() as u32 - 1));
+    }
+
+    #[test]
+    fn unacknowledged_batches_are_resent() {
+        let serialization = NetworkSerialization::default();
+        let serialize = |batch: &ClientUpdate| serialization.serialize_session_update(7, batch);
+        let orders = move_orders(2, &NetworkSerialization::authoritative(), &serialization);
+        let mut batcher = CommandBatcher::default();
+        let now = Instant::now();
+        assert!(batcher.flush(now, serialize).is_empty());
+        batcher.push(orders[0].clone());
+        assert_eq!(batcher.flush(now, serialize).len(), 1);
+        batcher.push(orders[1].clone());
+        assert_eq!(
+            batcher
+                .flush(now + Duration::from_millis(10), serialize)
+                .len(),
+            1
+        );
+        // Both are resent until acknowledged
+        let later = now + COMMAND_RESEND_INTERVAL + Duration::from_millis(10);
+        assert_eq!(batcher.flush(later, serialize).len(), 2);
+        batcher.acknowledge(0);
+        assert_eq!(batcher.unacknowledged(), 1);
+        assert!(batcher.flush(later, serialize).is_empty());
+        batcher.acknowledge(1);
+        assert_eq!(batcher.unacknowledged(), 0);
+        assert!(batcher
+            .flush(later + COMMAND_RESEND_INTERVAL, serialize)
+            .is_empty());
+    }
+
+    #[test]
+    fn sequencer_rejects_batches_far_ahead() {
+        let mut sequencer = CommandSequencer::default();
+        assert_eq!(sequencer.acknowledged(), None);
+        assert!(sequencer.receive(MAX_PENDING_BATCHES, Vec::new()).is_none());
+        assert_eq!(sequencer.receive(1, Vec::new()), Some(Vec::new()));
+        assert_eq!(sequencer.acknowledged(), None);
+        assert_eq!(sequencer.receive(0, Vec::new()), Some(Vec::new()));
+        assert_eq!(sequencer.acknowledged(), Some(1));
+    }
+}
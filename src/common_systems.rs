@@ -7,7 +7,7 @@ use winit::event::MouseButton;
 
 use crate::{
     assets::{Assets, Handle},
-    components::{Selectable, Transform},
+    components::{Hidden, Selectable, Transform},
     input::{CursorPosition, MouseButtonState},
     rendering::{
         camera::Camera, drawable_tilemap::*, gltf::GltfModel, ui::ui_resources::UiContext,
@@ -92,7 +92,12 @@ pub fn selection(
     #[resource] ui_ctx: &mut UiContext,
     #[resource] tilemap_handle: &Handle<DrawableTileMap<'static>>,
     #[resource] map_assets: &mut Assets<DrawableTileMap<'static>>,
-    query: &mut Query<(&Transform, &Handle<GltfModel>, &mut Selectable)>,
+    query: &mut Query<(
+        &Transform,
+        &Handle<GltfModel>,
+        &mut Selectable,
+        Option<&Hidden>,
+    )>,
 ) {
     if mouse_button_state.pressed_current_frame(&MouseButton::Left) {
         state.start_selection = Some(*mouse_pos);
@@ -131,13 +136,19 @@ pub fn selection(
                     // the map itself when not rotated.
                     let min_tile = screen_min_tile.min(screen_max_tile);
                     let max_tile = screen_max_tile.max(screen_min_tile);
-                    query.par_for_each_mut(world, |(transform, _handle, mut selectable)| {
-                        let tile_pos = tilemap
-                            .to_tile_coords(transform.matrix.translation)
-                            .unwrap();
-                        selectable.is_selected =
-                            tile_pos.cmpge(min_tile).all() && tile_pos.cmple(max_tile).all();
-                    });
+                    query.par_for_each_mut(
+                        world,
+                        |(transform, _handle, mut selectable, hidden)| {
+                            if hidden.is_some() {
+                                return;
+                            }
+                            let tile_pos = tilemap
+                                .to_tile_coords(transform.matrix.translation)
+                                .unwrap();
+                            selectable.is_selected =
+                                tile_pos.cmpge(min_tile).all() && tile_pos.cmple(max_tile).all();
+                        },
+                    );
                 }
             }
         } else {
@@ -149,7 +160,10 @@ pub fn selection(
                 window_size,
             );
             let dirfrac = ray.direction.recip();
-            query.par_for_each_mut(world, |(transform, handle, mut selectable)| {
+            query.par_for_each_mut(world, |(transform, handle, mut selectable, hidden)| {
+                if hidden.is_some() {
+                    return;
+                }
                 let model = asset_storage.get(handle).unwrap();
                 let (min, max) = (model.min_vertex, model.max_vertex);
                 let world_min = transform.matrix.transform_point3a(min.into());
@@ -12,11 +12,25 @@ pub struct Velocity {
     pub velocity: Vec3,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum EntityType {
     BasicUnit,
 }
 
+/// Identifies a player, assigned by the server in the order the players connected
+pub type PlayerId = u8;
+
+/// The player controlling the entity
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub player: PlayerId,
+}
+
+/// Marks entities the client currently doesn't receive updates for,
+/// these keep their last known state but aren't drawn or selectable
+#[derive(Debug, Clone, Copy)]
+pub struct Hidden;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
 pub struct Transform {
     pub matrix: Affine3A,
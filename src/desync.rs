@@ -0,0 +1,235 @@
+use std::fmt;
+
+use legion::{Entity, EntityStore};
+use serde::{Deserialize, Serialize};
+
+use crate::components::{EntityType, Owner, Transform};
+
+/// Number of state updates between the ones carrying a state checksum
+pub const STATE_CHECKSUM_INTERVAL: u64 = 30;
+/// Transforms are compared in steps of this size so harmless rounding differences
+/// don't show up as desyncs
+const QUANTIZATION_STEPS: f32 = 1024.0;
+
+/// FNV-1a, stable across platforms and builds unlike the std hasher
+#[derive(Debug)]
+pub(crate) struct Fnv1a(pub u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn write_f32s(&mut self, values: &[f32]) {
+        values
+            .iter()
+            .for_each(|value| self.write(&value.to_bits().to_le_bytes()));
+    }
+}
+
+/// The replicated components of an entity as the server or client sees them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReplicatedState {
+    pub entity: Entity,
+    pub entity_type: EntityType,
+    pub transform: Transform,
+    pub owner: Option<Owner>,
+}
+
+impl ReplicatedState {
+    /// Reads the replicated components of the entity, None if it isn't a replicated entity.
+    /// The transform is passed in since the client's may not be the replicated one.
+    pub fn read(world: &impl EntityStore, entity: Entity, transform: Transform) -> Option<Self> {
+        let entry = world.entry_ref(entity).ok()?;
+        Some(ReplicatedState {
+            entity,
+            entity_type: *entry.get_component::<EntityType>().ok()?,
+            transform,
+            owner: entry.get_component::<Owner>().ok().copied(),
+        })
+    }
+
+    fn quantized_transform(&self) -> [i32; 12] {
+        let mut quantized = [0; 12];
+        for (quantized, value) in quantized
+            .iter_mut()
+            .zip(self.transform.matrix.to_cols_array())
+        {
+            *quantized = (value * QUANTIZATION_STEPS).round() as i32;
+        }
+        quantized
+    }
+
+    /// Hash of the state identifying the entity by the network id it's replicated with
+    pub fn hash(&self, network_id: u64) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&network_id.to_le_bytes());
+        hasher.write(&[self.entity_type as u8]);
+        self.quantized_transform()
+            .iter()
+            .for_each(|value| hasher.write(&value.to_le_bytes()));
+        hasher.write(&[self.owner.map_or(u8::MAX, |owner| owner.player)]);
+        hasher.0
+    }
+
+    fn matches(&self, other: &ReplicatedState) -> bool {
+        self.entity_type == other.entity_type
+            && self.owner == other.owner
+            && self.quantized_transform() == other.quantized_transform()
+    }
+}
+
+/// Combines the hashes of the entities in an order independent way
+/// since the server and the clients iterate the entities in different orders
+pub fn state_checksum(hashes: impl IntoIterator<Item = u64>) -> u64 {
+    hashes.into_iter().fold(0, u64::wrapping_add)
+}
+
+/// Difference between the server's and the client's state of an entity
+#[derive(Debug)]
+pub enum EntityDiff {
+    /// The client doesn't have an entity the server sent
+    Missing(ReplicatedState),
+    Mismatch {
+        server: ReplicatedState,
+        client: ReplicatedState,
+    },
+}
+
+impl fmt::Display for EntityDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntityDiff::Missing(server) => write!(f, "{:?} is missing", server.entity),
+            EntityDiff::Mismatch { server, client } => {
+                write!(f, "{:?}:", server.entity)?;
+                if server.entity_type != client.entity_type {
+                    write!(
+                        f,
+                        " type {:?} != {:?}",
+                        server.entity_type, client.entity_type
+                    )?;
+                }
+                if server.owner != client.owner {
+                    write!(f, " owner {:?} != {:?}", server.owner, client.owner)?;
+                }
+                if server.quantized_transform() != client.quantized_transform() {
+                    write!(
+                        f,
+                        " translation {} != {}",
+                        server.transform.matrix.translation, client.transform.matrix.translation
+                    )?;
+                    if server.transform.matrix.matrix3 != client.transform.matrix.matrix3 {
+                        write!(f, " (rotation or scale differs)")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compares the server's state of the entities with the client's. `client_state` looks
+/// up the client's state of the entity, None if the client doesn't have it.
+pub fn diff(
+    server_states: &[ReplicatedState],
+    client_state: impl Fn(Entity) -> Option<ReplicatedState>,
+) -> Vec<EntityDiff> {
+    server_states
+        .iter()
+        .filter_map(|server| match client_state(server.entity) {
+            None => Some(EntityDiff::Missing(*server)),
+            Some(client) if !server.matches(&client) => Some(EntityDiff::Mismatch {
+                server: *server,
+                client,
+            }),
+            Some(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+    use legion::World;
+
+    fn state(entity: Entity, position: Vec3, player: u8) -> ReplicatedState {
+        ReplicatedState {
+            entity,
+            entity_type: EntityType::BasicUnit,
+            transform: Transform::from_position(position),
+            owner: Some(Owner { player }),
+        }
+    }
+
+    #[test]
+    fn checksum_ignores_order_and_rounding() {
+        let mut world = World::default();
+        let (a, b) = (world.push(()), world.push(()));
+        let states = [
+            state(a, Vec3::new(1.0, 0.0, 2.0), 0),
+            state(b, Vec3::new(5.0, 0.0, 2.0), 1),
+        ];
+        let ids = [1, 2];
+        let checksum = state_checksum(states.iter().zip(&ids).map(|(s, n)| s.hash(*n)));
+        let reversed = state_checksum(states.iter().zip(&ids).rev().map(|(s, n)| s.hash(*n)));
+        assert_eq!(checksum, reversed);
+
+        let mut rounded = states;
+        rounded[0].transform.matrix.translation.x += 0.00001;
+        let rounded_checksum = state_checksum(rounded.iter().zip(&ids).map(|(s, n)| s.hash(*n)));
+        assert_eq!(checksum, rounded_checksum);
+
+        let mut moved = states;
+        moved[1].transform.matrix.translation.z += 0.5;
+        let moved_checksum = state_checksum(moved.iter().zip(&ids).map(|(s, n)| s.hash(*n)));
+        assert_ne!(checksum, moved_checksum);
+
+        // The same state on another entity is a different state
+        let swapped_ids = [2, 1];
+        let swapped = state_checksum(states.iter().zip(&swapped_ids).map(|(s, n)| s.hash(*n)));
+        assert_ne!(checksum, swapped);
+    }
+
+    #[test]
+    fn diff_lists_missing_and_mismatching_entities() {
+        let mut world = World::default();
+        let (same, moved, stolen, missing) = (
+            world.push(()),
+            world.push(()),
+            world.push(()),
+            world.push(()),
+        );
+        let server_states = [
+            state(same, Vec3::ZERO, 0),
+            state(moved, Vec3::ONE, 0),
+            state(stolen, Vec3::ONE, 0),
+            state(missing, Vec3::ONE, 0),
+        ];
+        let diffs = diff(&server_states, |entity| {
+            if entity == same {
+                Some(state(same, Vec3::ZERO, 0))
+            } else if entity == moved {
+                Some(state(moved, Vec3::ZERO, 0))
+            } else if entity == stolen {
+                Some(state(stolen, Vec3::ONE, 1))
+            } else {
+                None
+            }
+        });
+        assert_eq!(diffs.len(), 3);
+        let messages: Vec<String> = diffs.iter().map(ToString::to_string).collect();
+        assert!(messages[0].contains("translation"));
+        assert!(messages[1].contains("owner") && !messages[1].contains("translation"));
+        assert!(
+            matches!(diffs[2], EntityDiff::Missing(missing_state) if missing_state.entity == missing)
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// The match clock. Unlike `resources::Time` it doesn't follow the wall clock: it only moves
+/// when a tick is simulated, stands still while the match is paused and runs faster or slower
+/// with its scale. Systems that measure game time (movement, cooldowns, production) read it.
+///
+/// The server's clock is authoritative and replicated to the clients with the snapshots.
+/// Pausing is announced with notices instead, snapshots aren't sent while paused.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GameTime {
+    tick: u64,
+    /// Game seconds since the start of the match, kept as f64 to stay precise in long matches
+    elapsed: f64,
+    scale: f32,
+    #[serde(skip)]
+    delta: f32,
+    #[serde(skip)]
+    paused: bool,
+}
+
+impl GameTime {
+    /// Runs the next tick that took `tick_duration` seconds of real time.
+    /// Does nothing while paused.
+    pub fn advance(&mut self, tick_duration: f32) {
+        if self.paused {
+            self.delta = 0.0;
+            return;
+        }
+        self.tick += 1;
+        self.delta = tick_duration * self.scale;
+        self.elapsed += self.delta as f64;
+    }
+
+    /// Number of the last simulated tick, starting from 1
+    #[inline]
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Game seconds simulated by the last tick
+    #[inline]
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta
+    }
+
+    #[inline]
+    pub fn elapsed_seconds(&self) -> f64 {
+        self.elapsed
+    }
+
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(self.elapsed)
+    }
+
+    /// How many game seconds pass per real second
+    #[inline]
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        assert!(
+            scale.is_finite() && scale > 0.0,
+            "The time scale must be positive"
+        );
+        self.scale = scale;
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    #[inline]
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Converts real seconds, like the duration of a rendered frame, to game seconds
+    #[inline]
+    pub fn scaled(&self, seconds: f32) -> f32 {
+        if self.paused {
+            0.0
+        } else {
+            seconds * self.scale
+        }
+    }
+
+    /// Takes over the replicated clock of the server unless it's older than the current
+    /// one. Whether the match is paused is kept, see `set_paused`.
+    pub fn synchronize(&mut self, server: &GameTime) {
+        if server.tick >= self.tick {
+            self.tick = server.tick;
+            self.elapsed = server.elapsed;
+            self.scale = server.scale;
+        }
+    }
+}
+
+impl Default for GameTime {
+    fn default() -> Self {
+        GameTime {
+            tick: 0,
+            elapsed: 0.0,
+            scale: 1.0,
+            delta: 0.0,
+            paused: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pausing_and_scaling() {
+        let mut game_time = GameTime::default();
+        game_time.advance(0.5);
+        assert_eq!(game_time.tick(), 1);
+        assert_eq!(game_time.delta_seconds(), 0.5);
+        game_time.set_paused(true);
+        game_time.advance(0.5);
+        assert_eq!(game_time.tick(), 1);
+        assert_eq!(game_time.delta_seconds(), 0.0);
+        assert_eq!(game_time.scaled(0.5), 0.0);
+        game_time.set_paused(false);
+        game_time.set_scale(2.0);
+        game_time.advance(0.5);
+        assert_eq!(game_time.tick(), 2);
+        assert_eq!(game_time.delta_seconds(), 1.0);
+        assert_eq!(game_time.elapsed(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn synchronizes_with_newer_clocks() {
+        let mut server = GameTime::default();
+        server.set_scale(0.5);
+        server.advance(1.0);
+        server.advance(1.0);
+        let mut client = GameTime::default();
+        client.set_paused(true);
+        client.synchronize(&server);
+        assert_eq!(client.tick(), 2);
+        assert_eq!(client.elapsed_seconds(), 1.0);
+        assert_eq!(client.scale(), 0.5);
+        assert!(client.is_paused());
+        // Snapshots arriving out of order don't turn the clock back
+        let mut old = GameTime::default();
+        old.advance(1.0);
+        client.synchronize(&old);
+        assert_eq!(client.tick(), 2);
+    }
+}
@@ -3,18 +3,36 @@ extern crate log;
 
 #[cfg(feature = "graphics")]
 pub mod assets;
+pub mod bot;
+pub mod chat;
+pub mod clock_sync;
+pub mod commands;
 #[cfg(feature = "graphics")]
 pub mod common_systems;
 pub mod components;
+pub mod desync;
 #[cfg(feature = "graphics")]
 pub mod engine;
+pub mod game_time;
 #[cfg(feature = "graphics")]
 pub mod input;
+pub mod link_conditioner;
+pub mod lockstep;
 pub mod map_chunk;
+pub mod metrics;
 pub mod navigation;
+pub mod network_stats;
+pub mod relevancy;
 #[cfg(feature = "graphics")]
 pub mod rendering;
+pub mod replay;
 pub mod resources;
+pub mod server;
+pub mod simulation;
 #[cfg(feature = "graphics")]
 pub mod states;
+pub mod telemetry;
 pub mod tilemap;
+pub mod timestep;
+pub mod transfer;
+pub mod transform_encoding;
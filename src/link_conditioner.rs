@@ -0,0 +1,408 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use laminar::DatagramSocket;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+/// Simulates a bad network connection by delaying, dropping and duplicating datagrams.
+/// It's applied below laminar so reliable packets are resent as they would be on a real
+/// network. Both outgoing and incoming datagrams are conditioned, meaning the latency is
+/// added twice to the round trip time of a socket using it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConditioner {
+    /// Delay in milliseconds added to every datagram
+    pub latency_ms: u64,
+    /// Max random delay in milliseconds added on top of the latency
+    pub jitter_ms: u64,
+    /// Probability of a datagram being dropped, between 0 and 1
+    pub packet_loss: f64,
+    /// Probability of a datagram being delivered twice, between 0 and 1
+    pub duplication: f64,
+    /// Seed of the random delays, losses and duplicates to make them reproducible,
+    /// seeded from entropy if not set
+    pub seed: Option<u64>,
+}
+
+impl LinkConditioner {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.packet_loss),
+            "Packet loss must be between 0 and 1"
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.duplication),
+            "Duplication must be between 0 and 1"
+        );
+        Ok(())
+    }
+
+    fn delay(&self, rng: &mut StdRng) -> Duration {
+        let jitter = if self.jitter_ms > 0 {
+            rng.gen_range(0..=self.jitter_ms)
+        } else {
+            0
+        };
+        Duration::from_millis(self.latency_ms + jitter)
+    }
+}
+
+/// Command line options for the link conditioner, flattened into the client and server arguments
+#[derive(Debug, StructOpt)]
+pub struct LinkConditionerArgs {
+    /// Simulated latency in milliseconds added to incoming and outgoing packets
+    #[structopt(long)]
+    latency: Option<u64>,
+    /// Simulated max random latency in milliseconds added on top of the latency
+    #[structopt(long)]
+    jitter: Option<u64>,
+    /// Simulated probability of a packet being dropped, between 0 and 1
+    #[structopt(long)]
+    packet_loss: Option<f64>,
+    /// Simulated probability of a packet being duplicated, between 0 and 1
+    #[structopt(long)]
+    duplication: Option<f64>,
+}
+
+impl LinkConditionerArgs {
+    /// Overrides the values of the configured link conditioner, a conditioner
+    /// is created if none is configured and any of the arguments are given
+    pub fn apply(
+        &self,
+        link_conditioner: Option<LinkConditioner>,
+    ) -> anyhow::Result<Option<LinkConditioner>> {
+        let any_given = self.latency.is_some()
+            || self.jitter.is_some()
+            || self.packet_loss.is_some()
+            || self.duplication.is_some();
+        let mut link_conditioner = match link_conditioner {
+            Some(link_conditioner) => link_conditioner,
+            None if any_given => LinkConditioner::default(),
+            None => return Ok(None),
+        };
+        if let Some(latency) = self.latency {
+            link_conditioner.latency_ms = latency;
+        }
+        if let Some(jitter) = self.jitter {
+            link_conditioner.jitter_ms = jitter;
+        }
+        if let Some(packet_loss) = self.packet_loss {
+            link_conditioner.packet_loss = packet_loss;
+        }
+        if let Some(duplication) = self.duplication {
+            link_conditioner.duplication = duplication;
+        }
+        link_conditioner.validate()?;
+        Ok(Some(link_conditioner))
+    }
+}
+
+#[derive(Debug)]
+struct DelayedDatagram {
+    release_at: Instant,
+    // Keeps datagrams released at the same time in the order they were queued
+    order: u64,
+    addr: SocketAddr,
+    payload: Box<[u8]>,
+}
+
+impl PartialEq for DelayedDatagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayedDatagram {}
+
+impl PartialOrd for DelayedDatagram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedDatagram {
+    // Reversed so the BinaryHeap pops the datagram that should be released first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.release_at, other.order).cmp(&(self.release_at, self.order))
+    }
+}
+
+/// Udp socket used by NetworkSocket, passes datagrams straight through
+/// unless a link conditioner is set.
+#[derive(Debug)]
+pub(crate) struct ConditionedSocket {
+    socket: UdpSocket,
+    is_blocking_mode: bool,
+    conditioner: Option<LinkConditioner>,
+    rng: StdRng,
+    outgoing: BinaryHeap<DelayedDatagram>,
+    incoming: BinaryHeap<DelayedDatagram>,
+    next_order: u64,
+    receive_buffer: Box<[u8]>,
+}
+
+impl ConditionedSocket {
+    pub(crate) fn new(
+        socket: UdpSocket,
+        is_blocking_mode: bool,
+        conditioner: Option<LinkConditioner>,
+    ) -> io::Result<Self> {
+        // Delayed datagrams can only be released if the socket never blocks
+        let is_blocking_mode = is_blocking_mode && conditioner.is_none();
+        socket.set_nonblocking(!is_blocking_mode)?;
+        let rng = match conditioner.and_then(|conditioner| conditioner.seed) {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(ConditionedSocket {
+            socket,
+            is_blocking_mode,
+            conditioner,
+            rng,
+            outgoing: BinaryHeap::new(),
+            incoming: BinaryHeap::new(),
+            next_order: 0,
+            receive_buffer: vec![0; u16::MAX as usize].into_boxed_slice(),
+        })
+    }
+
+    /// Queues the datagram unless it's lost, possibly more than once
+    fn condition(
+        conditioner: &LinkConditioner,
+        rng: &mut StdRng,
+        next_order: &mut u64,
+        queue: &mut BinaryHeap<DelayedDatagram>,
+        addr: SocketAddr,
+        payload: &[u8],
+    ) {
+        if rng.gen_bool(conditioner.packet_loss) {
+            return;
+        }
+        let copies = if rng.gen_bool(conditioner.duplication) {
+            2
+        } else {
+            1
+        };
+        let now = Instant::now();
+        for _ in 0..copies {
+            queue.push(DelayedDatagram {
+                release_at: now + conditioner.delay(rng),
+                order: *next_order,
+                addr,
+                payload: Box::from(payload),
+            });
+            *next_order += 1;
+        }
+    }
+
+    fn pop_released(
+        queue: &mut BinaryHeap<DelayedDatagram>,
+        now: Instant,
+    ) -> Option<DelayedDatagram> {
+        if queue.peek()?.release_at <= now {
+            queue.pop()
+        } else {
+            None
+        }
+    }
+
+    fn flush_outgoing(&mut self, now: Instant) {
+        while let Some(datagram) = Self::pop_released(&mut self.outgoing, now) {
+            if let Err(err) = self.socket.send_to(&datagram.payload, datagram.addr) {
+                error!("Failed to send delayed datagram: {}", err);
+            }
+        }
+    }
+}
+
+impl DatagramSocket for ConditionedSocket {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        match self.conditioner {
+            Some(conditioner) => {
+                Self::condition(
+                    &conditioner,
+                    &mut self.rng,
+                    &mut self.next_order,
+                    &mut self.outgoing,
+                    *addr,
+                    payload,
+                );
+                self.flush_outgoing(Instant::now());
+                Ok(payload.len())
+            }
+            None => self.socket.send_to(payload, addr),
+        }
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        let conditioner = match self.conditioner {
+            Some(conditioner) => conditioner,
+            None => {
+                return self
+                    .socket
+                    .recv_from(buffer)
+                    .map(move |(len, addr)| (&buffer[..len], addr))
+            }
+        };
+        // The socket is polled continuously so this is where delayed datagrams are sent
+        let now = Instant::now();
+        self.flush_outgoing(now);
+        loop {
+            match self.socket.recv_from(&mut self.receive_buffer) {
+                Ok((len, addr)) => Self::condition(
+                    &conditioner,
+                    &mut self.rng,
+                    &mut self.next_order,
+                    &mut self.incoming,
+                    addr,
+                    &self.receive_buffer[..len],
+                ),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        match Self::pop_released(&mut self.incoming, now) {
+            Some(datagram) => {
+                let len = datagram.payload.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&datagram.payload[..len]);
+                Ok((&buffer[..len], datagram.addr))
+            }
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.is_blocking_mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::NetworkSocket;
+    use laminar::{Config, Packet, SocketEvent};
+    use std::{collections::HashSet, convert::TryInto, thread::sleep};
+
+    const TICK: Duration = Duration::from_millis(33);
+
+    fn config() -> Config {
+        Config {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        }
+    }
+
+    fn bad_connection() -> Option<LinkConditioner> {
+        Some(LinkConditioner {
+            latency_ms: 200,
+            jitter_ms: 20,
+            packet_loss: 0.05,
+            duplication: 0.01,
+            // Keeps the tests from failing only with unlucky losses
+            seed: Some(29),
+        })
+    }
+
+    fn read_tick(payload: &[u8]) -> u32 {
+        u32::from_le_bytes(payload.try_into().unwrap())
+    }
+
+    #[test]
+    fn reliable_commands_survive_latency_and_loss() {
+        let server = NetworkSocket::bind_localhost_with_config(config(), None);
+        let client = NetworkSocket::bind_localhost_with_config(config(), bad_connection());
+        let mut received = HashSet::new();
+        let mut client_addr = None;
+        let start = Instant::now();
+        let mut tick = 0u32;
+        // Laminar only resends a reliable packet once it notices later packets being
+        // acknowledged, so both sides keep sending reliable packets every tick
+        while !(0..100).all(|tick| received.contains(&tick))
+            && start.elapsed() < Duration::from_secs(20)
+        {
+            client
+                .sender
+                .send(Packet::reliable_unordered(
+                    server.local_addr,
+                    tick.to_le_bytes().to_vec(),
+                ))
+                .unwrap();
+            for event in server.receiver.try_iter() {
+                if let SocketEvent::Packet(packet) = event {
+                    client_addr = Some(packet.addr());
+                    // Resent packets might arrive more than once
+                    received.insert(read_tick(packet.payload()));
+                }
+            }
+            if let Some(addr) = client_addr {
+                server
+                    .sender
+                    .send(Packet::reliable_unordered(
+                        addr,
+                        tick.to_le_bytes().to_vec(),
+                    ))
+                    .unwrap();
+            }
+            client.receiver.try_iter().for_each(drop);
+            tick += 1;
+            sleep(TICK);
+        }
+        assert!(start.elapsed() < Duration::from_secs(20));
+    }
+
+    #[test]
+    fn state_updates_stay_fresh_under_latency_and_loss() {
+        let server = NetworkSocket::bind_localhost_with_config(config(), None);
+        let client = NetworkSocket::bind_localhost_with_config(config(), bad_connection());
+        // Sent until the server learns the client address since the first ones might be lost
+        let client_addr = loop {
+            client
+                .sender
+                .send(Packet::unreliable(server.local_addr, vec![0; 4]))
+                .unwrap();
+            if let Ok(SocketEvent::Packet(packet)) = server.receiver.recv_timeout(TICK) {
+                break packet.addr();
+            }
+        };
+        let mut received = Vec::new();
+        let mut max_staleness = 0;
+        for tick in 0..90u32 {
+            server
+                .sender
+                .send(Packet::unreliable_sequenced(
+                    client_addr,
+                    tick.to_le_bytes().to_vec(),
+                    Some(crate::resources::SERVER_UPDATE_STREAM),
+                ))
+                .unwrap();
+            for event in client.receiver.try_iter() {
+                if let SocketEvent::Packet(packet) = event {
+                    received.push(read_tick(packet.payload()));
+                }
+            }
+            // Measured after the latency had time to pass
+            if tick >= 15 {
+                let latest = received.last().copied().unwrap_or(0);
+                max_staleness = max_staleness.max(tick - latest);
+            }
+            sleep(TICK);
+        }
+        // Sequenced updates never go backwards even though jitter reorders datagrams,
+        // duplicated datagrams are delivered twice though
+        assert!(received.windows(2).all(|ticks| ticks[0] <= ticks[1]));
+        assert!(received.len() >= 60, "Received {} updates", received.len());
+        // 200ms latency plus jitter is about 7 ticks, allow a few lost updates on top
+        assert!(max_staleness <= 12, "State was {} ticks old", max_staleness);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use legion::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{EntityType, Owner, Transform, Velocity},
+    desync::Fnv1a,
+    resources::Command,
+};
+
+/// Number of turns after the last turn a client has run that its commands are scheduled for.
+/// Gives the commands time to reach the server before the turn is sent out.
+pub const INPUT_DELAY_TURNS: u64 = 6;
+/// Commands scheduled further ahead than this of the current turn are rejected
+pub const MAX_TURNS_AHEAD: u64 = 120;
+/// Number of turns the server keeps its checksums for to compare with the clients
+const CHECKSUM_HISTORY: usize = 256;
+
+/// How the game state reaches the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetworkMode {
+    /// The server simulates the game and streams the state to the clients
+    Snapshots,
+    /// Only the commands of each turn are sent and every peer runs the same simulation.
+    /// One turn is one simulation tick at the given rate.
+    Lockstep { tick_rate: u32 },
+}
+
+/// Server side scheduling of the commands sent by the clients into turns
+#[derive(Debug, Default)]
+pub struct TurnScheduler {
+    /// The next turn to be sent out, commands for earlier turns are too late
+    next_turn: u64,
+    pending: BTreeMap<u64, Vec<Command>>,
+}
+
+impl TurnScheduler {
+    pub fn new(next_turn: u64) -> Self {
+        TurnScheduler {
+            next_turn,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Schedules commands for the requested turn. Commands arriving too late are moved to
+    /// the next turn that hasn't been sent yet. Returns the turn the commands were
+    /// scheduled for or None if the turn is too far ahead.
+    pub fn schedule(&mut self, turn: u64, commands: Vec<Command>) -> Option<u64> {
+        if turn > self.next_turn + MAX_TURNS_AHEAD {
+            return None;
+        }
+        let turn = turn.max(self.next_turn);
+        self.pending.entry(turn).or_default().extend(commands);
+        Some(turn)
+    }
+
+    /// Takes the commands of the next turn in the order they were scheduled.
+    /// Returns the turn number and its commands.
+    pub fn take_next_turn(&mut self) -> (u64, Vec<Command>) {
+        let turn = self.next_turn;
+        self.next_turn += 1;
+        (turn, self.pending.remove(&turn).unwrap_or_default())
+    }
+}
+
+/// Server side history of its own checksums to compare with the ones reported by the clients
+#[derive(Debug, Default)]
+pub struct ChecksumHistory {
+    checksums: VecDeque<(u64, u64)>,
+}
+
+impl ChecksumHistory {
+    pub fn record(&mut self, turn: u64, checksum: u64) {
+        if self.checksums.len() == CHECKSUM_HISTORY {
+            self.checksums.pop_front();
+        }
+        self.checksums.push_back((turn, checksum));
+    }
+
+    /// Whether the checksum matches the one recorded for the turn,
+    /// None if the turn isn't in the history
+    pub fn matches(&self, turn: u64, checksum: u64) -> Option<bool> {
+        self.checksums
+            .iter()
+            .find(|(recorded_turn, _)| *recorded_turn == turn)
+            .map(|(_, recorded)| *recorded == checksum)
+    }
+}
+
+/// Checksum of the simulated state of all units. The entities are combined in an order
+/// independent way since the clients store them in different archetypes than the server.
+pub fn state_checksum(world: &World) -> u64 {
+    let mut query =
+        <(&Transform, &Velocity, Option<&Owner>)>::query().filter(component::<EntityType>());
+    query
+        .iter(world)
+        .map(|(transform, velocity, owner)| {
+            let mut hasher = Fnv1a::new();
+            hasher.write_f32s(&transform.matrix.to_cols_array());
+            hasher.write_f32s(&velocity.velocity.to_array());
+            hasher.write(&[owner.map_or(u8::MAX, |owner| owner.player)]);
+            hasher.0
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::Selectable,
+        game_time::GameTime,
+        resources::NetworkSerialization,
+        simulation::{add_simulation_systems, Orders, SimulationStats},
+        tilemap::TileMap,
+        timestep::FixedTimestep,
+    };
+    use glam::{Quat, Vec3, Vec3A};
+    use std::time::Instant;
+
+    #[test]
+    fn late_commands_move_to_next_turn() {
+        let move_order = Command::Move {
+            entity: World::default().push(()),
+            target: Vec3A::ZERO,
+        };
+        let mut scheduler = TurnScheduler::new(1);
+        assert_eq!(scheduler.schedule(3, vec![move_order.clone()]), Some(3));
+        assert_eq!(scheduler.take_next_turn(), (1, Vec::new()));
+        assert_eq!(scheduler.schedule(1, vec![move_order.clone()]), Some(2));
+        assert_eq!(scheduler.schedule(2 + MAX_TURNS_AHEAD + 1, vec![]), None);
+        assert_eq!(scheduler.take_next_turn().1.len(), 1);
+        assert_eq!(scheduler.take_next_turn().1.len(), 1);
+    }
+
+    #[test]
+    fn checksum_history() {
+        let mut history = ChecksumHistory::default();
+        for turn in 0..300 {
+            history.record(turn, turn * 7);
+        }
+        assert_eq!(history.matches(299, 299 * 7), Some(true));
+        assert_eq!(history.matches(299, 0), Some(false));
+        assert_eq!(history.matches(10, 70), None);
+    }
+
+    /// A peer running the simulation, the client worlds have extra components
+    /// which puts the units in different archetypes than on the server
+    struct Peer {
+        world: World,
+        resources: Resources,
+        schedule: Schedule,
+        net_serialization: NetworkSerialization,
+    }
+
+    impl Peer {
+        fn new(initial_world: &[u8], tilemap: &TileMap, client: bool) -> Self {
+            let net_serialization = NetworkSerialization::default();
+            let mut world = net_serialization
+                .deserialize_new_world(initial_world)
+                .unwrap();
+            if client {
+                let entities: Vec<Entity> = <Entity>::query().iter(&world).copied().collect();
+                for entity in entities {
+                    world
+                        .entry(entity)
+                        .unwrap()
+                        .add_component(Selectable::default());
+                }
+            }
+            let mut resources = Resources::default();
+            resources.insert(tilemap.clone());
+            resources.insert(GameTime::default());
+            resources.insert(Orders::default());
+            resources.insert(SimulationStats::default());
+            Peer {
+                world,
+                resources,
+                schedule: add_simulation_systems(&mut Schedule::builder()).build(),
+                net_serialization,
+            }
+        }
+
+        /// Runs a turn with commands serialized by the server
+        fn run_turn(&mut self, delta_time: f32, commands: &[Vec<u8>]) -> u64 {
+            let commands = commands
+                .iter()
+                .map(|bytes| self.net_serialization.deserialize_command(bytes).unwrap())
+                .collect();
+            self.resources
+                .get_mut::<GameTime>()
+                .unwrap()
+                .advance(delta_time);
+            self.resources.insert(Orders { commands });
+            self.schedule.execute(&mut self.world, &mut self.resources);
+            state_checksum(&self.world)
+        }
+    }
+
+    #[test]
+    fn peers_stay_in_sync() {
+        let tilemap = TileMap::new("test".to_string(), Transform::default());
+        let net_serialization = NetworkSerialization::authoritative();
+        let mut world = World::default();
+        let units = world
+            .extend((0..2).map(|player| {
+                (
+                    EntityType::BasicUnit,
+                    Transform::new(
+                        Vec3::new(5.5 + player as f32 * 2.0, 0.0, 5.5),
+                        Vec3::ONE,
+                        Quat::IDENTITY,
+                    ),
+                    Velocity {
+                        velocity: Vec3::ZERO,
+                    },
+                    Owner { player },
+                )
+            }))
+            .to_vec();
+        let initial_world = net_serialization.serialize_world(&world, any());
+        let mut server = Peer::new(&initial_world, &tilemap, false);
+        let mut client = Peer::new(&initial_world, &tilemap, true);
+        let delta_time = FixedTimestep::new(60, 1, Instant::now())
+            .tick_duration()
+            .as_secs_f32();
+        let initial_checksum = state_checksum(&server.world);
+        let mut checksums = Vec::new();
+        for turn in 0..300 {
+            let commands = match turn {
+                10 => vec![Command::Move {
+                    entity: units[0],
+                    target: Vec3A::new(20.0, 0.0, 30.0),
+                }],
+                120 => vec![
+                    Command::Move {
+                        entity: units[0],
+                        target: Vec3A::new(2.0, 0.0, 60.0),
+                    },
+                    Command::Move {
+                        entity: units[1],
+                        target: Vec3A::new(30.0, 0.0, 3.0),
+                    },
+                ],
+                _ => Vec::new(),
+            };
+            let commands: Vec<Vec<u8>> = commands
+                .iter()
+                .map(|command| net_serialization.serialize_command(command))
+                .collect();
+            let checksum = server.run_turn(delta_time, &commands);
+            assert_eq!(checksum, client.run_turn(delta_time, &commands));
+            checksums.push(checksum);
+        }
+        assert!(checksums
+            .iter()
+            .any(|checksum| *checksum != initial_checksum));
+
+        // Moving a unit slightly is detected
+        let mut query = <&mut Transform>::query();
+        query
+            .iter_mut(&mut client.world)
+            .next()
+            .unwrap()
+            .matrix
+            .translation
+            .x += 0.001;
+        assert_ne!(state_checksum(&server.world), state_checksum(&client.world));
+    }
+}
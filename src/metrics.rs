@@ -0,0 +1,356 @@
+//! Metrics in the Prometheus text format, served over http for monitoring long running
+//! servers. The metrics are cheap to clone handles to shared atomics so systems can update
+//! them while the http thread reads them.
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Max size of a request line with its headers, longer requests are dropped
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+/// Time a client has to send its request and read the response. The requests are handled
+/// one at a time, so a slow client holds back the others for at most this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Value that only goes up
+#[derive(Debug, Default, Clone)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down, stored as the bits of a f64
+#[derive(Debug, Default, Clone)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct HistogramInner {
+    /// Upper bounds of the buckets, the +Inf bucket is the count
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// Sum of the observed values in nanoseconds, kept as an integer to update it atomically
+    sum_nanos: AtomicU64,
+}
+
+/// Distribution of durations over fixed buckets
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<HistogramInner>);
+
+impl Histogram {
+    /// The bounds are in seconds and must be sorted
+    pub fn new(bounds: &'static [f64]) -> Self {
+        debug_assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]));
+        Histogram(Arc::new(HistogramInner {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = self.0.bounds.iter().position(|&bound| seconds <= bound) {
+            self.0.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.count.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.0.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes metrics in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    /// Every metric starts with its help text and type, followed by its samples
+    pub fn header(&mut self, name: &str, help: &str, kind: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    /// A sample of the metric, the label values are escaped
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, label_value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let escaped = label_value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                write!(self.out, "{}=\"{}\"", label, escaped).unwrap();
+            }
+            self.out.push('}');
+        }
+        writeln!(self.out, " {}", format_value(value)).unwrap();
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], counter.get() as f64);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        self.sample(name, &[], value);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        let bucket_name = format!("{}_bucket", name);
+        // The buckets of the text format are cumulative
+        let mut cumulative = 0;
+        for (bound, bucket) in histogram.0.bounds.iter().zip(&histogram.0.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = format_value(*bound);
+            self.sample(&bucket_name, &[("le", &bound)], cumulative as f64);
+        }
+        let count = histogram.count() as f64;
+        self.sample(&bucket_name, &[("le", "+Inf")], count);
+        let sum = histogram.0.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        self.sample(&format!("{}_sum", name), &[], sum);
+        self.sample(&format!("{}_count", name), &[], count);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Serves the metrics rendered by `render` at /metrics on a background thread.
+/// Returns the address the endpoint is bound to.
+pub fn serve_metrics(
+    addr: SocketAddr,
+    render: impl Fn() -> String + Send + 'static,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(|stream| respond(stream, &render));
+                if let Err(err) = result {
+                    debug!("Failed to serve metrics: {}", err);
+                }
+            }
+        })?;
+    Ok(local_addr)
+}
+
+/// Reads from the stream until the deadline, the read timeout is shortened before every
+/// read so a client sending a byte at a time can't keep the request open
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Handles a single http request, the connection is closed afterwards
+fn respond(mut stream: TcpStream, render: &impl Fn() -> String) -> Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(
+        DeadlineReader {
+            stream: &stream,
+            deadline,
+        }
+        .take(MAX_REQUEST_SIZE),
+    );
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers aren't needed but have to be read before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    if reader.get_ref().limit() == 0 {
+        return Err(anyhow!("Request is larger than {} bytes", MAX_REQUEST_SIZE));
+    }
+    drop(reader);
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    use super::*;
+
+    const BOUNDS: &[f64] = &[0.001, 0.01];
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let histogram = Histogram::new(BOUNDS);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(1));
+        let mut writer = MetricsWriter::default();
+        writer.histogram("tick_seconds", "Tick time", &histogram);
+        assert_eq!(
+            writer.finish(),
+            "# HELP tick_seconds Tick time\n\
+             # TYPE tick_seconds histogram\n\
+             tick_seconds_bucket{le=\"0.001\"} 1\n\
+             tick_seconds_bucket{le=\"0.01\"} 3\n\
+             tick_seconds_bucket{le=\"+Inf\"} 4\n\
+             tick_seconds_sum 1.0105\n\
+             tick_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let mut writer = MetricsWriter::default();
+        writer.sample("clients", &[("session", "0"), ("name", "a \"b\"")], 2.0);
+        assert_eq!(
+            writer.finish(),
+            "clients{session=\"0\",name=\"a \\\"b\\\"\"} 2\n"
+        );
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let counter = Counter::default();
+        let rendered = counter.clone();
+        let addr = serve_metrics(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into(),
+            move || {
+                let mut writer = MetricsWriter::default();
+                writer.counter("requests_total", "Requests", &rendered);
+                writer.finish()
+            },
+        )
+        .unwrap();
+        counter.add(3);
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\nrequests_total 3\n"));
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn oversized_requests_are_dropped() {
+        let addr = serve_metrics(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into(), || {
+            String::new()
+        })
+        .unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // The server may close the connection before everything has been written
+        let _ = write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(2 * MAX_REQUEST_SIZE as usize)
+        );
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty());
+        // The endpoint keeps serving
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -5,7 +5,6 @@ use glam::{Affine3A, Quat, Vec2, Vec3, Vec3A};
 use crate::{
     components::{Transform, Velocity},
     map_chunk::{ChunkIndex, MapChunk, CHUNK_SIZE},
-    resources::Time,
     tilemap::{Tile, TileType, TILE_HEIGHT, TILE_WIDTH},
 };
 
@@ -94,13 +93,15 @@ impl FlowField {
 }
 
 /// Moves a given transfrom (with velocity) along the flow field
-/// Used in different systems both server and client side
+/// Used in different systems both server and client side.
+/// The result only depends on the arguments, given the same delta time every
+/// peer in lockstep mode ends up with the same transform.
 pub fn movement_impl(
     tilemap: &MapChunk<Tile>,
     flow_field: &FlowField,
     transform: &mut Transform,
     velocity: &mut Velocity,
-    time: &Time,
+    delta_time: f32,
 ) {
     // Movement along the flow field
     let position = transform.matrix.translation.floor();
@@ -124,7 +125,7 @@ pub fn movement_impl(
     }
     // Set new position (if valid)
     let offset: Vec3A = Vec3A::splat(4.0) * Vec3A::from(velocity.velocity);
-    let new_pos: Vec3A = Vec3A::from(translation) + (offset * time.delta_time());
+    let new_pos: Vec3A = Vec3A::from(translation) + (offset * delta_time);
     let floored_new_pos = new_pos.floor();
     if let Ok(new_chunk_pos) = ChunkIndex::new(floored_new_pos.x as i32, floored_new_pos.z as i32) {
         let translation = &mut transform.matrix.translation;
@@ -135,14 +136,18 @@ pub fn movement_impl(
     }
 }
 
+/// Rotation from +Z to the given direction. Only uses arithmetic and sqrt which are exactly
+/// rounded, unlike acos and friends, so the rotation is the same on every peer.
 pub fn look_at(direction: Vec3A) -> Quat {
-    let mut rotation_axis = Vec3A::Z.cross(direction).normalize_or_zero();
-    if rotation_axis.length_squared() < 0.001 {
-        rotation_axis = Vec3A::Y;
-    }
+    let direction = direction.normalize_or_zero();
     let dot = Vec3A::Z.dot(direction);
-    let angle = dot.acos();
-    Quat::from_axis_angle(rotation_axis.into(), angle)
+    if dot < -0.9999 {
+        // Half turn around Y, the axis is undefined for opposite directions
+        return Quat::from_xyzw(0.0, 1.0, 0.0, 0.0);
+    }
+    // The quaternion for twice the rotation to the halfway vector, normalized
+    let axis = Vec3A::Z.cross(direction);
+    Quat::from_xyzw(axis.x, axis.y, axis.z, 1.0 + dot).normalize()
 }
 
 fn calc_distance(n_tile: &Tile, _current_tile: &Tile) -> Option<u32> {
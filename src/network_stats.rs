@@ -0,0 +1,450 @@
+//! Traffic statistics of a NetworkSocket. They are collected by the connections laminar
+//! creates on the socket's polling thread, so every message and datagram is counted
+//! without the game code having to report what it sends and receives.
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use laminar::{
+    Config, Connection, ConnectionMessenger, OrderingGuarantee, Packet, SocketEvent,
+    VirtualConnection,
+};
+
+use crate::resources::{
+    AREA_OF_INTEREST_STREAM, CHAT_STREAM, CLIENT_UPDATE_STREAM, LOCKSTEP_STREAM, MAP_STREAM,
+    SERVER_UPDATE_STREAM, TRANSFER_STREAM,
+};
+
+/// The ordering or sequencing stream of a message, None for unordered messages
+pub type StreamId = Option<u8>;
+
+/// Size in bytes of laminar's header, a datagram of this size is a heartbeat
+const STANDARD_HEADER_SIZE: usize = 5;
+
+pub fn stream_name(stream: StreamId) -> String {
+    match stream {
+        None => "unordered".to_string(),
+        Some(SERVER_UPDATE_STREAM) => "server updates".to_string(),
+        Some(CLIENT_UPDATE_STREAM) => "client updates".to_string(),
+        Some(MAP_STREAM) => "map".to_string(),
+        Some(AREA_OF_INTEREST_STREAM) => "area of interest".to_string(),
+        Some(LOCKSTEP_STREAM) => "lockstep".to_string(),
+        Some(CHAT_STREAM) => "chat".to_string(),
+        Some(TRANSFER_STREAM) => "transfers".to_string(),
+        Some(stream) => format!("stream {}", stream),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl TrafficCounter {
+    #[inline]
+    pub fn record(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn average_size(&self) -> f32 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.bytes as f32 / self.packets as f32
+    }
+
+    /// The traffic counted since the earlier snapshot of the counter
+    pub fn since(&self, earlier: &TrafficCounter) -> TrafficCounter {
+        TrafficCounter {
+            packets: self.packets.saturating_sub(earlier.packets),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+        }
+    }
+}
+
+impl std::ops::AddAssign for TrafficCounter {
+    fn add_assign(&mut self, other: TrafficCounter) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Traffic exchanged with a single remote address
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Messages given to laminar per stream, before fragmentation and headers
+    pub sent: BTreeMap<StreamId, TrafficCounter>,
+    /// Messages delivered by laminar per stream
+    pub received: BTreeMap<StreamId, TrafficCounter>,
+    /// Datagrams on the wire including headers, fragments, resends and heartbeats
+    pub datagrams_sent: TrafficCounter,
+    pub datagrams_received: TrafficCounter,
+    /// Reliable datagrams sent again because they weren't acknowledged in time
+    pub resent: TrafficCounter,
+}
+
+impl PeerStats {
+    pub fn total_sent(&self) -> TrafficCounter {
+        total(&self.sent)
+    }
+
+    pub fn total_received(&self) -> TrafficCounter {
+        total(&self.received)
+    }
+
+    /// Share of the sent datagrams that were lost, estimated from the resends. Only
+    /// reliable datagrams are resent so the loss of unreliable ones isn't noticed.
+    pub fn loss_estimate(&self) -> f32 {
+        if self.datagrams_sent.packets == 0 {
+            return 0.0;
+        }
+        self.resent.packets as f32 / self.datagrams_sent.packets as f32
+    }
+
+    /// The traffic counted since the earlier snapshot of the stats
+    pub fn since(&self, earlier: &PeerStats) -> PeerStats {
+        let streams_since =
+            |current: &BTreeMap<StreamId, TrafficCounter>,
+             earlier: &BTreeMap<StreamId, TrafficCounter>| {
+                current
+                    .iter()
+                    .map(|(stream, counter)| {
+                        let since = earlier
+                            .get(stream)
+                            .map_or(*counter, |earlier| counter.since(earlier));
+                        (*stream, since)
+                    })
+                    .collect()
+            };
+        PeerStats {
+            sent: streams_since(&self.sent, &earlier.sent),
+            received: streams_since(&self.received, &earlier.received),
+            datagrams_sent: self.datagrams_sent.since(&earlier.datagrams_sent),
+            datagrams_received: self.datagrams_received.since(&earlier.datagrams_received),
+            resent: self.resent.since(&earlier.resent),
+        }
+    }
+}
+
+fn total(streams: &BTreeMap<StreamId, TrafficCounter>) -> TrafficCounter {
+    let mut total = TrafficCounter::default();
+    for counter in streams.values() {
+        total += *counter;
+    }
+    total
+}
+
+/// Datagrams sent and received by a socket over all of its connections
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketTotals {
+    pub datagrams_sent: TrafficCounter,
+    pub datagrams_received: TrafficCounter,
+}
+
+impl SocketTotals {
+    fn add(&mut self, stats: &PeerStats) {
+        self.datagrams_sent += stats.datagrams_sent;
+        self.datagrams_received += stats.datagrams_received;
+    }
+}
+
+/// Stats of every connection of a socket, shared between the socket's polling thread
+/// and the socket's users. The stats of a connection are removed when it's dropped.
+#[derive(Debug, Default, Clone)]
+pub struct NetworkStats {
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerStats>>>,
+    /// Traffic of the connections that have been dropped
+    closed: Arc<Mutex<SocketTotals>>,
+}
+
+impl NetworkStats {
+    /// Snapshot of the traffic exchanged with the address so far
+    pub fn peer(&self, addr: SocketAddr) -> Option<PeerStats> {
+        self.peers.lock().unwrap().get(&addr).cloned()
+    }
+
+    /// The traffic of the socket so far, including the connections that have been dropped
+    pub fn totals(&self) -> SocketTotals {
+        let peers = self.peers.lock().unwrap();
+        let mut totals = *self.closed.lock().unwrap();
+        for stats in peers.values() {
+            totals.add(stats);
+        }
+        totals
+    }
+
+    fn update(&self, addr: SocketAddr, update: impl FnOnce(&mut PeerStats)) {
+        update(self.peers.lock().unwrap().entry(addr).or_default());
+    }
+
+    fn remove(&self, addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(stats) = peers.remove(&addr) {
+            self.closed.lock().unwrap().add(&stats);
+        }
+    }
+}
+
+thread_local! {
+    /// Stats of the socket polled by the current thread. Laminar creates the connections
+    /// itself without a way to pass them any state.
+    static SOCKET_STATS: RefCell<NetworkStats> = RefCell::new(NetworkStats::default());
+}
+
+/// Called by the polling thread of a socket before the first poll
+pub(crate) fn set_socket_stats(stats: NetworkStats) {
+    SOCKET_STATS.with(|socket_stats| *socket_stats.borrow_mut() = stats);
+}
+
+#[inline]
+fn stream_id(ordering: OrderingGuarantee) -> StreamId {
+    match ordering {
+        OrderingGuarantee::None => None,
+        OrderingGuarantee::Sequenced(stream) | OrderingGuarantee::Ordered(stream) => stream,
+    }
+}
+
+/// Counts the datagrams and messages passing through laminar's messenger
+struct CountingMessenger<'a, M> {
+    inner: &'a mut M,
+    stats: &'a mut PeerStats,
+    resending: bool,
+}
+
+impl<'a, M: ConnectionMessenger<SocketEvent>> ConnectionMessenger<SocketEvent>
+    for CountingMessenger<'a, M>
+{
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn send_event(&mut self, address: &SocketAddr, event: SocketEvent) {
+        if let SocketEvent::Packet(packet) = &event {
+            self.stats
+                .received
+                .entry(stream_id(packet.order_guarantee()))
+                .or_default()
+                .record(packet.payload().len());
+        }
+        self.inner.send_event(address, event);
+    }
+
+    fn send_packet(&mut self, address: &SocketAddr, payload: &[u8]) {
+        self.stats.datagrams_sent.record(payload.len());
+        if self.resending && payload.len() > STANDARD_HEADER_SIZE {
+            self.stats.resent.record(payload.len());
+        }
+        self.inner.send_packet(address, payload);
+    }
+}
+
+/// Laminar's connection counting the traffic into the stats of the socket
+#[derive(Debug)]
+pub(crate) struct CountingConnection {
+    inner: VirtualConnection,
+    stats: NetworkStats,
+}
+
+impl CountingConnection {
+    fn counting<M: ConnectionMessenger<SocketEvent>, T>(
+        &mut self,
+        messenger: &mut M,
+        resending: bool,
+        run: impl FnOnce(&mut VirtualConnection, &mut CountingMessenger<M>) -> T,
+    ) -> T {
+        let inner = &mut self.inner;
+        let mut result = None;
+        self.stats.update(inner.remote_address, |stats| {
+            let mut messenger = CountingMessenger {
+                inner: messenger,
+                stats,
+                resending,
+            };
+            result = Some(run(inner, &mut messenger));
+        });
+        result.unwrap()
+    }
+}
+
+impl Connection for CountingConnection {
+    type SendEvent = Packet;
+    type ReceiveEvent = SocketEvent;
+
+    fn create_connection(
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        address: SocketAddr,
+        time: Instant,
+    ) -> Self {
+        CountingConnection {
+            inner: VirtualConnection::new(address, messenger.config(), time),
+            stats: SOCKET_STATS.with(|stats| stats.borrow().clone()),
+        }
+    }
+
+    fn is_established(&self) -> bool {
+        self.inner.is_established()
+    }
+
+    fn should_drop(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        time: Instant,
+    ) -> bool {
+        let should_drop = Connection::should_drop(&mut self.inner, messenger, time);
+        if should_drop {
+            self.stats.remove(self.inner.remote_address);
+        }
+        should_drop
+    }
+
+    fn process_packet(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        payload: &[u8],
+        time: Instant,
+    ) {
+        self.counting(messenger, false, |connection, messenger| {
+            messenger.stats.datagrams_received.record(payload.len());
+            connection.process_packet(messenger, payload, time);
+        });
+    }
+
+    fn process_event(
+        &mut self,
+        messenger: &mut impl ConnectionMessenger<SocketEvent>,
+        event: Packet,
+        time: Instant,
+    ) {
+        self.counting(messenger, false, |connection, messenger| {
+            messenger
+                .stats
+                .sent
+                .entry(stream_id(event.order_guarantee()))
+                .or_default()
+                .record(event.payload().len());
+            connection.process_event(messenger, event, time);
+        });
+    }
+
+    /// Besides heartbeats the only datagrams sent during an update are resends
+    fn update(&mut self, messenger: &mut impl ConnectionMessenger<SocketEvent>, time: Instant) {
+        self.counting(messenger, true, |connection, messenger| {
+            connection.update(messenger, time);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::resources::NetworkSocket;
+
+    #[test]
+    fn stats_since_snapshot() {
+        let mut earlier = PeerStats::default();
+        earlier.sent.entry(Some(1)).or_default().record(100);
+        earlier.datagrams_sent.record(110);
+        let mut current = earlier.clone();
+        current.sent.entry(Some(1)).or_default().record(50);
+        current.sent.entry(None).or_default().record(20);
+        current.datagrams_sent.record(60);
+        current.datagrams_sent.record(30);
+        current.resent.record(60);
+
+        let since = current.since(&earlier);
+        assert_eq!(
+            since.sent[&Some(1)],
+            TrafficCounter {
+                packets: 1,
+                bytes: 50
+            }
+        );
+        assert_eq!(
+            since.sent[&None],
+            TrafficCounter {
+                packets: 1,
+                bytes: 20
+            }
+        );
+        assert_eq!(
+            since.total_sent(),
+            TrafficCounter {
+                packets: 2,
+                bytes: 70
+            }
+        );
+        assert_eq!(since.total_sent().average_size(), 35.0);
+        assert_eq!(since.loss_estimate(), 0.5);
+        assert_eq!(since.total_received().average_size(), 0.0);
+    }
+
+    #[test]
+    fn sockets_count_messages_per_stream() {
+        let config = Config {
+            heartbeat_interval: None,
+            ..Config::default()
+        };
+        let client = NetworkSocket::bind_localhost_with_config(config.clone(), None);
+        let server = NetworkSocket::bind_localhost_with_config(config, None);
+        for _ in 0..3 {
+            client
+                .sender
+                .send(Packet::reliable_ordered(
+                    server.local_addr,
+                    vec![0; 100],
+                    Some(CLIENT_UPDATE_STREAM),
+                ))
+                .unwrap();
+        }
+        client
+            .sender
+            .send(Packet::unreliable(server.local_addr, vec![0; 10]))
+            .unwrap();
+        let mut received = 0;
+        while received < 4 {
+            if let SocketEvent::Packet(_) = server
+                .receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+            {
+                received += 1;
+            }
+        }
+
+        let sent = client.stats.peer(server.local_addr).unwrap();
+        assert_eq!(
+            sent.sent[&Some(CLIENT_UPDATE_STREAM)],
+            TrafficCounter {
+                packets: 3,
+                bytes: 300
+            }
+        );
+        assert_eq!(
+            sent.sent[&None],
+            TrafficCounter {
+                packets: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(sent.datagrams_sent.packets, 4);
+        assert!(sent.datagrams_sent.bytes > 310);
+        let received = server.stats.peer(client.local_addr).unwrap();
+        assert_eq!(received.received, sent.sent);
+        assert_eq!(received.datagrams_received, sent.datagrams_sent);
+
+        // The totals keep the traffic of dropped connections
+        let totals = server.stats.totals();
+        assert_eq!(totals.datagrams_received, received.datagrams_received);
+        server.stats.remove(client.local_addr);
+        assert!(server.stats.peer(client.local_addr).is_none());
+        assert_eq!(server.stats.totals(), totals);
+    }
+}
@@ -0,0 +1,210 @@
+use glam::{Vec2, Vec3Swizzles};
+use legion::*;
+use serde::{Deserialize, Serialize};
+
+use crate::components::{Owner, PlayerId, Transform};
+
+/// Rectangle on the ground plane (x, z) viewed by a client
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AreaOfInterest {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl AreaOfInterest {
+    /// Creates the smallest area containing all the points
+    pub fn from_points(points: impl IntoIterator<Item = Vec2>) -> Option<Self> {
+        points.into_iter().fold(None, |area, point| {
+            Some(match area {
+                Some(AreaOfInterest { min, max }) => AreaOfInterest {
+                    min: min.min(point),
+                    max: max.max(point),
+                },
+                None => AreaOfInterest {
+                    min: point,
+                    max: point,
+                },
+            })
+        })
+    }
+
+    #[inline]
+    pub fn contains(&self, point: Vec2, margin: f32) -> bool {
+        point.cmpge(self.min - Vec2::splat(margin)).all()
+            && point.cmple(self.max + Vec2::splat(margin)).all()
+    }
+}
+
+/// The client a state update is being built for
+#[derive(Debug)]
+pub struct Viewer<'a> {
+    pub player: PlayerId,
+    /// None until the client has reported what it's looking at
+    pub area_of_interest: Option<AreaOfInterest>,
+    /// Ground positions of the player's units
+    pub unit_positions: &'a [Vec2],
+}
+
+/// Decides which entities a client receives updates for. Fog of war
+/// rules should be implemented as a RelevancyFilter.
+pub trait RelevancyFilter: Send + Sync {
+    fn is_relevant(&self, viewer: &Viewer, owner: Option<&Owner>, position: Vec2) -> bool;
+}
+
+/// Sends the player's own units, everything close to the client's view and
+/// everything within sight range of the player's units
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultRelevancy {
+    /// Distance outside the area of interest that is still relevant so entities
+    /// don't pop in at the edges of the screen
+    pub view_margin: f32,
+    pub sight_range: f32,
+}
+
+impl Default for DefaultRelevancy {
+    fn default() -> Self {
+        DefaultRelevancy {
+            view_margin: 5.0,
+            sight_range: 10.0,
+        }
+    }
+}
+
+impl RelevancyFilter for DefaultRelevancy {
+    fn is_relevant(&self, viewer: &Viewer, owner: Option<&Owner>, position: Vec2) -> bool {
+        owner.is_some_and(|owner| owner.player == viewer.player)
+            || viewer
+                .area_of_interest
+                .is_some_and(|area| area.contains(position, self.view_margin))
+            || viewer
+                .unit_positions
+                .iter()
+                .any(|unit| unit.distance_squared(position) <= self.sight_range * self.sight_range)
+    }
+}
+
+/// Server resource holding the relevancy filter in use
+pub struct Relevancy {
+    filter: Box<dyn RelevancyFilter>,
+}
+
+impl Default for Relevancy {
+    fn default() -> Self {
+        Relevancy::new(DefaultRelevancy::default())
+    }
+}
+
+impl Relevancy {
+    pub fn new(filter: impl RelevancyFilter + 'static) -> Self {
+        Relevancy {
+            filter: Box::new(filter),
+        }
+    }
+
+    /// Ground positions of all units owned by the player
+    pub fn unit_positions(world: &World, player: PlayerId) -> Vec<Vec2> {
+        let mut query = <(&Transform, &Owner)>::query();
+        query
+            .iter(world)
+            .filter(|(_, owner)| owner.player == player)
+            .map(|(transform, _)| transform.matrix.translation.xz())
+            .collect()
+    }
+
+    /// Transforms of the entities that are relevant to the viewer
+    pub fn relevant_transforms(&self, world: &World, viewer: &Viewer) -> Vec<(Entity, Transform)> {
+        let mut query = <(Entity, &Transform, Option<&Owner>)>::query();
+        query
+            .iter(world)
+            .filter(|(_, transform, owner)| {
+                self.filter
+                    .is_relevant(viewer, *owner, transform.matrix.translation.xz())
+            })
+            .map(|(entity, transform, _)| (*entity, *transform))
+            .collect()
+    }
+
+    /// Transforms of all entities, sent to spectators which aren't affected by fog of war
+    pub fn all_transforms(world: &World) -> Vec<(Entity, Transform)> {
+        let mut query = <(Entity, &Transform)>::query();
+        query
+            .iter(world)
+            .map(|(entity, transform)| (*entity, *transform))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+    use std::collections::HashSet;
+
+    #[test]
+    fn default_relevancy() {
+        let mut world = World::default();
+        let own_unit = world.push((
+            Transform::from_position(Vec3::new(50.0, 0.0, 50.0)),
+            Owner { player: 0 },
+        ));
+        let near_own_unit = world.push((
+            Transform::from_position(Vec3::new(55.0, 0.0, 50.0)),
+            Owner { player: 1 },
+        ));
+        let in_view = world.push((Transform::from_position(Vec3::new(2.0, 0.0, 3.0)),));
+        let _far_away = world.push((
+            Transform::from_position(Vec3::new(100.0, 0.0, 0.0)),
+            Owner { player: 1 },
+        ));
+        let relevancy = Relevancy::default();
+        let unit_positions = Relevancy::unit_positions(&world, 0);
+        let viewer = Viewer {
+            player: 0,
+            area_of_interest: AreaOfInterest::from_points(vec![
+                Vec2::new(0.0, 0.0),
+                Vec2::new(10.0, 10.0),
+            ]),
+            unit_positions: &unit_positions,
+        };
+        let relevant: HashSet<Entity> = relevancy
+            .relevant_transforms(&world, &viewer)
+            .into_iter()
+            .map(|(entity, _)| entity)
+            .collect();
+        assert_eq!(relevant, [own_unit, near_own_unit, in_view].into());
+
+        // Player 1 has no view yet but sees the unit near its own
+        let unit_positions = Relevancy::unit_positions(&world, 1);
+        let viewer = Viewer {
+            player: 1,
+            area_of_interest: None,
+            unit_positions: &unit_positions,
+        };
+        let relevant = relevancy.relevant_transforms(&world, &viewer);
+        assert_eq!(relevant.len(), 3);
+        assert!(relevant.iter().all(|(entity, _)| *entity != in_view));
+
+        // Spectators see everything
+        assert_eq!(Relevancy::all_transforms(&world).len(), 4);
+    }
+
+    #[test]
+    fn custom_filter() {
+        struct NothingRelevant;
+        impl RelevancyFilter for NothingRelevant {
+            fn is_relevant(&self, _: &Viewer, _: Option<&Owner>, _: Vec2) -> bool {
+                false
+            }
+        }
+        let mut world = World::default();
+        world.push((Transform::default(), Owner { player: 0 }));
+        let viewer = Viewer {
+            player: 0,
+            area_of_interest: None,
+            unit_positions: &[],
+        };
+        assert!(Relevancy::new(NothingRelevant)
+            .relevant_transforms(&world, &viewer)
+            .is_empty());
+    }
+}
@@ -167,6 +167,23 @@ impl Camera {
         }
     }
 
+    /// Screen position of the point in the same coordinates as the cursor position,
+    /// the inverse of raycast. None if the point is behind the camera.
+    pub fn project(&self, point: Vec3A, window_size: &WindowSize) -> Option<CursorPosition> {
+        let clip_space =
+            self.proj_matrix * self.view_matrix * Vec4::new(point.x, point.y, point.z, 1.0);
+        if clip_space.w <= 0.0 {
+            return None;
+        }
+        let normalised = clip_space.xyz() / clip_space.w;
+        let width = window_size.physical_width as f32 * window_size.scale_factor;
+        let height = window_size.physical_height as f32 * window_size.scale_factor;
+        Some(CursorPosition {
+            x: ((normalised.x + 1.0) * width / 2.0) as f64,
+            y: ((1.0 - normalised.y) * height / 2.0) as f64,
+        })
+    }
+
     pub fn get_or_create_layout(device: &wgpu::Device) -> &'static wgpu::BindGroupLayout {
         static LAYOUT: OnceCell<wgpu::BindGroupLayout> = OnceCell::new();
         LAYOUT.get_or_init(|| {
@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 
 use crate::assets::{Assets, Handle};
-use crate::components::Transform;
+use crate::components::{Hidden, Transform};
 use crate::engine::FrameTexture;
 use crossbeam_channel::Sender;
 use legion::{world::SubWorld, *};
@@ -29,7 +29,7 @@ pub fn draw(
     #[resource] current_frame: &FrameTexture,
     #[resource] camera: &Camera,
     #[resource] queue: &wgpu::Queue,
-    query: &mut Query<(&Transform, &Handle<GltfModel>)>,
+    query: &mut Query<(&Transform, &Handle<GltfModel>, Option<&Hidden>)>,
 ) {
     let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
         label: Some("Model pass encoder"),
@@ -68,7 +68,10 @@ pub fn draw(
     render_pass.set_bind_group(2, &light_uniform.bind_group, &[]);
 
     instance_data.evict_stale(asset_storage);
-    query.for_each(world, |(transform, model_handle)| {
+    query.for_each(world, |(transform, model_handle, hidden)| {
+        if hidden.is_some() {
+            return;
+        }
         let model = asset_storage.get(model_handle).unwrap();
         instance_data.put(device, model_handle, model, |mesh| Transform {
             matrix: transform.matrix * *mesh.local_transform(),
@@ -0,0 +1,418 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bincode::{DefaultOptions, Options};
+use legion::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    game_time::GameTime,
+    resources::NetworkSerialization,
+    simulation::{add_simulation_systems, Orders, SimulationStats},
+    tilemap::TileMap,
+};
+
+pub const REPLAY_VERSION: u32 = 4;
+/// Size limits that keeps corrupt length prefixes from allocating huge buffers
+const MAX_HEADER_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_TICK_SIZE: u64 = 1024 * 1024;
+/// Longest time recorded ticks stay buffered, which is what a replay misses at most
+/// when the server doesn't shut down cleanly
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Written once at the start of the replay file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub map_hash: u64,
+    /// The world the match started from (see NetworkSerialization::serialize_world)
+    pub initial_world: Vec<u8>,
+}
+
+/// Everything needed to resimulate a single server tick. The orders are serialized
+/// commands which share entity names with the initial world.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayTick {
+    pub tick: u64,
+    /// Game seconds simulated by the tick, see `GameTime::delta_seconds`
+    pub delta_time: f32,
+    pub orders: Vec<Vec<u8>>,
+}
+
+/// Streams ticks to the replay file as the match progresses so that the
+/// replay is usable even if the server doesn't shut down cleanly.
+/// The ticks are flushed every FLUSH_INTERVAL.
+pub struct ReplayRecorder<W: Write> {
+    writer: W,
+    flushed_at: Instant,
+}
+
+impl ReplayRecorder<BufWriter<File>> {
+    pub fn create(path: &Path, header: &ReplayHeader) -> Result<Self> {
+        let file = File::create(path)?;
+        ReplayRecorder::new(BufWriter::with_capacity(16_000, file), header)
+    }
+}
+
+impl<W: Write> ReplayRecorder<W> {
+    pub fn new(mut writer: W, header: &ReplayHeader) -> Result<Self> {
+        bincode::serialize_into(&mut writer, header)?;
+        Ok(ReplayRecorder {
+            writer,
+            flushed_at: Instant::now(),
+        })
+    }
+
+    pub fn record_tick(
+        &mut self,
+        tick: u64,
+        delta_time: f32,
+        orders: &Orders,
+        net_serialization: &NetworkSerialization,
+    ) -> Result<()> {
+        let replay_tick = ReplayTick {
+            tick,
+            delta_time,
+            orders: orders
+                .commands
+                .iter()
+                .map(|command| net_serialization.serialize_command(command))
+                .collect(),
+        };
+        bincode::serialize_into(&mut self.writer, &replay_tick)?;
+        if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub ticks: Vec<ReplayTick>,
+}
+
+impl Replay {
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let options = DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let header: ReplayHeader = options
+            .with_limit(MAX_HEADER_SIZE)
+            .deserialize_from(&mut reader)?;
+        if header.version != REPLAY_VERSION {
+            return Err(anyhow!(
+                "Unsupported replay version: {}, expected: {}",
+                header.version,
+                REPLAY_VERSION
+            ));
+        }
+        let mut ticks = Vec::new();
+        loop {
+            match options
+                .with_limit(MAX_TICK_SIZE)
+                .deserialize_from::<_, ReplayTick>(&mut reader)
+            {
+                Ok(tick) => ticks.push(tick),
+                Err(err) => {
+                    // The last tick might have been cut off if the server didn't shut down cleanly
+                    if !matches!(*err, bincode::ErrorKind::Io(ref io_err) if io_err.kind() == std::io::ErrorKind::UnexpectedEof)
+                    {
+                        warn!("Replay ends with invalid tick: {}", err);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(Replay { header, ticks })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Replay::read(BufReader::with_capacity(16_000, file))
+    }
+}
+
+/// Reruns the recorded match by simulating it from the initial world.
+/// Seeking backwards is done by resimulating from the start.
+pub struct ReplayPlayer {
+    replay: Replay,
+    world: World,
+    resources: Resources,
+    schedule: Schedule,
+    net_serialization: NetworkSerialization,
+    next_tick: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay, tilemap: TileMap) -> Result<Self> {
+        let mut resources = Resources::default();
+        resources.insert(tilemap);
+        let mut player = ReplayPlayer {
+            replay,
+            world: World::default(),
+            resources,
+            schedule: add_simulation_systems(&mut Schedule::builder()).build(),
+            net_serialization: NetworkSerialization::default(),
+            next_tick: 0,
+        };
+        player.restart()?;
+        Ok(player)
+    }
+
+    /// Restores the initial world
+    fn restart(&mut self) -> Result<()> {
+        // A fresh serializer is needed to map the entity names to the new entities
+        self.net_serialization = NetworkSerialization::default();
+        self.world = self
+            .net_serialization
+            .deserialize_new_world(&self.replay.header.initial_world)?;
+        self.resources.insert(GameTime::default());
+        self.resources.insert(Orders::default());
+        self.resources.insert(SimulationStats::default());
+        self.next_tick = 0;
+        Ok(())
+    }
+
+    /// Simulates the next tick, returns false if the replay has ended
+    pub fn step(&mut self) -> Result<bool> {
+        let replay_tick = match self.replay.ticks.get(self.next_tick) {
+            Some(replay_tick) => replay_tick,
+            None => return Ok(false),
+        };
+        self.resources
+            .get_mut::<GameTime>()
+            .unwrap()
+            .advance(replay_tick.delta_time);
+        let commands = replay_tick
+            .orders
+            .iter()
+            .map(|bytes| self.net_serialization.deserialize_command(bytes))
+            .collect::<Result<_>>()?;
+        self.resources.insert(Orders { commands });
+        self.schedule.execute(&mut self.world, &mut self.resources);
+        self.next_tick += 1;
+        Ok(true)
+    }
+
+    /// Moves the replay so that the given number of ticks have been simulated
+    pub fn seek(&mut self, tick: usize) -> Result<()> {
+        if tick < self.next_tick {
+            self.restart()?;
+        }
+        while self.next_tick < tick && self.step()? {}
+        Ok(())
+    }
+
+    /// Number of ticks that have been simulated
+    #[inline]
+    pub fn current_tick(&self) -> usize {
+        self.next_tick
+    }
+
+    /// Total number of ticks in the replay
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.replay.ticks.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.replay.ticks.is_empty()
+    }
+
+    /// The delta time of the next tick to be simulated
+    #[inline]
+    pub fn next_delta_time(&self) -> Option<f32> {
+        self.replay
+            .ticks
+            .get(self.next_tick)
+            .map(|tick| tick.delta_time)
+    }
+
+    #[inline]
+    pub fn header(&self) -> &ReplayHeader {
+        &self.replay.header
+    }
+
+    #[inline]
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::{EntityType, Transform, Velocity},
+        resources::Command,
+    };
+    use glam::{Vec3, Vec3A};
+    use proptest::prelude::{prop, proptest};
+
+    fn positions(world: &World) -> Vec<[f32; 3]> {
+        let mut query = <&Transform>::query();
+        let mut positions: Vec<[f32; 3]> = query
+            .iter(world)
+            .map(|transform| transform.matrix.translation.into())
+            .collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        positions
+    }
+
+    #[test]
+    fn replay_reproduces_final_positions() {
+        let tilemap = TileMap::new("test".to_string(), Transform::default());
+        let net_serialization = NetworkSerialization::authoritative();
+        let mut world = World::default();
+        let units = world
+            .extend(vec![
+                (
+                    EntityType::BasicUnit,
+                    Transform::from_position(Vec3::new(5.5, 0.0, 5.5)),
+                    Velocity {
+                        velocity: Vec3::ZERO,
+                    },
+                ),
+                (
+                    EntityType::BasicUnit,
+                    Transform::from_position(Vec3::new(40.5, 0.0, 12.5)),
+                    Velocity {
+                        velocity: Vec3::ZERO,
+                    },
+                ),
+            ])
+            .to_vec();
+        let initial_positions = positions(&world);
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            map_hash: 0,
+            initial_world: net_serialization.serialize_world(&world, any()),
+        };
+        let mut recorded = Vec::new();
+        let mut recorder = ReplayRecorder::new(&mut recorded, &header).unwrap();
+
+        let mut resources = Resources::default();
+        resources.insert(tilemap.clone());
+        resources.insert(GameTime::default());
+        resources.insert(Orders::default());
+        resources.insert(SimulationStats::default());
+        let mut schedule = add_simulation_systems(&mut Schedule::builder()).build();
+        let mut halfway_positions = Vec::new();
+        for tick in 0..300 {
+            // Uneven delta times like the ones of the server loop
+            let delta_time = 0.01 + (tick % 7) as f32 * 0.003;
+            resources.get_mut::<GameTime>().unwrap().advance(delta_time);
+            let commands = match tick {
+                10 => vec![Command::Move {
+                    entity: units[0],
+                    target: Vec3A::new(20.0, 0.0, 30.0),
+                }],
+                120 => vec![
+                    Command::Move {
+                        entity: units[0],
+                        target: Vec3A::new(2.0, 0.0, 60.0),
+                    },
+                    Command::Move {
+                        entity: units[1],
+                        target: Vec3A::new(30.0, 0.0, 3.0),
+                    },
+                ],
+                _ => Vec::new(),
+            };
+            resources.insert(Orders { commands });
+            schedule.execute(&mut world, &mut resources);
+            recorder
+                .record_tick(
+                    tick,
+                    delta_time,
+                    &resources.get::<Orders>().unwrap(),
+                    &net_serialization,
+                )
+                .unwrap();
+            if tick == 149 {
+                halfway_positions = positions(&world);
+            }
+        }
+        let final_positions = positions(&world);
+        assert_ne!(initial_positions, final_positions);
+
+        let replay = Replay::read(recorded.as_slice()).unwrap();
+        let mut player = ReplayPlayer::new(replay, tilemap).unwrap();
+        assert_eq!(player.len(), 300);
+        while player.step().unwrap() {}
+        assert_eq!(final_positions, positions(player.world()));
+
+        player.seek(150).unwrap();
+        assert_eq!(player.current_tick(), 150);
+        assert_eq!(halfway_positions, positions(player.world()));
+    }
+
+    #[test]
+    fn truncated_replay_is_readable() {
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            map_hash: 0,
+            initial_world: Vec::new(),
+        };
+        let mut recorded = Vec::new();
+        let mut recorder = ReplayRecorder::new(&mut recorded, &header).unwrap();
+        for tick in 0..3 {
+            recorder
+                .record_tick(
+                    tick,
+                    0.016,
+                    &Orders::default(),
+                    &NetworkSerialization::default(),
+                )
+                .unwrap();
+        }
+        let replay = Replay::read(&recorded[..recorded.len() - 2]).unwrap();
+        assert_eq!(replay.ticks.len(), 2);
+    }
+
+    #[test]
+    fn buffered_ticks_are_flushed_periodically() {
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            map_hash: 0,
+            initial_world: Vec::new(),
+        };
+        let mut recorder = ReplayRecorder::new(BufWriter::new(Vec::new()), &header).unwrap();
+        let record_tick = |recorder: &mut ReplayRecorder<BufWriter<Vec<u8>>>, tick| {
+            recorder
+                .record_tick(
+                    tick,
+                    0.016,
+                    &Orders::default(),
+                    &NetworkSerialization::default(),
+                )
+                .unwrap();
+            Replay::read(recorder.writer.get_ref().as_slice())
+                .map_or(0, |replay| replay.ticks.len())
+        };
+        assert_eq!(record_tick(&mut recorder, 0), 0);
+        recorder.flushed_at -= FLUSH_INTERVAL;
+        assert_eq!(record_tick(&mut recorder, 1), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn reading_random_bytes_never_panics(bytes in prop::collection::vec(prop::num::u8::ANY, 0..512)) {
+            let _ = Replay::read(bytes.as_slice());
+        }
+    }
+}
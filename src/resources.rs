@@ -1,16 +1,30 @@
-use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
-use std::time::Instant;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bincode::de::Deserializer;
 use bincode::{DefaultOptions, Options};
 use crossbeam_channel::{Receiver, Sender};
 use glam::Vec3A;
-use laminar::{Config, Packet, Socket, SocketEvent};
-use legion::{query::LayoutFilter, serialize::Canon, *};
-use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use laminar::{Config, ConnectionManager, Packet, SocketEvent};
+use legion::{query::LayoutFilter, serialize::CustomEntitySerializer, world::Allocate, *};
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed},
+    Deserialize, Serialize,
+};
 
-use crate::components::{EntityType, Transform, Velocity};
+use crate::chat::{ChatChannel, PingKind};
+use crate::components::{EntityType, Owner, PlayerId, Transform, Velocity};
+use crate::desync::ReplicatedState;
+use crate::game_time::GameTime;
+use crate::link_conditioner::{ConditionedSocket, LinkConditioner};
+use crate::lockstep::NetworkMode;
+use crate::network_stats::{self, CountingConnection, NetworkStats};
+use crate::relevancy::AreaOfInterest;
+use crate::transfer::{TransferAck, TransferChunk};
+use crate::transform_encoding::{self, MapBounds, MIN_ENCODED_SIZE};
 #[derive(Debug, Clone, Copy)]
 pub struct WindowSize {
     pub physical_width: u32,
@@ -30,6 +44,8 @@ impl WindowSize {
 pub struct DebugRenderSettings {
     pub show_grid: bool,
     pub show_bounding_boxes: bool,
+    pub show_prediction_corrections: bool,
+    pub show_network_stats: bool,
 }
 
 #[derive(Debug)]
@@ -47,6 +63,14 @@ impl Time {
         self.current_frame += 1;
     }
 
+    /// Advance the time with a given delta instead of the elapsed wall clock time.
+    /// Used when the simulation must be reproducible.
+    pub fn advance(&mut self, delta_time: f32) {
+        self.delta_time = delta_time;
+        self.current_time += Duration::from_secs_f32(delta_time);
+        self.current_frame += 1;
+    }
+
     /// Get the delta time.
     #[inline]
     pub fn delta_time(&self) -> f32 {
@@ -109,63 +133,444 @@ impl Default for FpsStats {
 pub struct NetworkSocket {
     pub sender: Sender<Packet>,
     pub receiver: Receiver<SocketEvent>,
-    pub ip: [u8; 4],
-    pub port: u16,
+    pub local_addr: SocketAddr,
+    /// Traffic of the socket's connections, shared by every copy of the socket
+    pub stats: NetworkStats,
 }
 
 impl NetworkSocket {
-    fn from_socket(mut socket: Socket) -> NetworkSocket {
+    fn from_socket(
+        socket: UdpSocket,
+        config: Config,
+        link_conditioner: Option<LinkConditioner>,
+    ) -> NetworkSocket {
         let local_addr = socket
             .local_addr()
             .expect("There must exist a local addr the socket is bound to");
-        let ip = if let IpAddr::V4(ipv4) = local_addr.ip() {
-            ipv4.octets()
-        } else {
-            panic!("Expect to be bound to ipV4 addr");
-        };
+        let socket = ConditionedSocket::new(socket, config.blocking_mode, link_conditioner)
+            .expect("Failed to configure socket");
+        let mut connection_manager: ConnectionManager<_, CountingConnection> =
+            ConnectionManager::new(socket, config);
         let network_socket = NetworkSocket {
-            sender: socket.get_packet_sender(),
-            receiver: socket.get_event_receiver(),
-            ip,
-            port: local_addr.port(),
+            sender: connection_manager.event_sender().clone(),
+            receiver: connection_manager.event_receiver().clone(),
+            local_addr,
+            stats: NetworkStats::default(),
         };
-        std::thread::spawn(move || socket.start_polling());
+        let stats = network_socket.stats.clone();
+        // Same polling loop as laminar's own Socket
+        std::thread::spawn(move || {
+            network_stats::set_socket_stats(stats);
+            loop {
+                connection_manager.manual_poll(Instant::now());
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
         network_socket
     }
 
-    pub fn bind_any_with_config(config: Config) -> NetworkSocket {
-        let socket = Socket::bind_any_with_config(config).expect("Failed to open socket");
-        NetworkSocket::from_socket(socket)
+    /// Binds to a random port on localhost
+    pub fn bind_localhost_with_config(
+        config: Config,
+        link_conditioner: Option<LinkConditioner>,
+    ) -> NetworkSocket {
+        NetworkSocket::bind_with_config((Ipv4Addr::LOCALHOST, 0), config, link_conditioner)
     }
 
-    pub fn bind_with_config<A: ToSocketAddrs>(addresses: A, config: Config) -> NetworkSocket {
-        let socket = Socket::bind_with_config(addresses, config).expect("Failed to open socket");
-        NetworkSocket::from_socket(socket)
+    pub fn bind_with_config<A: ToSocketAddrs>(
+        addresses: A,
+        config: Config,
+        link_conditioner: Option<LinkConditioner>,
+    ) -> NetworkSocket {
+        let socket = UdpSocket::bind(addresses).expect("Failed to open socket");
+        NetworkSocket::from_socket(socket, config, link_conditioner)
+    }
+
+    /// Binds to a random port on the local interface that's used to reach the remote address.
+    /// This makes sure the socket uses the same ip version as the remote and that the local
+    /// address is reachable from it, which isn't the case when binding to the unspecified address.
+    pub fn bind_for_remote_with_config(
+        remote: SocketAddr,
+        config: Config,
+        link_conditioner: Option<LinkConditioner>,
+    ) -> NetworkSocket {
+        let local_ip = local_ip_for_remote(remote).unwrap_or_else(|err| {
+            warn!(
+                "Failed to determine local ip used to reach {}: {}, falling back to unspecified",
+                remote, err
+            );
+            unspecified_ip_for(remote)
+        });
+        NetworkSocket::bind_with_config(SocketAddr::new(local_ip, 0), config, link_conditioner)
+    }
+
+    /// Sends through the same socket but receives the events from the given channel
+    /// instead, used when the events of a socket are routed to several receivers
+    pub fn with_receiver(&self, receiver: Receiver<SocketEvent>) -> NetworkSocket {
+        NetworkSocket {
+            sender: self.sender.clone(),
+            receiver,
+            local_addr: self.local_addr,
+            stats: self.stats.clone(),
+        }
     }
 }
 
-//Move this
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub enum ClientUpdate {
+fn unspecified_ip_for(remote: SocketAddr) -> IpAddr {
+    match remote {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+fn local_ip_for_remote(remote: SocketAddr) -> Result<IpAddr> {
+    // Connecting a udp socket doesn't send anything but makes the os pick
+    // the local interface that routes to the remote address
+    let probe = UdpSocket::bind(SocketAddr::new(unspecified_ip_for(remote), 0))?;
+    probe.connect(remote)?;
+    Ok(probe.local_addr()?.ip())
+}
+
+/// Random secret the server gives a client when it joins. Updates are only accepted when they
+/// carry the token of the client at the address they came from, so they can't be forged
+/// by anyone who merely knows or spoofs the address.
+pub type SessionToken = u64;
+
+/// A client update as it's sent to the server. Every update after the Welcome carries the
+/// client's token, the server checks it before looking at the update.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientMessage {
+    /// None for the StartGame, which is sent before the client has a token
+    pub token: Option<SessionToken>,
+    pub update: ClientUpdate,
+}
+
+/// Serialized like a ClientMessage without cloning the update
+#[derive(Serialize)]
+struct ClientMessageRef<'a> {
+    token: Option<SessionToken>,
+    update: &'a ClientUpdate,
+}
+
+/// An order of a player, sent in batches with ClientCommands or Commands and applied by the
+/// simulation. Kept apart from ClientUpdate so a batch can't contain other messages or
+/// nest batches, which would make decoding recurse for every level of nesting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Command {
     Move { entity: Entity, target: Vec3A },
-    StartGame { ip: [u8; 4], port: u16 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientUpdate {
+    /// Asks to join a match, the server identifies the client by the address the
+    /// packet came from and answers with a Welcome
+    StartGame { name: String, role: ClientRole },
+    /// Request map chunks starting from the chunk index `start`
+    RequestMapChunks { start: u32, count: u32 },
+    /// The client has a map matching the announced map hash
+    MapReady,
+    /// The part of the map currently viewed by the client
+    AreaOfInterest(AreaOfInterest),
+    /// Answered with a pong to measure the round trip time and clock offset
+    Ping { sequence: u32 },
+    /// Commands to run in the given lockstep turn
+    Commands { turn: u64, commands: Vec<Command> },
+    /// Checksum of the client's state after running the given lockstep turn
+    TurnChecksum { turn: u64, checksum: u64 },
+    /// The client's state doesn't match the server's, asks for a Resync
+    RequestResync,
+    /// Commands issued by the client during a frame, see `commands::CommandBatcher`.
+    /// `tick` is the last server tick the client had received and `sequence` numbers
+    /// the batches so the server can apply them in order and drop duplicates.
+    ClientCommands {
+        tick: u64,
+        sequence: u32,
+        commands: Vec<Command>,
+    },
+    /// Chat message for the server to pass on, sent on the CHAT_STREAM
+    Chat { channel: ChatChannel, text: String },
+    /// Marks a position on the map for the sender's team, sent on the CHAT_STREAM
+    MapPing { position: Vec3A, kind: PingKind },
+    /// The chunks of a transfer received so far, the acks may arrive in any order
+    TransferAck(TransferAck),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerUpdate {
+    /// The state of the entities relevant to the client after the given tick.
+    /// Every STATE_CHECKSUM_INTERVAL updates carries a checksum of their replicated state.
     State {
-        transforms: Vec<(Entity, Transform)>,
+        tick: u64,
+        /// The match clock after the tick
+        game_time: GameTime,
+        /// Encoded with NetworkSerialization::encode_transforms
+        transforms: Vec<u8>,
+        checksum: Option<u64>,
+    },
+    /// The full replicated state of the entities relevant to the client,
+    /// sent as a response to RequestResync
+    Resync {
+        tick: u64,
+        entities: Vec<ReplicatedState>,
+    },
+    /// Answer to StartGame with the token the client's updates have to carry
+    Welcome { token: SessionToken },
+    /// Announces the map that will be played, sent as a response to StartGame after Welcome
+    MapInfo { name: String, hash: u64, size: u64 },
+    /// Part of the map file starting at byte `index * MAP_CHUNK_SIZE`
+    MapChunk { index: u32, bytes: Vec<u8> },
+    /// The match has started. Tells the receiving client its player id and how the state is
+    /// kept in sync, spectators have no player id. The world follows as a transfer.
+    InitialState {
+        player: Option<PlayerId>,
+        network_mode: NetworkMode,
+    },
+    /// The serialized world the game starts from, always sent as a transfer. Spectators
+    /// joining after the start get the world as it is when they are ready.
+    World { world: Vec<u8> },
+    /// Part of a server update too large for a single packet, sent on the TRANSFER_STREAM.
+    /// The reassembled data is a serialized ServerUpdate, see `transfer`.
+    Transfer(TransferChunk),
+    /// Answer to a ping with the server clock at the time it was handled
+    Pong {
+        sequence: u32,
+        server_time: Duration,
+    },
+    /// The commands of all players to run in the given lockstep turn, in the order they
+    /// must be applied. Sent for every turn even if there are no commands.
+    Turn { turn: u64, commands: Vec<Command> },
+    /// All command batches up to and including the sequence have been received
+    CommandsAck { sequence: u32 },
+    /// Change of the server's state the players should be told about
+    Notice(ServerNotice),
+    /// Entities spawned by the server after the game started
+    Spawned { entities: Vec<ReplicatedState> },
+    /// Chat message of a client, the player is None for spectators
+    Chat {
+        sender: String,
+        player: Option<PlayerId>,
+        channel: ChatChannel,
+        text: String,
     },
+    /// Position on the map pinged by a teammate, or a spectator when spectating
+    MapPing {
+        sender: String,
+        player: Option<PlayerId>,
+        position: Vec3A,
+        kind: PingKind,
+    },
+}
+
+/// Whether a client joins the match as a player or only watches it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientRole {
+    Player,
+    /// Receives all entities, can join after the match started but can't give orders
+    Spectator,
+}
+
+/// Shown to the players when the server is paused, shut down or kicks them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerNotice {
+    Paused,
+    Resumed,
+    Kicked {
+        reason: String,
+    },
+    /// All players have left the match, the server closes it
+    MatchEnded,
+    ShuttingDown,
 }
 
 pub const SERVER_UPDATE_STREAM: u8 = 1;
 pub const CLIENT_UPDATE_STREAM: u8 = 2;
+pub const MAP_STREAM: u8 = 3;
+pub const AREA_OF_INTEREST_STREAM: u8 = 4;
+pub const LOCKSTEP_STREAM: u8 = 5;
+pub const CHAT_STREAM: u8 = 6;
+pub const TRANSFER_STREAM: u8 = 7;
+
+/// Size in bytes of each map chunk sent during map download
+pub const MAP_CHUNK_SIZE: usize = 1024;
+/// Max number of map chunks requested at once during map download
+pub const MAP_DOWNLOAD_WINDOW: u32 = 64;
+
+/// Bumped whenever the format of client or server updates changes
+pub const PROTOCOL_VERSION: u16 = 13;
+/// Max size in bytes of a decoded message. This protects against length prefixes
+/// claiming huge allocations and is larger than any packet laminar reassembles by default.
+pub const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ClientUpdate,
+    ServerUpdate,
+    /// A single command as it's stored in replays
+    Command,
+}
+
+/// Every client and server update is wrapped in an envelope so that messages
+/// from other protocol versions or sent to the wrong side can be rejected
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u16,
+    message_type: MessageType,
+    message: T,
+}
+
+/// The beginning of an Envelope, decoded first to validate it
+#[derive(Deserialize)]
+struct EnvelopeHeader {
+    version: u16,
+    message_type: MessageType,
+}
+
+/// Max number of bad packets logged per BAD_PACKET_LOG_INTERVAL
+const MAX_BAD_PACKET_LOGS: u32 = 10;
+const BAD_PACKET_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logs packets that failed to decode without letting a misbehaving peer flood the log
+#[derive(Debug)]
+pub struct BadPacketLog {
+    interval_start: Instant,
+    logged: u32,
+    suppressed: u32,
+}
+
+impl Default for BadPacketLog {
+    fn default() -> Self {
+        BadPacketLog {
+            interval_start: Instant::now(),
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+}
+
+impl BadPacketLog {
+    pub fn report(&mut self, addr: SocketAddr, err: &anyhow::Error) {
+        let now = Instant::now();
+        if now.duration_since(self.interval_start) >= BAD_PACKET_LOG_INTERVAL {
+            if self.suppressed > 0 {
+                warn!(
+                    "Suppressed {} bad packet warnings during the last {}s",
+                    self.suppressed,
+                    BAD_PACKET_LOG_INTERVAL.as_secs()
+                );
+            }
+            *self = BadPacketLog {
+                interval_start: now,
+                logged: 0,
+                suppressed: 0,
+            };
+        }
+        if self.logged < MAX_BAD_PACKET_LOGS {
+            self.logged += 1;
+            warn!("Bad packet from {}: {:#}", addr, err);
+        } else {
+            self.suppressed += 1;
+        }
+    }
+}
+
+pub const DEFAULT_SERVER_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_SERVER_PORT: u16 = 1338;
+/// Ids handed out by a replica for entities it created itself start here, the authoritative
+/// side hands out ids from 0. The ranges keep the two from ever colliding.
+const REPLICA_IDS_START: u64 = 1 << 63;
+
+/// Maps entities to the ids they are replicated with. Ids are handed out in the order the
+/// entities are first serialized which keeps them small for the varint encoded state updates.
+/// A replica maps ids of the authoritative side it hasn't seen yet to newly allocated
+/// entities, the authoritative side rejects ids it never handed out.
+struct NetworkIds {
+    inner: Mutex<NetworkIdsInner>,
+    authoritative: bool,
+}
+
+struct NetworkIdsInner {
+    ids: HashMap<Entity, u64>,
+    entities: HashMap<u64, Entity>,
+    allocate: Allocate,
+    next_id: u64,
+    /// Id that couldn't be mapped while deserializing, see `NetworkIds::take_unknown_id`
+    unknown_id: Option<u64>,
+}
+
+impl NetworkIds {
+    fn new(authoritative: bool) -> Self {
+        NetworkIds {
+            inner: Mutex::new(NetworkIdsInner {
+                ids: HashMap::new(),
+                entities: HashMap::new(),
+                allocate: Allocate::new(),
+                next_id: if authoritative { 0 } else { REPLICA_IDS_START },
+                unknown_id: None,
+            }),
+            authoritative,
+        }
+    }
+
+    /// The entity replicated with the id
+    fn entity(&self, id: u64) -> Result<Entity> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entity) = inner.entities.get(&id) {
+            return Ok(*entity);
+        }
+        if self.authoritative || id >= REPLICA_IDS_START {
+            return Err(anyhow!("Unknown network id: {}", id));
+        }
+        let entity = inner.allocate.next().unwrap();
+        inner.ids.insert(entity, id);
+        inner.entities.insert(id, entity);
+        Ok(entity)
+    }
+
+    fn remove(&self, entity: Entity) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(id) = inner.ids.remove(&entity) {
+            inner.entities.remove(&id);
+        }
+    }
+
+    /// Legion's entity deserialization can't fail, an unknown id is remembered instead and
+    /// checked once the message has been deserialized
+    fn take_unknown_id(&self) -> Result<()> {
+        match self.inner.lock().unwrap().unknown_id.take() {
+            Some(id) => Err(anyhow!("Unknown network id: {}", id)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CustomEntitySerializer for NetworkIds {
+    type SerializedID = u64;
+
+    fn to_serialized(&self, entity: Entity) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(id) = inner.ids.get(&entity) {
+            return *id;
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.ids.insert(entity, id);
+        inner.entities.insert(id, entity);
+        id
+    }
+
+    fn from_serialized(&self, id: u64) -> Entity {
+        self.entity(id).unwrap_or_else(|_| {
+            // The entity isn't stored, the message is rejected by take_unknown_id
+            let mut inner = self.inner.lock().unwrap();
+            inner.unknown_id.get_or_insert(id);
+            inner.allocate.next().unwrap()
+        })
+    }
+}
 
-pub const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
-pub const SERVER_PORT: u16 = 1338;
 pub struct NetworkSerialization {
     registry: Registry<i32>,
-    canon: Canon,
+    ids: NetworkIds,
 }
 
 impl Default for NetworkSerialization {
@@ -174,56 +579,416 @@ impl Default for NetworkSerialization {
         registry.register::<Velocity>(1);
         registry.register::<Transform>(2);
         registry.register::<EntityType>(3);
+        registry.register::<Owner>(4);
         NetworkSerialization {
             registry,
-            canon: Canon::default(),
+            ids: NetworkIds::new(false),
         }
     }
 }
 // TODO: refactor this
 impl NetworkSerialization {
-    pub fn serialize_client_update(&self, update: &ClientUpdate) -> Vec<u8> {
+    /// Serialization of the side that owns the entities, only accepts the ids it handed out.
+    /// The default is a replica that gets its entities from the authoritative side.
+    pub fn authoritative() -> Self {
+        NetworkSerialization {
+            ids: NetworkIds::new(true),
+            ..Default::default()
+        }
+    }
+
+    fn serialize_message<T: Serialize>(&self, message_type: MessageType, message: &T) -> Vec<u8> {
         use legion::serialize::set_entity_serializer;
-        set_entity_serializer(&self.canon, || {
-            bincode::serialize(&update).expect("Client update to be seializable")
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            message_type,
+            message,
+        };
+        set_entity_serializer(&self.ids, || {
+            DefaultOptions::new()
+                .with_fixint_encoding()
+                .serialize(&envelope)
+                .expect("Message to be serializable")
         })
     }
 
-    pub fn deserialize_client_update(&self, bytes: &[u8]) -> ClientUpdate {
+    fn deserialize_message<T: DeserializeOwned>(
+        &self,
+        message_type: MessageType,
+        bytes: &[u8],
+    ) -> Result<T> {
         use legion::serialize::set_entity_serializer;
-        set_entity_serializer(&self.canon, || {
-            bincode::deserialize(bytes).expect("Client update to be deserializable")
-        })
+        let options = DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(MAX_MESSAGE_SIZE);
+        let header: EnvelopeHeader = options.allow_trailing_bytes().deserialize(bytes)?;
+        if header.version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "Unsupported protocol version: {}, expected: {}",
+                header.version,
+                PROTOCOL_VERSION
+            ));
+        }
+        if header.message_type != message_type {
+            return Err(anyhow!(
+                "Unexpected message type: {:?}, expected: {:?}",
+                header.message_type,
+                message_type
+            ));
+        }
+        self.ids.take_unknown_id()?;
+        let envelope: Envelope<T> =
+            set_entity_serializer(&self.ids, || options.deserialize(bytes))?;
+        self.ids.take_unknown_id()?;
+        Ok(envelope.message)
+    }
+
+    /// Serializes an update sent before the Welcome, which carries no token
+    pub fn serialize_client_update(&self, update: &ClientUpdate) -> Vec<u8> {
+        self.serialize_message(
+            MessageType::ClientUpdate,
+            &ClientMessageRef {
+                token: None,
+                update,
+            },
+        )
+    }
+
+    /// Serializes an update of a client that has been given the token in the Welcome
+    pub fn serialize_session_update(&self, token: SessionToken, update: &ClientUpdate) -> Vec<u8> {
+        self.serialize_message(
+            MessageType::ClientUpdate,
+            &ClientMessageRef {
+                token: Some(token),
+                update,
+            },
+        )
+    }
+
+    pub fn deserialize_client_update(&self, bytes: &[u8]) -> Result<ClientMessage> {
+        self.deserialize_message(MessageType::ClientUpdate, bytes)
+    }
+
+    pub fn serialize_command(&self, command: &Command) -> Vec<u8> {
+        self.serialize_message(MessageType::Command, command)
+    }
+
+    pub fn deserialize_command(&self, bytes: &[u8]) -> Result<Command> {
+        self.deserialize_message(MessageType::Command, bytes)
     }
 
     pub fn serialize_server_update(&self, server_update: &ServerUpdate) -> Vec<u8> {
-        use legion::serialize::set_entity_serializer;
-        set_entity_serializer(&self.canon, || {
-            bincode::serialize(server_update).expect("Server update to be serializable")
-        })
+        self.serialize_message(MessageType::ServerUpdate, server_update)
     }
 
-    pub fn deserialize_server_update(&self, bytes: &[u8]) -> ServerUpdate {
-        use legion::serialize::set_entity_serializer;
-        set_entity_serializer(&self.canon, || {
-            bincode::deserialize(bytes).expect("Server update to be serializable")
-        })
+    pub fn deserialize_server_update(&self, bytes: &[u8]) -> Result<ServerUpdate> {
+        self.deserialize_message(MessageType::ServerUpdate, bytes)
     }
 
     pub fn deserialize_new_world(&self, world_bytes: &[u8]) -> Result<World> {
-        let new_world = self.registry.as_deserialize(&self.canon).deserialize(
-            &mut Deserializer::from_slice(
-                world_bytes,
-                DefaultOptions::new()
-                    .with_fixint_encoding()
-                    .allow_trailing_bytes(),
-            ),
-        )?;
+        let new_world =
+            self.registry
+                .as_deserialize(&self.ids)
+                .deserialize(&mut Deserializer::from_slice(
+                    world_bytes,
+                    DefaultOptions::new()
+                        .with_fixint_encoding()
+                        // Nothing in a valid world can be larger than the serialized world itself
+                        .with_limit(world_bytes.len() as u64)
+                        .allow_trailing_bytes(),
+                ))?;
+        self.ids.take_unknown_id()?;
         Ok(new_world)
     }
 
+    /// The id the entity is replicated with, the same on the server and the clients
+    pub fn network_id(&self, entity: Entity) -> u64 {
+        self.ids.to_serialized(entity)
+    }
+
+    /// Forgets the id of an entity removed from the replicated world. Nothing despawns
+    /// replicated entities yet, units of clients that left stay in the match and keep their ids.
+    pub fn forget_entity(&self, entity: Entity) {
+        self.ids.remove(entity);
+    }
+
+    /// Encodes the transforms of the entities in the compact format of
+    /// `transform_encoding` prefixed by their varint network ids
+    pub fn encode_transforms(
+        &self,
+        transforms: &[(Entity, Transform)],
+        bounds: &MapBounds,
+    ) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + transforms.len() * (MIN_ENCODED_SIZE + 2));
+        transform_encoding::write_varint(transforms.len() as u64, &mut bytes);
+        for (entity, transform) in transforms {
+            transform_encoding::write_varint(self.network_id(*entity), &mut bytes);
+            transform_encoding::encode_transform(transform, bounds, &mut bytes);
+        }
+        bytes
+    }
+
+    pub fn decode_transforms(
+        &self,
+        mut bytes: &[u8],
+        bounds: &MapBounds,
+    ) -> Result<Vec<(Entity, Transform)>> {
+        let count = transform_encoding::read_varint(&mut bytes)?;
+        // Every transform takes up at least one byte for the id and MIN_ENCODED_SIZE bytes
+        if count > (bytes.len() / (MIN_ENCODED_SIZE + 1)) as u64 {
+            return Err(anyhow!(
+                "Too many transforms for the message size: {}",
+                count
+            ));
+        }
+        let mut transforms = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = transform_encoding::read_varint(&mut bytes)?;
+            let transform = transform_encoding::decode_transform(&mut bytes, bounds)?;
+            transforms.push((self.ids.entity(id)?, transform));
+        }
+        if !bytes.is_empty() {
+            return Err(anyhow!(
+                "{} trailing bytes after the transforms",
+                bytes.len()
+            ));
+        }
+        Ok(transforms)
+    }
+
     pub fn serialize_world<F: LayoutFilter>(&self, world: &World, filter: F) -> Vec<u8> {
-        let serilizable_world = world.as_serializable(filter, &self.registry, &self.canon);
+        let serilizable_world = world.as_serializable(filter, &self.registry, &self.ids);
         bincode::serialize(&serilizable_world).expect("World to be serializable")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn header(message_type: MessageType) -> Vec<u8> {
+        let mut bytes = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(message_type as u32).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn updates_round_trip() {
+        let net_serialization = NetworkSerialization::default();
+        let mut world = World::default();
+        let entity = world.push((Transform::default(),));
+        let update = ClientUpdate::Commands {
+            turn: 1,
+            commands: vec![Command::Move {
+                entity,
+                target: Vec3A::new(1.0, 0.0, 2.0),
+            }],
+        };
+        let bytes = net_serialization.serialize_session_update(2, &update);
+        assert_eq!(bytes[..6], header(MessageType::ClientUpdate)[..]);
+        assert_eq!(
+            net_serialization.deserialize_client_update(&bytes).unwrap(),
+            ClientMessage {
+                token: Some(2),
+                update
+            }
+        );
+        let bytes = net_serialization.serialize_client_update(&ClientUpdate::MapReady);
+        assert_eq!(
+            net_serialization.deserialize_client_update(&bytes).unwrap(),
+            ClientMessage {
+                token: None,
+                update: ClientUpdate::MapReady
+            }
+        );
+        let bytes = net_serialization.serialize_server_update(&ServerUpdate::MapChunk {
+            index: 3,
+            bytes: vec![1, 2, 3],
+        });
+        assert!(matches!(
+            net_serialization.deserialize_server_update(&bytes).unwrap(),
+            ServerUpdate::MapChunk { index: 3, bytes } if bytes == [1, 2, 3]
+        ));
+    }
+
+    #[test]
+    fn worlds_larger_than_a_packet_arrive_as_a_transfer() {
+        use crate::transfer::{IncomingTransfers, OutgoingTransfers};
+        let net_serialization = NetworkSerialization::default();
+        let world = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let bytes = net_serialization.serialize_server_update(&ServerUpdate::World {
+            world: world.clone(),
+        });
+        let mut sender = OutgoingTransfers::default();
+        sender.push(&bytes);
+        let mut receiver = IncomingTransfers::default();
+        let mut received = None;
+        while received.is_none() {
+            for chunk in sender.next_chunks() {
+                let chunk =
+                    net_serialization.serialize_server_update(&ServerUpdate::Transfer(chunk));
+                let chunk = match net_serialization.deserialize_server_update(&chunk).unwrap() {
+                    ServerUpdate::Transfer(chunk) => chunk,
+                    update => panic!("Unexpected update: {:?}", update),
+                };
+                let (ack, data) = receiver.receive(chunk).unwrap();
+                if let Some(ack) = ack {
+                    sender.acknowledge(ack);
+                }
+                received = received.or(data);
+            }
+        }
+        assert!(matches!(
+            net_serialization.deserialize_server_update(&received.unwrap()).unwrap(),
+            ServerUpdate::World { world: received } if received == world
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_message_type_and_version() {
+        let net_serialization = NetworkSerialization::default();
+        let bytes = net_serialization.serialize_client_update(&ClientUpdate::MapReady);
+        assert!(net_serialization.deserialize_server_update(&bytes).is_err());
+        let mut other_version = bytes.clone();
+        other_version[0] = other_version[0].wrapping_add(1);
+        assert!(net_serialization
+            .deserialize_client_update(&other_version)
+            .is_err());
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(net_serialization
+            .deserialize_client_update(&trailing)
+            .is_err());
+    }
+
+    #[test]
+    fn transforms_keep_entity_ids() {
+        let server = NetworkSerialization::authoritative();
+        let client = NetworkSerialization::default();
+        let mut world = World::default();
+        let entities: Vec<Entity> = world
+            .extend((0..200).map(|_| (EntityType::BasicUnit,)))
+            .to_vec();
+        let client_world = client
+            .deserialize_new_world(&server.serialize_world(&world, legion::any()))
+            .unwrap();
+        let transforms: Vec<(Entity, Transform)> = entities
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| {
+                let position = glam::Vec3::new(i as f32 * 0.5, 0.0, 3.0);
+                (*entity, Transform::from_position(position))
+            })
+            .collect();
+        let bounds = MapBounds::default();
+        let bytes = server.encode_transforms(&transforms, &bounds);
+        // Ids below 128 take one byte and the rest two, plus two bytes for the count
+        assert_eq!(bytes.len(), 2 + 200 * MIN_ENCODED_SIZE + 128 + 72 * 2);
+        let decoded = client.decode_transforms(&bytes, &bounds).unwrap();
+        for ((server_entity, sent), (client_entity, received)) in transforms.iter().zip(&decoded) {
+            assert!(client_world.entry_ref(*client_entity).is_ok());
+            assert_eq!(
+                server.network_id(*server_entity),
+                client.network_id(*client_entity)
+            );
+            assert!(sent
+                .matrix
+                .translation
+                .abs_diff_eq(received.matrix.translation, 1e-2));
+        }
+        assert!(client
+            .decode_transforms(&bytes[..bytes.len() - 1], &bounds)
+            .is_err());
+        assert!(client
+            .decode_transforms(&[0xff, 0xff, 0x03], &bounds)
+            .is_err());
+    }
+
+    #[test]
+    fn only_ids_of_the_authoritative_side_are_accepted() {
+        let server = NetworkSerialization::authoritative();
+        let client = NetworkSerialization::default();
+        let mut world = World::default();
+        let entity = world.push((EntityType::BasicUnit,));
+        let client_world = client
+            .deserialize_new_world(&server.serialize_world(&world, legion::any()))
+            .unwrap();
+        let client_entity = *<Entity>::query().iter(&client_world).next().unwrap();
+        let batch = |entity| ClientUpdate::Commands {
+            turn: 0,
+            commands: vec![Command::Move {
+                entity,
+                target: Vec3A::ZERO,
+            }],
+        };
+        let known = client.serialize_client_update(&batch(client_entity));
+        assert_eq!(
+            server.deserialize_client_update(&known).unwrap().update,
+            batch(entity)
+        );
+        // Entities the client created itself get ids the server never issued
+        let local_entity = World::default().push(());
+        let unknown = client.serialize_client_update(&batch(local_entity));
+        assert!(client.network_id(local_entity) >= REPLICA_IDS_START);
+        assert!(server.deserialize_client_update(&unknown).is_err());
+        // The failed message leaves nothing behind
+        assert!(server.deserialize_client_update(&known).is_ok());
+        // Forgotten entities are unknown again
+        server.forget_entity(entity);
+        assert!(server.deserialize_client_update(&known).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_length_prefix() {
+        let net_serialization = NetworkSerialization::default();
+        let mut bytes = header(MessageType::ServerUpdate);
+        // ServerUpdate::MapChunk claiming u64::MAX bytes
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(net_serialization.deserialize_server_update(&bytes).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_batches_are_rejected() {
+        let net_serialization = NetworkSerialization::default();
+        // A batch whose single command claims to be another batch, nested a thousand times
+        let batch = net_serialization.serialize_client_update(&ClientUpdate::Commands {
+            turn: 0,
+            commands: Vec::new(),
+        });
+        // The header and the missing token are only written once
+        let (header, batch) = batch.split_at(7);
+        let mut nested = batch[..batch.len() - 8].to_vec();
+        nested.extend_from_slice(&1u64.to_le_bytes());
+        let mut bytes = header.to_vec();
+        for _ in 0..1000 {
+            bytes.extend_from_slice(&nested);
+        }
+        assert!(net_serialization.deserialize_client_update(&bytes).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn decoding_random_bytes_never_panics(bytes in prop::collection::vec(prop::num::u8::ANY, 0..512)) {
+            let net_serialization = NetworkSerialization::default();
+            let _ = net_serialization.deserialize_client_update(&bytes);
+            let _ = net_serialization.deserialize_server_update(&bytes);
+            let _ = net_serialization.deserialize_new_world(&bytes);
+        }
+
+        #[test]
+        fn decoding_random_messages_never_panics(
+            message_type in prop_oneof![Just(MessageType::ClientUpdate), Just(MessageType::ServerUpdate)],
+            payload in prop::collection::vec(prop::num::u8::ANY, 0..512),
+        ) {
+            // A valid header makes sure the message decoding itself is exercised
+            let mut bytes = header(message_type);
+            bytes.extend_from_slice(&payload);
+            let net_serialization = NetworkSerialization::default();
+            let _ = net_serialization.deserialize_client_update(&bytes);
+            let _ = net_serialization.deserialize_server_update(&bytes);
+        }
+    }
+}
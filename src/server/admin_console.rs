@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use crossbeam_channel::Receiver;
+use glam::Vec3;
+use log::warn;
+
+use crate::components::{EntityType, PlayerId};
+
+use super::session::SessionId;
+
+const USAGE: &str = "expected one of: list, session <id>, kick <player id or name>, pause, \
+resume, tickrate <ticks per second>, speed <scale>, spawn <entity type> <x> <z> [player id], \
+save <path>, shutdown";
+
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// Lists the sessions and their clients
+    List,
+    /// Selects the session the session commands apply to
+    Select(SessionId),
+    /// Kicks the player with the given id or name
+    Kick(String),
+    Session(SessionCommand),
+    Shutdown,
+}
+
+/// Commands that apply to a single session
+#[derive(Debug)]
+pub enum SessionCommand {
+    Pause,
+    Resume,
+    TickRate(u32),
+    /// Scales how fast the game time passes, see `GameTime`
+    Speed(f32),
+    Spawn {
+        entity_type: EntityType,
+        position: Vec3,
+        owner: Option<PlayerId>,
+    },
+    /// Saves the world in the same format as the initial world of the replays
+    Save(PathBuf),
+}
+
+fn parse_entity_type(name: &str) -> Option<EntityType> {
+    match name.to_lowercase().as_str() {
+        "basicunit" | "basic_unit" | "unit" => Some(EntityType::BasicUnit),
+        _ => None,
+    }
+}
+
+/// Parses a coordinate of a position, which has to be finite to be on the map
+fn parse_coordinate(value: &str) -> Option<f32> {
+    value.parse().ok().filter(|value: &f32| value.is_finite())
+}
+
+fn parse_command(line: &str) -> Option<AdminCommand> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let command = match parts[..] {
+        ["list"] | ["players"] => AdminCommand::List,
+        ["session", id] => AdminCommand::Select(id.parse().ok()?),
+        ["kick", player] => AdminCommand::Kick(player.to_string()),
+        ["pause"] => AdminCommand::Session(SessionCommand::Pause),
+        ["resume"] => AdminCommand::Session(SessionCommand::Resume),
+        ["tickrate", tick_rate] => {
+            AdminCommand::Session(SessionCommand::TickRate(tick_rate.parse().ok()?))
+        }
+        ["speed", scale] => AdminCommand::Session(SessionCommand::Speed(scale.parse().ok()?)),
+        ["spawn", entity_type, x, z] | ["spawn", entity_type, x, z, _] => {
+            AdminCommand::Session(SessionCommand::Spawn {
+                entity_type: parse_entity_type(entity_type)?,
+                position: Vec3::new(parse_coordinate(x)?, 0.0, parse_coordinate(z)?),
+                owner: match parts.get(4) {
+                    Some(owner) => Some(owner.parse().ok()?),
+                    None => None,
+                },
+            })
+        }
+        ["save", path] => AdminCommand::Session(SessionCommand::Save(PathBuf::from(path))),
+        ["shutdown"] | ["quit"] | ["exit"] => AdminCommand::Shutdown,
+        _ => return None,
+    };
+    Some(command)
+}
+
+/// Reads admin commands from stdin on a separate thread
+pub fn spawn_admin_console() -> Receiver<AdminCommand> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while stdin
+            .read_line(&mut line)
+            .map(|read| read > 0)
+            .unwrap_or(false)
+        {
+            if !line.trim().is_empty() {
+                match parse_command(&line) {
+                    Some(command) => {
+                        if sender.send(command).is_err() {
+                            break;
+                        }
+                    }
+                    None => warn!("Unknown command: {}, {}", line.trim(), USAGE),
+                }
+            }
+            line.clear();
+        }
+    });
+    receiver
+}
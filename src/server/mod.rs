@@ -0,0 +1,822 @@
+//! The authoritative game server. Every match is a session with its own world and schedule,
+//! the sessions share a single socket.
+use std::{collections::HashSet, fs::File, io::BufWriter, net::SocketAddr, time::Instant};
+
+use anyhow::{Context, Result};
+use glam::{Quat, Vec3};
+use laminar::{Config, Packet, SocketEvent};
+use legion::{world::SubWorld, *};
+use log::{debug, error, info, warn};
+use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::{
+    chat::{is_recipient, sanitize_message, sanitize_name, ChatChannel, ChatRateLimiter, TeamId},
+    clock_sync::ServerClock,
+    commands::CommandSequencer,
+    components::*,
+    desync::{self, ReplicatedState, STATE_CHECKSUM_INTERVAL},
+    game_time::GameTime,
+    lockstep::{ChecksumHistory, NetworkMode, TurnScheduler},
+    metrics::serve_metrics,
+    relevancy::{AreaOfInterest, Relevancy, Viewer},
+    replay::ReplayRecorder,
+    resources::{
+        BadPacketLog, ClientMessage, ClientRole, ClientUpdate, Command, NetworkSerialization,
+        NetworkSocket, ServerNotice, ServerUpdate, SessionToken, CHAT_STREAM, LOCKSTEP_STREAM,
+        MAP_STREAM, SERVER_UPDATE_STREAM, TRANSFER_STREAM,
+    },
+    simulation::Orders,
+    tilemap::TileMap,
+    transfer::{OutgoingTransfers, TransferId, TRANSFER_CHUNK_SIZE},
+    transform_encoding::{quantize, MapBounds},
+};
+
+mod admin_console;
+mod server_config;
+mod server_map;
+mod server_metrics;
+mod session;
+
+pub use admin_console::{spawn_admin_console, AdminCommand, SessionCommand};
+pub use server_config::ServerConfig;
+pub use server_map::ServerMap;
+pub use server_metrics::ServerMetrics;
+use session::SessionManager;
+pub use session::{Session, SessionId, SessionPhase};
+
+#[derive(Debug)]
+struct ConnectedClient {
+    addr: SocketAddr,
+    /// Updates are only accepted from the address when they carry this token
+    token: SessionToken,
+    name: String,
+    /// None for spectators
+    player: Option<PlayerId>,
+    /// None for spectators, set once the match starts
+    team: Option<TeamId>,
+    has_map: bool,
+    /// Spectators that don't get the map in time are kicked
+    connected_at: Instant,
+    /// Set once the client has been sent the initial state, spectators joining after
+    /// the start get it once they have the map
+    joined: bool,
+    area_of_interest: Option<AreaOfInterest>,
+    /// Set once the client reports a lockstep checksum that doesn't match the server
+    desynced: bool,
+    /// The client asked for the full state to be sent with the next state update
+    resync_requested: bool,
+    /// Transfer of the last resync too large for a single packet, further resyncs
+    /// aren't sent until the client has received it
+    resync_transfer: Option<TransferId>,
+    commands: CommandSequencer,
+    chat_limiter: ChatRateLimiter,
+    /// Updates too large for a single packet, like the world, waiting to be sent
+    transfers: OutgoingTransfers,
+    /// Entities the client has been sent, the others are sent with `ServerUpdate::Spawned`
+    /// once they become relevant to it
+    known_entities: HashSet<Entity>,
+}
+
+impl ConnectedClient {
+    fn new(addr: SocketAddr, name: String, player: Option<PlayerId>) -> Self {
+        ConnectedClient {
+            addr,
+            token: rand::random(),
+            name,
+            player,
+            team: None,
+            has_map: false,
+            connected_at: Instant::now(),
+            joined: false,
+            area_of_interest: None,
+            desynced: false,
+            resync_requested: false,
+            resync_transfer: None,
+            commands: CommandSequencer::default(),
+            chat_limiter: ChatRateLimiter::new(Instant::now()),
+            transfers: OutgoingTransfers::default(),
+            known_entities: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectedClients {
+    // hash set?
+    clients: Vec<ConnectedClient>,
+}
+
+impl ConnectedClient {
+    /// Whether the message carries the client's token, the update is dropped otherwise
+    fn is_authentic(&self, message: &ClientMessage) -> bool {
+        let authentic = message.token == Some(self.token);
+        if !authentic {
+            warn!(
+                "Rejected an update from {} ({}) with the wrong session token",
+                self.name, self.addr
+            );
+        }
+        authentic
+    }
+
+    /// Runs `f` with the client's view of the world, None for spectators which
+    /// aren't affected by relevancy
+    fn with_viewer<R>(&self, world: &World, f: impl FnOnce(&Viewer) -> R) -> Option<R> {
+        let player = self.player?;
+        let unit_positions = Relevancy::unit_positions(world, player);
+        Some(f(&Viewer {
+            player,
+            area_of_interest: self.area_of_interest,
+            unit_positions: &unit_positions,
+        }))
+    }
+}
+
+impl ConnectedClients {
+    fn players(&self) -> impl Iterator<Item = &ConnectedClient> {
+        self.clients.iter().filter(|client| client.player.is_some())
+    }
+
+    fn spectators(&self) -> impl Iterator<Item = &ConnectedClient> {
+        self.clients.iter().filter(|client| client.player.is_none())
+    }
+
+    fn remove(&mut self, addr: SocketAddr) -> Option<ConnectedClient> {
+        let index = self.clients.iter().position(|client| client.addr == addr)?;
+        Some(self.clients.remove(index))
+    }
+}
+
+fn setup_world(
+    world: &mut World,
+    resources: &mut Resources,
+    net_serilization: &NetworkSerialization,
+    map: TileMap,
+    num_players: u8,
+) -> Vec<u8> {
+    // One unit per player, placed next to each other
+    world.extend((0..num_players).map(|player| {
+        (
+            EntityType::BasicUnit,
+            Transform::new(
+                Vec3::new(player as f32 * 2.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 1.0),
+                Quat::IDENTITY,
+            ),
+            Velocity {
+                velocity: Vec3::splat(0.0),
+            },
+            Owner { player },
+        )
+    }));
+    resources.insert(map);
+    net_serilization.serialize_world(world, any())
+}
+
+/// A game server hosting matches on its own socket. The binary runs it until the admin
+/// console shuts it down, tests run it in-process and step it with `update`.
+pub struct Server {
+    sessions: SessionManager,
+    local_addr: SocketAddr,
+    metrics: ServerMetrics,
+}
+
+impl Server {
+    /// Loads the configured map, binds the socket and serves the metrics if enabled
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let (server_map, tilemap) = ServerMap::load(&config.map)
+            .with_context(|| format!("Failed to load map {}", config.map.display()))?;
+        info!("Loaded map {} ({:016x})", server_map.name, server_map.hash);
+        Server::with_map(config, server_map, tilemap)
+    }
+
+    /// Same as `new` with a map that's already loaded
+    pub fn with_map(config: ServerConfig, server_map: ServerMap, tilemap: TileMap) -> Result<Self> {
+        if let Some(link_conditioner) = &config.link_conditioner {
+            warn!("Simulating bad network conditions: {:?}", link_conditioner);
+        }
+        let network_socket = NetworkSocket::bind_with_config(
+            config.socket_addr(),
+            Config::default(),
+            config.link_conditioner,
+        );
+        let local_addr = network_socket.local_addr;
+        info!("Server listening at {}", local_addr);
+        info!(
+            "Hosting up to {} matches of {} players",
+            config.max_sessions, config.players
+        );
+        let metrics = ServerMetrics::new(network_socket.stats.clone());
+        if let Some(addr) = config.metrics_addr() {
+            let rendered = metrics.clone();
+            let addr = serve_metrics(addr, move || rendered.render())
+                .with_context(|| format!("Failed to serve metrics at {}", addr))?;
+            info!("Serving metrics at http://{}/metrics", addr);
+        }
+        let sessions =
+            SessionManager::new(network_socket, config, server_map, tilemap, metrics.clone());
+        Ok(Server {
+            sessions,
+            local_addr,
+            metrics,
+        })
+    }
+
+    /// The address the clients connect to
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Runs the ticks that are due and handles the packets that arrive until the next
+    /// tick of a session is due, waits at most 50ms
+    #[inline]
+    pub fn update(&mut self) {
+        self.sessions.update();
+    }
+
+    /// Runs an admin command, returns true if the server should shut down
+    #[inline]
+    pub fn run_admin_command(&mut self, command: AdminCommand) -> bool {
+        self.sessions.run_admin_command(command)
+    }
+
+    #[inline]
+    pub fn session(&self, id: SessionId) -> Option<&Session> {
+        self.sessions.session(id)
+    }
+
+    /// Tells the clients the server is shutting down and ends every session
+    pub fn shut_down(&mut self) {
+        self.sessions.shut_down();
+    }
+}
+
+/// Copy of the world with only the replicated components of the entities. The server adds
+/// components like FlowField that can't be serialized.
+fn replicated_world(world: &World) -> World {
+    let mut replicated = World::default();
+    let mut query = <(Entity, &EntityType, &Transform, &Velocity, Option<&Owner>)>::query();
+    for (entity, entity_type, transform, velocity, owner) in query.iter(world) {
+        match owner {
+            Some(owner) => {
+                replicated.push_with_id(*entity, (*entity_type, *transform, *velocity, *owner))
+            }
+            None => replicated.push_with_id(*entity, (*entity_type, *transform, *velocity)),
+        }
+    }
+    replicated
+}
+
+/// Every entity of the world, the clients know all of them once they have been sent the world
+fn world_entities(world: &World) -> HashSet<Entity> {
+    <Entity>::query().iter(world).copied().collect()
+}
+
+/// Sends the current world to the spectators that joined after the start once they have the map
+fn send_world_to_late_spectators(world: &World, resources: &Resources) {
+    let mut connected_clients = resources.get_mut::<ConnectedClients>().unwrap();
+    let mut waiting = connected_clients
+        .clients
+        .iter_mut()
+        .filter(|client| client.has_map && !client.joined)
+        .peekable();
+    if waiting.peek().is_none() {
+        return;
+    }
+    let network = resources.get::<NetworkSocket>().unwrap();
+    let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+    let network_mode = *resources.get::<NetworkMode>().unwrap();
+    let world_update = net_serilization.serialize_server_update(&ServerUpdate::World {
+        world: net_serilization.serialize_world(&replicated_world(world), any()),
+    });
+    // The pause was announced before the spectators joined
+    let paused = resources.get::<GameTime>().unwrap().is_paused();
+    let paused_notice =
+        net_serilization.serialize_server_update(&ServerUpdate::Notice(ServerNotice::Paused));
+    for client in waiting {
+        let payload = net_serilization.serialize_server_update(&ServerUpdate::InitialState {
+            player: client.player,
+            network_mode,
+        });
+        network
+            .sender
+            .send(Packet::reliable_ordered(client.addr, payload, None))
+            .unwrap();
+        if paused {
+            // On the same stream as the initial state so it arrives once the client is in game
+            network
+                .sender
+                .send(Packet::reliable_ordered(
+                    client.addr,
+                    paused_notice.clone(),
+                    None,
+                ))
+                .unwrap();
+        }
+        client.transfers.push(&world_update);
+        client.known_entities = world_entities(world);
+        client.joined = true;
+        info!("Sent the world to spectator {}", client.name);
+    }
+}
+
+/// Lets spectators join a match in progress, players can only join before it starts.
+/// Lockstep clients must run every turn from the start so spectators can't join those late.
+#[allow(clippy::too_many_arguments)]
+fn join_started_match(
+    network: &NetworkSocket,
+    net_serilization: &NetworkSerialization,
+    connected_clients: &mut ConnectedClients,
+    server_map: &ServerMap,
+    network_mode: NetworkMode,
+    addr: SocketAddr,
+    name: String,
+    role: ClientRole,
+) {
+    // Clients without a name are refused before they are routed to a session
+    let name = match sanitize_name(&name) {
+        Some(name) => name,
+        None => return,
+    };
+    let refusal = match (role, network_mode) {
+        (ClientRole::Player, _) => Some("The match has already started"),
+        (ClientRole::Spectator, NetworkMode::Snapshots) => None,
+        (ClientRole::Spectator, _) => Some("Spectators can't join a lockstep match late"),
+    };
+    let update = match refusal {
+        Some(reason) => {
+            info!("Refused {} ({}): {}", name, addr, reason);
+            ServerUpdate::Notice(ServerNotice::Kicked {
+                reason: reason.to_string(),
+            })
+        }
+        None => {
+            info!("Spectator {} ({}) joined the match", name, addr);
+            let client = ConnectedClient::new(addr, name, None);
+            welcome(network, net_serilization, &client);
+            connected_clients.clients.push(client);
+            server_map.map_info()
+        }
+    };
+    let payload = net_serilization.serialize_server_update(&update);
+    network
+        .sender
+        .send(Packet::reliable_ordered(addr, payload, Some(MAP_STREAM)))
+        .unwrap();
+}
+
+/// Gives the client its session token, sent on the MAP_STREAM so it arrives before the map info
+fn welcome(
+    network: &NetworkSocket,
+    net_serilization: &NetworkSerialization,
+    client: &ConnectedClient,
+) {
+    let payload = net_serilization.serialize_server_update(&ServerUpdate::Welcome {
+        token: client.token,
+    });
+    network
+        .sender
+        .send(Packet::reliable_ordered(
+            client.addr,
+            payload,
+            Some(MAP_STREAM),
+        ))
+        .unwrap();
+}
+
+fn notify_clients(resources: &Resources, notice: ServerNotice) {
+    let network = resources.get::<NetworkSocket>().unwrap();
+    let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+    let connected_clients = resources.get::<ConnectedClients>().unwrap();
+    let payload = net_serilization.serialize_server_update(&ServerUpdate::Notice(notice));
+    for client in &connected_clients.clients {
+        network
+            .sender
+            .send(Packet::reliable_unordered(client.addr, payload.clone()))
+            .unwrap();
+    }
+}
+
+/// Sends the chunks of the clients' transfers that fit in the transfer window
+fn send_transfers(resources: &Resources) {
+    let network = resources.get::<NetworkSocket>().unwrap();
+    let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+    let mut connected_clients = resources.get_mut::<ConnectedClients>().unwrap();
+    for client in &mut connected_clients.clients {
+        for chunk in client.transfers.next_chunks() {
+            let payload = net_serilization.serialize_server_update(&ServerUpdate::Transfer(chunk));
+            let packet = Packet::reliable_ordered(client.addr, payload, Some(TRANSFER_STREAM));
+            network.sender.send(packet).unwrap();
+        }
+    }
+}
+
+/// Records the tick to the replay (if enabled) and clears the applied orders
+fn end_tick(resources: &Resources, replay_recorder: &mut Option<ReplayRecorder<BufWriter<File>>>) {
+    let mut orders = resources.get_mut::<Orders>().unwrap();
+    if let Some(recorder) = replay_recorder {
+        let game_time = resources.get::<GameTime>().unwrap();
+        let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+        if let Err(err) = recorder.record_tick(
+            game_time.tick(),
+            game_time.delta_seconds(),
+            &orders,
+            &net_serilization,
+        ) {
+            error!("Failed to record replay tick, stopping recording: {}", err);
+            *replay_recorder = None;
+        }
+    }
+    orders.commands.clear();
+}
+
+/// Whether the entity exists and is owned by the player, spectators don't own anything
+fn is_owned_by(world: &SubWorld, entity: Entity, player: Option<PlayerId>) -> bool {
+    player.is_some_and(|player| {
+        world
+            .entry_ref(entity)
+            .ok()
+            .and_then(|entry| entry.get_component::<Owner>().ok().copied())
+            == Some(Owner { player })
+    })
+}
+
+#[system]
+#[read_component(Owner)]
+#[allow(clippy::too_many_arguments)]
+fn client_input(
+    world: &SubWorld,
+    #[resource] network: &NetworkSocket,
+    #[resource] net_serilization: &NetworkSerialization,
+    #[resource] connected_clients: &mut ConnectedClients,
+    #[resource] orders: &mut Orders,
+    #[resource] bad_packets: &mut BadPacketLog,
+    #[resource] clock: &ServerClock,
+    #[resource] network_mode: &NetworkMode,
+    #[resource] turn_scheduler: &mut TurnScheduler,
+    #[resource] checksums: &ChecksumHistory,
+    #[resource] server_map: &ServerMap,
+    #[resource] map_bounds: &MapBounds,
+    #[resource] metrics: &ServerMetrics,
+) {
+    for event in network.receiver.try_iter() {
+        match event {
+            SocketEvent::Packet(packet) => {
+                let client = match connected_clients
+                    .clients
+                    .iter_mut()
+                    .find(|client| client.addr == packet.addr())
+                {
+                    Some(client) => client,
+                    None => {
+                        let update = net_serilization
+                            .deserialize_client_update(packet.payload())
+                            .map(|message| message.update);
+                        match update {
+                            Ok(ClientUpdate::StartGame { name, role }) => join_started_match(
+                                network,
+                                net_serilization,
+                                connected_clients,
+                                server_map,
+                                *network_mode,
+                                packet.addr(),
+                                name,
+                                role,
+                            ),
+                            _ => {
+                                warn!("Ignoring packet from unknown address: {}", packet.addr())
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let update = match net_serilization.deserialize_client_update(packet.payload()) {
+                    Ok(message) if !client.is_authentic(&message) => continue,
+                    result => result.map(|message| message.update),
+                };
+                match update {
+                    Ok(ClientUpdate::ClientCommands { .. } | ClientUpdate::Commands { .. })
+                        if client.player.is_none() =>
+                    {
+                        warn!("Rejected orders from spectator {}", client.name);
+                    }
+                    Ok(ClientUpdate::ClientCommands {
+                        sequence, commands, ..
+                    }) if *network_mode == NetworkMode::Snapshots => {
+                        let commands = match client.commands.receive(sequence, commands) {
+                            Some(commands) => commands,
+                            None => {
+                                warn!(
+                                    "{} sent command batch {} which is too far ahead",
+                                    client.name, sequence
+                                );
+                                continue;
+                            }
+                        };
+                        for command in commands {
+                            match command {
+                                Command::Move { entity, .. }
+                                    if !is_owned_by(world, entity, client.player) =>
+                                {
+                                    warn!(
+                                        "{} tried to move {:?} which it doesn't own",
+                                        client.name, entity
+                                    );
+                                }
+                                Command::Move { .. } => orders.commands.push(command),
+                            }
+                        }
+                        // Acked for every batch since the client resends until it gets an ack
+                        if let Some(sequence) = client.commands.acknowledged() {
+                            let payload = net_serilization
+                                .serialize_server_update(&ServerUpdate::CommandsAck { sequence });
+                            network
+                                .sender
+                                .send(Packet::unreliable(client.addr, payload))
+                                .unwrap();
+                        }
+                    }
+                    Ok(ClientUpdate::RequestMapChunks { start, count }) if !client.has_map => {
+                        server_map.send_chunks(
+                            network,
+                            net_serilization,
+                            client.addr,
+                            start,
+                            count,
+                        );
+                    }
+                    Ok(ClientUpdate::MapReady) if !client.has_map => {
+                        info!("Spectator {} has the map", client.name);
+                        client.has_map = true;
+                    }
+                    Ok(ClientUpdate::Chat { .. } | ClientUpdate::MapPing { .. })
+                        if !client.chat_limiter.try_send(Instant::now()) =>
+                    {
+                        warn!("{} is chatting too fast, dropped the message", client.name);
+                    }
+                    Ok(ClientUpdate::Chat { channel, text }) => {
+                        let text = match sanitize_message(&text) {
+                            Some(text) => text,
+                            None => continue,
+                        };
+                        info!("[{:?}] {}: {}", channel, client.name, text);
+                        let sender = client.team;
+                        let chat = ServerUpdate::Chat {
+                            sender: client.name.clone(),
+                            player: client.player,
+                            channel,
+                            text,
+                        };
+                        send_chat(
+                            network,
+                            net_serilization,
+                            connected_clients,
+                            channel,
+                            sender,
+                            &chat,
+                        );
+                    }
+                    Ok(ClientUpdate::MapPing { position, kind }) => {
+                        if !map_bounds.contains(position) || !position.y.is_finite() {
+                            warn!("{} pinged outside the map: {}", client.name, position);
+                            continue;
+                        }
+                        let sender = client.team;
+                        let ping = ServerUpdate::MapPing {
+                            sender: client.name.clone(),
+                            player: client.player,
+                            position,
+                            kind,
+                        };
+                        let channel = ChatChannel::Team;
+                        send_chat(
+                            network,
+                            net_serilization,
+                            connected_clients,
+                            channel,
+                            sender,
+                            &ping,
+                        );
+                    }
+                    Ok(ClientUpdate::TransferAck(ack)) => client.transfers.acknowledge(ack),
+                    Ok(ClientUpdate::AreaOfInterest(area_of_interest)) => {
+                        client.area_of_interest = Some(area_of_interest);
+                    }
+                    Ok(ClientUpdate::Ping { sequence }) => {
+                        // Answered right away so the time spent on the server is negligible
+                        let payload =
+                            net_serilization.serialize_server_update(&ServerUpdate::Pong {
+                                sequence,
+                                server_time: clock.now(),
+                            });
+                        network
+                            .sender
+                            .send(Packet::unreliable(client.addr, payload))
+                            .unwrap();
+                    }
+                    Ok(ClientUpdate::Commands { turn, commands, .. })
+                        if *network_mode != NetworkMode::Snapshots =>
+                    {
+                        let commands = commands
+                            .into_iter()
+                            .filter(|command| match *command {
+                                Command::Move { entity, .. } => {
+                                    is_owned_by(world, entity, client.player)
+                                }
+                            })
+                            .collect();
+                        if turn_scheduler.schedule(turn, commands).is_none() {
+                            warn!(
+                                "{} sent commands for turn {} which is too far ahead",
+                                client.name, turn
+                            );
+                        }
+                    }
+                    Ok(ClientUpdate::RequestResync)
+                        if client
+                            .resync_transfer
+                            .is_some_and(|transfer| client.transfers.is_queued(transfer)) =>
+                    {
+                        debug!(
+                            "{} requested a resync while the last one is being sent",
+                            client.name
+                        );
+                    }
+                    Ok(ClientUpdate::RequestResync) if *network_mode == NetworkMode::Snapshots => {
+                        info!("{} requested a resync", client.name);
+                        client.resync_requested = true;
+                    }
+                    Ok(ClientUpdate::TurnChecksum { turn, checksum }) => {
+                        if checksums.matches(turn, checksum) == Some(false) && !client.desynced {
+                            error!("{} is out of sync since turn {}", client.name, turn);
+                            client.desynced = true;
+                        }
+                    }
+                    Ok(
+                        ClientUpdate::ClientCommands { .. }
+                        | ClientUpdate::StartGame { .. }
+                        | ClientUpdate::RequestMapChunks { .. }
+                        | ClientUpdate::MapReady
+                        | ClientUpdate::Commands { .. }
+                        | ClientUpdate::RequestResync,
+                    ) => {
+                        warn!("unexpected packet");
+                    }
+                    Err(err) => {
+                        metrics.decode_errors.inc();
+                        bad_packets.report(packet.addr(), &err);
+                    }
+                }
+            }
+            SocketEvent::Connect(addr) => {
+                info!("Connected to: {}", addr);
+            }
+            SocketEvent::Timeout(addr) => {
+                error!("Timeout to: {}", addr);
+                connected_clients.remove(addr);
+            }
+            SocketEvent::Disconnect(addr) => {
+                warn!("Disconnected from: {}", addr);
+                connected_clients.remove(addr);
+            }
+        }
+    }
+}
+
+/// Passes a chat message or ping on to the clients the channel reaches
+fn send_chat(
+    network: &NetworkSocket,
+    net_serilization: &NetworkSerialization,
+    connected_clients: &ConnectedClients,
+    channel: ChatChannel,
+    sender: Option<TeamId>,
+    update: &ServerUpdate,
+) {
+    let payload = net_serilization.serialize_server_update(update);
+    connected_clients
+        .clients
+        .iter()
+        .filter(|client| client.joined && is_recipient(channel, sender, client.team))
+        .for_each(|client| {
+            let packet = Packet::reliable_ordered(client.addr, payload.clone(), Some(CHAT_STREAM));
+            network.sender.send(packet).unwrap();
+        });
+}
+
+/// Sends the commands of the next lockstep turn to the clients and applies them.
+/// Turns are sent even without commands since the clients wait for every turn.
+#[system]
+fn lockstep_turn(
+    #[resource] network: &NetworkSocket,
+    #[resource] net_serilization: &NetworkSerialization,
+    #[resource] connected_clients: &ConnectedClients,
+    #[resource] network_mode: &NetworkMode,
+    #[resource] turn_scheduler: &mut TurnScheduler,
+    #[resource] orders: &mut Orders,
+    #[resource] game_time: &GameTime,
+) {
+    if *network_mode == NetworkMode::Snapshots {
+        return;
+    }
+    let (turn, commands) = turn_scheduler.take_next_turn();
+    debug_assert_eq!(turn, game_time.tick());
+    let payload = net_serilization.serialize_server_update(&ServerUpdate::Turn {
+        turn,
+        commands: commands.clone(),
+    });
+    for client in connected_clients.clients.iter() {
+        let packet = Packet::reliable_ordered(client.addr, payload.clone(), Some(LOCKSTEP_STREAM));
+        network.sender.send(packet).unwrap();
+    }
+    orders.commands = commands;
+}
+
+/// Sends the state after the tick to the clients. Every STATE_CHECKSUM_INTERVAL
+/// snapshots include a checksum for the clients to verify their state with.
+fn send_state(world: &World, resources: &Resources, tick: u64, snapshot: u64) {
+    let network = resources.get::<NetworkSocket>().unwrap();
+    let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+    let mut connected_clients = resources.get_mut::<ConnectedClients>().unwrap();
+    let relevancy = resources.get::<Relevancy>().unwrap();
+    let metrics = resources.get::<ServerMetrics>().unwrap();
+    let game_time = *resources.get::<GameTime>().unwrap();
+    let bounds = resources.get::<MapBounds>().unwrap();
+    let joined = connected_clients
+        .clients
+        .par_iter_mut()
+        .filter(|client| client.joined);
+    joined.for_each(|client| {
+        let transforms = client
+            .with_viewer(world, |viewer| relevancy.relevant_transforms(world, viewer))
+            // Spectators see everything
+            .unwrap_or_else(|| Relevancy::all_transforms(world));
+        // Entities that became relevant for the first time since the client got the world
+        let spawned = transforms
+            .iter()
+            .filter(|(entity, _)| client.known_entities.insert(*entity))
+            .filter_map(|(entity, transform)| {
+                ReplicatedState::read(world, *entity, quantize(transform, &bounds))
+            })
+            .collect::<Vec<_>>();
+        // The Spawned might arrive after the state, the checksum would then count entities
+        // the client doesn't have yet
+        let any_spawned = !spawned.is_empty();
+        if any_spawned {
+            let payload = net_serilization
+                .serialize_server_update(&ServerUpdate::Spawned { entities: spawned });
+            network
+                .sender
+                .send(Packet::reliable_unordered(client.addr, payload))
+                .unwrap();
+        }
+        let replicated_states = || {
+            transforms
+                .iter()
+                .filter_map(|(entity, transform)| {
+                    // The clients only see the transforms after quantization
+                    ReplicatedState::read(world, *entity, quantize(transform, &bounds))
+                })
+                .collect::<Vec<_>>()
+        };
+        if client.resync_requested {
+            let payload = net_serilization.serialize_server_update(&ServerUpdate::Resync {
+                tick,
+                entities: replicated_states(),
+            });
+            // Resyncs of many entities are compressed and sent in chunks like the world
+            if payload.len() > TRANSFER_CHUNK_SIZE {
+                client.resync_transfer = Some(client.transfers.push(&payload));
+            } else {
+                network
+                    .sender
+                    .send(Packet::reliable_unordered(client.addr, payload))
+                    .unwrap();
+            }
+            client.resync_requested = false;
+        }
+        let checksum_due = snapshot.is_multiple_of(STATE_CHECKSUM_INTERVAL) && !any_spawned;
+        let checksum = checksum_due.then(|| {
+            desync::state_checksum(
+                replicated_states()
+                    .iter()
+                    .map(|state| state.hash(net_serilization.network_id(state.entity))),
+            )
+        });
+        let server_update = ServerUpdate::State {
+            tick,
+            game_time,
+            transforms: net_serilization.encode_transforms(&transforms, &bounds),
+            checksum,
+        };
+        let payload = net_serilization.serialize_server_update(&server_update);
+        metrics.snapshot_bytes.add(payload.len() as u64);
+        let packet = Packet::unreliable_sequenced(client.addr, payload, Some(SERVER_UPDATE_STREAM));
+        network.sender.send(packet).unwrap();
+    });
+}
@@ -0,0 +1,256 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::{
+    link_conditioner::{LinkConditioner, LinkConditionerArgs},
+    lockstep::NetworkMode,
+    resources::{DEFAULT_SERVER_ADDR, DEFAULT_SERVER_PORT},
+    timestep::{is_valid_tick_rate, MAX_TICK_RATE},
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "server", about = "Unnamed rts game server")]
+struct ServerArgs {
+    /// Toml config file, arguments given on the command line takes precedence over it
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to bind the server to, use 0.0.0.0 or :: to listen on all interfaces
+    #[structopt(long)]
+    bind: Option<IpAddr>,
+    /// Port to bind the server to
+    #[structopt(long)]
+    port: Option<u16>,
+    /// Number of players that needs to connect before the game starts
+    #[structopt(long)]
+    players: Option<u8>,
+    /// Max number of spectators watching each match, 0 disables spectating
+    #[structopt(long)]
+    max_spectators: Option<u8>,
+    /// Seconds a spectator has to get the map before it's kicked
+    #[structopt(long)]
+    spectator_map_timeout: Option<u64>,
+    /// Max number of matches hosted at the same time, players that connect while every
+    /// match has started get a new one until the limit is reached
+    #[structopt(long)]
+    max_sessions: Option<u32>,
+    /// Number of teams the players are split into in the order they connected, every
+    /// player is on its own team by default
+    #[structopt(long)]
+    teams: Option<u8>,
+    /// Path to the map that should be played
+    #[structopt(long, parse(from_os_str))]
+    map: Option<PathBuf>,
+    /// Number of simulation ticks per second, at most 1000
+    #[structopt(long)]
+    tick_rate: Option<u32>,
+    /// Number of state updates sent to the clients per second, at most the tick rate
+    #[structopt(long)]
+    snapshot_rate: Option<u32>,
+    /// Max number of ticks run back to back to catch up after falling behind
+    #[structopt(long)]
+    max_catch_up_ticks: Option<u32>,
+    /// Record a replay of the match to the given file
+    #[structopt(long, parse(from_os_str))]
+    replay: Option<PathBuf>,
+    /// Only send the commands to the clients and let them run the simulation in lockstep
+    #[structopt(long)]
+    lockstep: bool,
+    /// Seconds between the network traffic summaries logged for every client, 0 disables them
+    #[structopt(long)]
+    network_stats_interval: Option<u64>,
+    /// Write a telemetry log of every match to the given directory
+    #[structopt(long, parse(from_os_str))]
+    telemetry: Option<PathBuf>,
+    /// Serve Prometheus metrics over http at /metrics on the given port
+    #[structopt(long)]
+    metrics_port: Option<u16>,
+    /// Address to bind the metrics endpoint to, localhost unless the metrics should be
+    /// reachable from other machines
+    #[structopt(long)]
+    metrics_bind: Option<IpAddr>,
+    #[structopt(flatten)]
+    link_conditioner: LinkConditionerArgs,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub players: u8,
+    /// Spectators of each match, spectating is disabled when 0
+    pub max_spectators: u8,
+    /// Seconds a spectator has to get the map before it's kicked
+    pub spectator_map_timeout: u64,
+    pub max_sessions: u32,
+    /// Every player is on its own team when 0
+    pub teams: u8,
+    pub map: PathBuf,
+    pub tick_rate: u32,
+    pub snapshot_rate: u32,
+    pub max_catch_up_ticks: u32,
+    pub replay: Option<PathBuf>,
+    /// Only send the commands to the clients and let them run the simulation in lockstep
+    pub lockstep: bool,
+    /// Seconds between the network traffic summaries logged for every client, 0 disables them
+    pub network_stats_interval: u64,
+    /// Directory the telemetry log of every match is written to
+    pub telemetry: Option<PathBuf>,
+    /// The metrics endpoint is disabled unless a port is given
+    pub metrics_port: Option<u16>,
+    pub metrics_bind: IpAddr,
+    /// Simulates a bad network connection, used for testing
+    pub link_conditioner: Option<LinkConditioner>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: DEFAULT_SERVER_ADDR,
+            port: DEFAULT_SERVER_PORT,
+            players: 1,
+            max_spectators: 8,
+            spectator_map_timeout: 60,
+            max_sessions: 4,
+            teams: 0,
+            map: PathBuf::from("assets/Tilemap.map"),
+            tick_rate: 60,
+            snapshot_rate: 30,
+            max_catch_up_ticks: 5,
+            replay: None,
+            lockstep: false,
+            network_stats_interval: 30,
+            telemetry: None,
+            metrics_port: None,
+            metrics_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            link_conditioner: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config from the command line arguments and the optional config file
+    pub fn load() -> Result<Self> {
+        let args = ServerArgs::from_args();
+        let mut config = match &args.config {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(players) = args.players {
+            config.players = players;
+        }
+        if let Some(max_spectators) = args.max_spectators {
+            config.max_spectators = max_spectators;
+        }
+        if let Some(spectator_map_timeout) = args.spectator_map_timeout {
+            config.spectator_map_timeout = spectator_map_timeout;
+        }
+        if let Some(max_sessions) = args.max_sessions {
+            config.max_sessions = max_sessions;
+        }
+        if let Some(teams) = args.teams {
+            config.teams = teams;
+        }
+        if let Some(map) = args.map {
+            config.map = map;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(snapshot_rate) = args.snapshot_rate {
+            config.snapshot_rate = snapshot_rate;
+        }
+        if let Some(max_catch_up_ticks) = args.max_catch_up_ticks {
+            config.max_catch_up_ticks = max_catch_up_ticks;
+        }
+        if args.replay.is_some() {
+            config.replay = args.replay;
+        }
+        if args.lockstep {
+            config.lockstep = true;
+        }
+        if let Some(network_stats_interval) = args.network_stats_interval {
+            config.network_stats_interval = network_stats_interval;
+        }
+        if args.telemetry.is_some() {
+            config.telemetry = args.telemetry;
+        }
+        if args.metrics_port.is_some() {
+            config.metrics_port = args.metrics_port;
+        }
+        if let Some(metrics_bind) = args.metrics_bind {
+            config.metrics_bind = metrics_bind;
+        }
+        config.link_conditioner = args.link_conditioner.apply(config.link_conditioner)?;
+        anyhow::ensure!(config.players > 0, "The server needs at least one player");
+        anyhow::ensure!(
+            config.max_sessions > 0,
+            "The server needs to host at least one match"
+        );
+        anyhow::ensure!(
+            is_valid_tick_rate(config.tick_rate),
+            "The tick rate must be between 1 and {}",
+            MAX_TICK_RATE
+        );
+        anyhow::ensure!(
+            config.snapshot_rate > 0 && config.snapshot_rate <= config.tick_rate,
+            "The snapshot rate must be positive and at most the tick rate"
+        );
+        anyhow::ensure!(
+            config.max_catch_up_ticks > 0,
+            "The max catch up ticks must be positive"
+        );
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    pub fn network_mode(&self) -> NetworkMode {
+        if self.lockstep {
+            NetworkMode::Lockstep {
+                tick_rate: self.tick_rate,
+            }
+        } else {
+            NetworkMode::Snapshots
+        }
+    }
+
+    /// None if the network stats shouldn't be logged
+    pub fn network_stats_interval(&self) -> Option<Duration> {
+        (self.network_stats_interval > 0).then(|| Duration::from_secs(self.network_stats_interval))
+    }
+
+    /// None if the metrics shouldn't be served
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_port
+            .map(|port| SocketAddr::new(self.metrics_bind, port))
+    }
+
+    #[inline]
+    pub fn spectator_map_timeout(&self) -> Duration {
+        Duration::from_secs(self.spectator_map_timeout)
+    }
+
+    #[inline]
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, path::Path};
+
+use anyhow::Result;
+use laminar::Packet;
+
+use crate::{
+    resources::{
+        NetworkSerialization, NetworkSocket, ServerUpdate, MAP_CHUNK_SIZE, MAP_DOWNLOAD_WINDOW,
+        MAP_STREAM,
+    },
+    tilemap::{map_hash, LoadableMap, TileMap},
+};
+
+/// The map played on the server. The raw file content is kept around
+/// so it can be sent to clients that doesn't have the map.
+#[derive(Debug, Clone)]
+pub struct ServerMap {
+    pub name: String,
+    pub hash: u64,
+    bytes: Vec<u8>,
+}
+
+impl ServerMap {
+    pub fn load(path: &Path) -> Result<(ServerMap, TileMap)> {
+        let bytes = std::fs::read(path)?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        ServerMap::from_bytes(name, bytes)
+    }
+
+    /// The map is named after the tilemap if no name is given
+    pub fn from_bytes(name: Option<String>, bytes: Vec<u8>) -> Result<(ServerMap, TileMap)> {
+        let tilemap = LoadableMap::from_bytes(&bytes)?.map.into_owned();
+        let server_map = ServerMap {
+            name: name.unwrap_or_else(|| tilemap.name.clone()),
+            hash: map_hash(&bytes),
+            bytes,
+        };
+        Ok((server_map, tilemap))
+    }
+
+    pub fn map_info(&self) -> ServerUpdate {
+        ServerUpdate::MapInfo {
+            name: self.name.clone(),
+            hash: self.hash,
+            size: self.bytes.len() as u64,
+        }
+    }
+
+    /// Sends the requested map chunks over the reliable map stream
+    pub fn send_chunks(
+        &self,
+        socket: &NetworkSocket,
+        net_serilization: &NetworkSerialization,
+        addr: SocketAddr,
+        start: u32,
+        count: u32,
+    ) {
+        let count = count.min(MAP_DOWNLOAD_WINDOW) as usize;
+        self.bytes
+            .chunks(MAP_CHUNK_SIZE)
+            .enumerate()
+            .skip(start as usize)
+            .take(count)
+            .for_each(|(index, bytes)| {
+                let payload = net_serilization.serialize_server_update(&ServerUpdate::MapChunk {
+                    index: index as u32,
+                    bytes: bytes.to_vec(),
+                });
+                socket
+                    .sender
+                    .send(Packet::reliable_ordered(addr, payload, Some(MAP_STREAM)))
+                    .expect("failed to send map chunk");
+            });
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metrics::{Counter, Gauge, Histogram, MetricsWriter},
+    network_stats::{NetworkStats, SocketTotals},
+};
+
+use super::session::SessionId;
+
+/// Upper bounds in seconds of the tick duration buckets, a tick at 60Hz has 16ms
+const TICK_DURATION_BOUNDS: &[f64] = &[
+    0.000_5, 0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064, 0.128,
+];
+/// Time the per second rates are averaged over
+const RATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of a session when it last ran its ticks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SessionGauges {
+    pub entities: usize,
+    pub moving_entities: u32,
+    pub players: usize,
+    pub spectators: usize,
+}
+
+/// The totals the rates were last computed from
+#[derive(Debug)]
+struct RateSample {
+    sampled_at: Instant,
+    flow_fields: u64,
+    network: SocketTotals,
+}
+
+/// Metrics of the server shared by the sessions and the metrics endpoint. The counters are
+/// updated by the server's systems, the gauges once per update of a session.
+#[derive(Debug, Clone)]
+pub struct ServerMetrics {
+    pub tick_duration: Histogram,
+    pub flow_fields: Counter,
+    pub decode_errors: Counter,
+    pub snapshot_bytes: Counter,
+    sessions: Arc<Mutex<BTreeMap<SessionId, SessionGauges>>>,
+    flow_fields_per_second: Gauge,
+    sent_bytes_per_second: Gauge,
+    received_bytes_per_second: Gauge,
+    rate_sample: Arc<Mutex<RateSample>>,
+    network: NetworkStats,
+}
+
+impl ServerMetrics {
+    pub fn new(network: NetworkStats) -> Self {
+        ServerMetrics {
+            tick_duration: Histogram::new(TICK_DURATION_BOUNDS),
+            flow_fields: Counter::default(),
+            decode_errors: Counter::default(),
+            snapshot_bytes: Counter::default(),
+            sessions: Arc::default(),
+            flow_fields_per_second: Gauge::default(),
+            sent_bytes_per_second: Gauge::default(),
+            received_bytes_per_second: Gauge::default(),
+            rate_sample: Arc::new(Mutex::new(RateSample {
+                sampled_at: Instant::now(),
+                flow_fields: 0,
+                network: network.totals(),
+            })),
+            network,
+        }
+    }
+
+    pub fn update_session(&self, id: SessionId, gauges: SessionGauges) {
+        self.sessions.lock().unwrap().insert(id, gauges);
+    }
+
+    pub fn remove_session(&self, id: SessionId) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    /// Updates the per second rates once every RATE_INTERVAL
+    pub fn sample_rates(&self, now: Instant) {
+        let mut sample = self.rate_sample.lock().unwrap();
+        let elapsed = now.saturating_duration_since(sample.sampled_at);
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64();
+        let flow_fields = self.flow_fields.get();
+        let network = self.network.totals();
+        let per_second =
+            |current: u64, earlier: u64| current.saturating_sub(earlier) as f64 / seconds;
+        self.flow_fields_per_second
+            .set(per_second(flow_fields, sample.flow_fields));
+        self.sent_bytes_per_second.set(per_second(
+            network.datagrams_sent.bytes,
+            sample.network.datagrams_sent.bytes,
+        ));
+        self.received_bytes_per_second.set(per_second(
+            network.datagrams_received.bytes,
+            sample.network.datagrams_received.bytes,
+        ));
+        *sample = RateSample {
+            sampled_at: now,
+            flow_fields,
+            network,
+        };
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut writer = MetricsWriter::default();
+        writer.histogram(
+            "rts_tick_duration_seconds",
+            "Time spent running a simulation tick and sending its results",
+            &self.tick_duration,
+        );
+        let sessions = self.sessions.lock().unwrap().clone();
+        writer.gauge(
+            "rts_sessions",
+            "Matches hosted by the server",
+            sessions.len() as f64,
+        );
+        let session_labels: Vec<(SessionId, String)> =
+            sessions.keys().map(|id| (*id, id.to_string())).collect();
+        let mut session_gauge = |name: &str, help: &str, value: fn(&SessionGauges) -> f64| {
+            writer.header(name, help, "gauge");
+            for (id, label) in &session_labels {
+                writer.sample(name, &[("session", label)], value(&sessions[id]));
+            }
+        };
+        session_gauge("rts_entities", "Entities in the world", |gauges| {
+            gauges.entities as f64
+        });
+        session_gauge(
+            "rts_moving_entities",
+            "Entities following a flow field",
+            |gauges| gauges.moving_entities as f64,
+        );
+        writer.header(
+            "rts_connected_clients",
+            "Clients connected to the session",
+            "gauge",
+        );
+        for (id, label) in &session_labels {
+            let gauges = &sessions[id];
+            writer.sample(
+                "rts_connected_clients",
+                &[("session", label), ("role", "player")],
+                gauges.players as f64,
+            );
+            writer.sample(
+                "rts_connected_clients",
+                &[("session", label), ("role", "spectator")],
+                gauges.spectators as f64,
+            );
+        }
+        writer.counter(
+            "rts_flow_fields_generated_total",
+            "Flow fields generated for move orders",
+            &self.flow_fields,
+        );
+        writer.gauge(
+            "rts_flow_fields_generated_per_second",
+            "Flow fields generated per second over the last 5 seconds",
+            self.flow_fields_per_second.get(),
+        );
+        let network = self.network.totals();
+        writer.header(
+            "rts_network_bytes_total",
+            "Bytes of the datagrams on the wire, including headers and resends",
+            "counter",
+        );
+        writer.sample(
+            "rts_network_bytes_total",
+            &[("direction", "sent")],
+            network.datagrams_sent.bytes as f64,
+        );
+        writer.sample(
+            "rts_network_bytes_total",
+            &[("direction", "received")],
+            network.datagrams_received.bytes as f64,
+        );
+        writer.header(
+            "rts_network_bytes_per_second",
+            "Bytes of the datagrams on the wire per second over the last 5 seconds",
+            "gauge",
+        );
+        writer.sample(
+            "rts_network_bytes_per_second",
+            &[("direction", "sent")],
+            self.sent_bytes_per_second.get(),
+        );
+        writer.sample(
+            "rts_network_bytes_per_second",
+            &[("direction", "received")],
+            self.received_bytes_per_second.get(),
+        );
+        writer.counter(
+            "rts_snapshot_bytes_total",
+            "Bytes of the state snapshots sent to the clients",
+            &self.snapshot_bytes,
+        );
+        writer.counter(
+            "rts_decode_errors_total",
+            "Packets from clients that failed to decode",
+            &self.decode_errors,
+        );
+        writer.finish()
+    }
+}
@@ -0,0 +1,1172 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::File,
+    io::BufWriter,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use glam::{Quat, Vec3};
+use itertools::Itertools;
+use laminar::{Packet, SocketEvent};
+use legion::*;
+use log::{debug, error, info, warn};
+use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::{
+    chat::{sanitize_name, team_of},
+    clock_sync::ServerClock,
+    components::{Owner, PlayerId, Transform, Velocity},
+    game_time::GameTime,
+    lockstep::{state_checksum, ChecksumHistory, NetworkMode, TurnScheduler},
+    network_stats::{stream_name, PeerStats},
+    relevancy::Relevancy,
+    replay::{ReplayHeader, ReplayRecorder, REPLAY_VERSION},
+    resources::{
+        BadPacketLog, ClientMessage, ClientRole, ClientUpdate, Command, NetworkSerialization,
+        NetworkSocket, ServerNotice, ServerUpdate, MAP_STREAM,
+    },
+    simulation::{add_simulation_systems, Orders, SimulationStats},
+    telemetry::{
+        unix_time, EndReason, MatchTelemetry, TelemetryEvent, TelemetryPlayer, TELEMETRY_VERSION,
+    },
+    tilemap::TileMap,
+    timestep::{is_snapshot_tick, is_valid_tick_rate, FixedTimestep, MAX_TICK_RATE},
+    transform_encoding::MapBounds,
+};
+
+use super::{
+    admin_console::{AdminCommand, SessionCommand},
+    client_input_system, end_tick, lockstep_turn_system, notify_clients, replicated_world,
+    send_state, send_transfers, send_world_to_late_spectators,
+    server_config::ServerConfig,
+    server_map::ServerMap,
+    server_metrics::{ServerMetrics, SessionGauges},
+    setup_world, welcome, world_entities, ConnectedClient, ConnectedClients,
+};
+
+pub type SessionId = u32;
+
+/// Max time the server waits for packets when no tick is due, the admin console
+/// is checked in between
+const IDLE_WAIT: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    /// Waiting for the players to connect and get the map
+    Lobby,
+    InGame,
+    /// Everyone has left, the session is torn down
+    Ended,
+}
+
+/// Simulation state of a session controlled through the admin console
+#[derive(Debug)]
+struct SessionControl {
+    tick_rate: u32,
+    snapshot_rate: u32,
+}
+
+/// A match with its own world, clients, map and schedule. The sessions share the server's
+/// socket, a session gets the events of its clients through its NetworkSocket resource.
+pub struct Session {
+    id: SessionId,
+    phase: SessionPhase,
+    world: World,
+    resources: Resources,
+    schedule: Schedule,
+    /// Keeps handling the clients while the simulation is paused
+    paused_schedule: Schedule,
+    timestep: FixedTimestep,
+    control: SessionControl,
+    /// Events of the session's clients routed from the shared socket
+    inbox: Sender<SocketEvent>,
+    initial_state: Vec<u8>,
+    num_players: u8,
+    max_spectators: u8,
+    spectator_map_timeout: Duration,
+    teams: u8,
+    lockstep: bool,
+    replay_path: Option<PathBuf>,
+    replay_recorder: Option<ReplayRecorder<BufWriter<File>>>,
+    telemetry_dir: Option<PathBuf>,
+    telemetry: Option<MatchTelemetry<BufWriter<File>>>,
+    snapshots_sent: u64,
+}
+
+/// The replay of each session is recorded next to the configured path with the session id
+/// appended to the file name, `match.replay` becomes `match-0.replay` for session 0
+fn session_replay_path(path: &Path, id: SessionId) -> PathBuf {
+    let mut file_name = OsString::from(path.file_stem().unwrap_or_default());
+    file_name.push(format!("-{}", id));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+impl Session {
+    fn new(
+        id: SessionId,
+        config: &ServerConfig,
+        server_map: ServerMap,
+        tilemap: TileMap,
+        socket: &NetworkSocket,
+        metrics: &ServerMetrics,
+    ) -> Self {
+        let (inbox, receiver) = crossbeam_channel::unbounded();
+        let net_serilization = NetworkSerialization::authoritative();
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        resources.insert(MapBounds::of(&tilemap));
+        let initial_state = setup_world(
+            &mut world,
+            &mut resources,
+            &net_serilization,
+            tilemap,
+            config.players,
+        );
+        resources.insert(server_map);
+        resources.insert(GameTime::default());
+        resources.insert(Orders::default());
+        resources.insert(SimulationStats::default());
+        resources.insert(metrics.clone());
+        resources.insert(net_serilization);
+        resources.insert(socket.with_receiver(receiver));
+        resources.insert(ConnectedClients::default());
+        resources.insert(BadPacketLog::default());
+        resources.insert(Relevancy::default());
+        resources.insert(ServerClock::default());
+        resources.insert(config.network_mode());
+        // The first tick is 1, see FixedTimestep::tick
+        resources.insert(TurnScheduler::new(1));
+        resources.insert(ChecksumHistory::default());
+
+        let schedule = add_simulation_systems(
+            Schedule::builder()
+                .add_system(client_input_system())
+                .add_system(lockstep_turn_system()),
+        )
+        .build();
+        let paused_schedule = Schedule::builder()
+            .add_system(client_input_system())
+            .build();
+        Session {
+            id,
+            phase: SessionPhase::Lobby,
+            world,
+            resources,
+            schedule,
+            paused_schedule,
+            timestep: FixedTimestep::new(
+                config.tick_rate,
+                config.max_catch_up_ticks,
+                Instant::now(),
+            ),
+            control: SessionControl {
+                tick_rate: config.tick_rate,
+                snapshot_rate: config.snapshot_rate,
+            },
+            inbox,
+            initial_state,
+            num_players: config.players,
+            max_spectators: config.max_spectators,
+            spectator_map_timeout: config.spectator_map_timeout(),
+            teams: config.teams,
+            lockstep: config.lockstep,
+            replay_path: config
+                .replay
+                .as_deref()
+                .map(|path| session_replay_path(path, id)),
+            replay_recorder: None,
+            telemetry_dir: config.telemetry.clone(),
+            telemetry: None,
+            snapshots_sent: 0,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    #[inline]
+    pub fn phase(&self) -> SessionPhase {
+        self.phase
+    }
+
+    /// The authoritative world of the match
+    #[inline]
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// The last tick that has run, 0 before the match starts
+    pub fn tick(&self) -> u64 {
+        self.resources.get::<GameTime>().unwrap().tick()
+    }
+
+    /// The match clock, advanced by every tick
+    pub fn game_time(&self) -> GameTime {
+        *self.resources.get::<GameTime>().unwrap()
+    }
+
+    /// Whether the admin has paused the simulation
+    fn is_paused(&self) -> bool {
+        self.resources.get::<GameTime>().unwrap().is_paused()
+    }
+
+    /// Whether a player connecting now can join the session
+    fn accepts_players(&self) -> bool {
+        self.phase == SessionPhase::Lobby
+            && self
+                .resources
+                .get::<ConnectedClients>()
+                .unwrap()
+                .players()
+                .count()
+                < self.num_players as usize
+    }
+
+    /// Whether a spectator connecting now can join the session, lockstep
+    /// matches can only be watched from the start
+    fn accepts_spectators(&self) -> bool {
+        let joinable = match self.phase {
+            SessionPhase::Lobby => true,
+            SessionPhase::InGame => !self.lockstep,
+            SessionPhase::Ended => false,
+        };
+        joinable
+            && self
+                .resources
+                .get::<ConnectedClients>()
+                .unwrap()
+                .spectators()
+                .count()
+                < self.max_spectators as usize
+    }
+
+    /// Kicks the spectators that haven't got the map in time, returns their addresses
+    fn kick_slow_spectators(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let timeout = self.spectator_map_timeout;
+        let slow = {
+            let mut connected_clients = self.resources.get_mut::<ConnectedClients>().unwrap();
+            let (slow, rest) = connected_clients.clients.drain(..).partition(|client| {
+                client.player.is_none()
+                    && !client.has_map
+                    && now.saturating_duration_since(client.connected_at) >= timeout
+            });
+            connected_clients.clients = rest;
+            slow
+        };
+        slow.into_iter()
+            .map(|client: ConnectedClient| {
+                self.send_kicked(&client, "Took too long to get the map");
+                client.addr
+            })
+            .collect()
+    }
+
+    /// Passes on an event of one of the session's clients. The lobby handles it right away
+    /// so it's known whether the lobby is full when the next client is routed.
+    fn deliver(&mut self, event: SocketEvent) {
+        // The receiver is a resource of the session
+        self.inbox.send(event).unwrap();
+        if self.phase == SessionPhase::Lobby {
+            self.run_lobby(Instant::now());
+        }
+    }
+
+    /// Runs the ticks that are due. Returns the time until the next tick,
+    /// None when the session only waits for packets.
+    fn update(&mut self, now: Instant) -> Option<Duration> {
+        if self.phase != SessionPhase::InGame {
+            return None;
+        }
+        send_world_to_late_spectators(&self.world, &self.resources);
+        if self.is_paused() {
+            self.paused_schedule
+                .execute(&mut self.world, &mut self.resources);
+        } else {
+            self.run_ticks(now);
+        }
+        send_transfers(&self.resources);
+        let players_left = {
+            let connected_clients = self.resources.get::<ConnectedClients>().unwrap();
+            let players = connected_clients.players().count();
+            self.resources
+                .get::<ServerMetrics>()
+                .unwrap()
+                .update_session(
+                    self.id,
+                    SessionGauges {
+                        entities: self.world.len(),
+                        moving_entities: self
+                            .resources
+                            .get::<SimulationStats>()
+                            .unwrap()
+                            .moving_entities,
+                        players,
+                        spectators: connected_clients.clients.len() - players,
+                    },
+                );
+            if let Some(telemetry) = self.telemetry.as_mut() {
+                let tick = self.resources.get::<GameTime>().unwrap().tick();
+                let connected = connected_clients
+                    .players()
+                    .filter_map(|client| client.player);
+                if let Err(err) = telemetry.update_connected(tick, now, connected) {
+                    error!("Failed to write telemetry, stopping it: {}", err);
+                    self.telemetry = None;
+                }
+            }
+            players
+        };
+        if players_left == 0 {
+            info!("Session {}: all players have left", self.id);
+            notify_clients(&self.resources, ServerNotice::MatchEnded);
+            self.finish_telemetry(now, EndReason::AllPlayersLeft);
+            self.close();
+            return None;
+        }
+        Some(if self.is_paused() {
+            self.timestep.tick_duration()
+        } else {
+            self.timestep.time_until_next_tick(now)
+        })
+    }
+
+    fn run_ticks(&mut self, now: Instant) {
+        let skipped = self.timestep.accumulate(now);
+        if skipped > 0 {
+            warn!(
+                "Session {} is running behind, skipped {} ticks ({} in total)",
+                self.id,
+                skipped,
+                self.timestep.skipped_ticks()
+            );
+        }
+        let delta_time = self.timestep.tick_duration().as_secs_f32();
+        let metrics = self.resources.get::<ServerMetrics>().unwrap().clone();
+        while let Some(tick) = self.timestep.next_tick() {
+            let tick_started = Instant::now();
+            self.resources
+                .get_mut::<GameTime>()
+                .unwrap()
+                .advance(delta_time);
+            self.schedule.execute(&mut self.world, &mut self.resources);
+            metrics.flow_fields.add(std::mem::take(
+                &mut self
+                    .resources
+                    .get_mut::<SimulationStats>()
+                    .unwrap()
+                    .flow_fields_generated,
+            ));
+            self.record_telemetry(tick, now);
+            end_tick(&self.resources, &mut self.replay_recorder);
+            if self.lockstep {
+                // The clients only get the commands, the state is compared using checksums
+                self.resources
+                    .get_mut::<ChecksumHistory>()
+                    .unwrap()
+                    .record(tick, state_checksum(&self.world));
+            } else if is_snapshot_tick(tick, self.control.tick_rate, self.control.snapshot_rate) {
+                send_state(&self.world, &self.resources, tick, self.snapshots_sent);
+                self.snapshots_sent += 1;
+            }
+            metrics.tick_duration.observe(tick_started.elapsed());
+        }
+    }
+
+    /// Counts the orders applied this tick and samples the units of every player
+    fn record_telemetry(&mut self, tick: u64, now: Instant) {
+        let telemetry = match self.telemetry.as_mut() {
+            Some(telemetry) => telemetry,
+            None => return,
+        };
+        let world = &self.world;
+        let orders = self.resources.get::<Orders>().unwrap();
+        for command in orders.commands.iter() {
+            let entity = match command {
+                Command::Move { entity, .. } => *entity,
+            };
+            let owner = world
+                .entry_ref(entity)
+                .ok()
+                .and_then(|entry| entry.get_component::<Owner>().ok().copied());
+            if let Some(owner) = owner {
+                telemetry.record_order(owner.player);
+            }
+        }
+        let result = telemetry.record_tick(tick, now, || {
+            let mut units = BTreeMap::new();
+            for owner in <&Owner>::query().iter(world) {
+                *units.entry(owner.player).or_default() += 1;
+            }
+            units
+        });
+        if let Err(err) = result {
+            error!("Failed to write telemetry, stopping it: {}", err);
+            self.telemetry = None;
+        }
+    }
+
+    /// Writes the result of the match to the telemetry log
+    fn finish_telemetry(&mut self, now: Instant, reason: EndReason) {
+        if let Some(mut telemetry) = self.telemetry.take() {
+            let tick = self.resources.get::<GameTime>().unwrap().tick();
+            if let Err(err) = telemetry.finish(tick, now, reason) {
+                error!("Failed to write telemetry: {}", err);
+            }
+        }
+    }
+
+    /// Handles the clients joining before the match starts and starts it once the
+    /// players are connected and everyone has the map
+    fn run_lobby(&mut self, now: Instant) {
+        let ready = {
+            let network = self.resources.get::<NetworkSocket>().unwrap();
+            let net_serilization = self.resources.get::<NetworkSerialization>().unwrap();
+            let server_map = self.resources.get::<ServerMap>().unwrap();
+            let mut connected_clients = self.resources.get_mut::<ConnectedClients>().unwrap();
+            let mut bad_packets = self.resources.get_mut::<BadPacketLog>().unwrap();
+            let metrics = self.resources.get::<ServerMetrics>().unwrap();
+            let is_ready = |connected_clients: &ConnectedClients| {
+                // Spectators still getting the map join once they have it
+                self.num_players as usize <= connected_clients.players().count()
+                    && connected_clients.players().all(|client| client.has_map)
+            };
+            for event in network.receiver.try_iter() {
+                let packet = match event {
+                    SocketEvent::Packet(packet) => packet,
+                    SocketEvent::Timeout(addr) | SocketEvent::Disconnect(addr) => {
+                        if let Some(client) = connected_clients.remove(addr) {
+                            info!("Session {}: {} left the lobby", self.id, client.name);
+                        }
+                        // Keeps the player ids in the order the remaining players connected
+                        let players = connected_clients
+                            .clients
+                            .iter_mut()
+                            .filter(|client| client.player.is_some());
+                        for (id, client) in players.enumerate() {
+                            client.player = Some(id as PlayerId);
+                        }
+                        continue;
+                    }
+                    SocketEvent::Connect(_) => continue,
+                };
+                let message = match net_serilization.deserialize_client_update(packet.payload()) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        metrics.decode_errors.inc();
+                        bad_packets.report(packet.addr(), &err);
+                        continue;
+                    }
+                };
+                let client = connected_clients
+                    .clients
+                    .iter()
+                    .find(|client| client.addr == packet.addr());
+                match message.update {
+                    ClientUpdate::StartGame { name, role } => {
+                        let addr = packet.addr();
+                        if !connected_clients
+                            .clients
+                            .iter()
+                            .any(|client| client.addr == addr)
+                        {
+                            // Clients without a name are refused before they are routed here
+                            let name = match sanitize_name(&name) {
+                                Some(name) => name,
+                                None => continue,
+                            };
+                            info!(
+                                "Session {}: connected {:?} {} ({})",
+                                self.id, role, name, addr
+                            );
+                            let player = match role {
+                                ClientRole::Player => {
+                                    Some(connected_clients.players().count() as PlayerId)
+                                }
+                                ClientRole::Spectator => None,
+                            };
+                            connected_clients
+                                .clients
+                                .push(ConnectedClient::new(addr, name, player));
+                        }
+                        // A repeated StartGame gets the token the client already has
+                        let client = connected_clients
+                            .clients
+                            .iter()
+                            .find(|client| client.addr == addr)
+                            .unwrap();
+                        welcome(&network, &net_serilization, client);
+                        let payload =
+                            net_serilization.serialize_server_update(&server_map.map_info());
+                        network
+                            .sender
+                            .send(Packet::reliable_ordered(addr, payload, Some(MAP_STREAM)))
+                            .expect("failed to send map info");
+                    }
+                    // Only the StartGame is sent before the client has its token
+                    _ if !client.is_some_and(|client| client.is_authentic(&message)) => {}
+                    ClientUpdate::RequestMapChunks { start, count } => {
+                        server_map.send_chunks(
+                            &network,
+                            &net_serilization,
+                            packet.addr(),
+                            start,
+                            count,
+                        );
+                    }
+                    ClientUpdate::MapReady => {
+                        if let Some(client) = connected_clients
+                            .clients
+                            .iter_mut()
+                            .find(|client| client.addr == packet.addr())
+                        {
+                            info!("Session {}: {} has the map", self.id, client.name);
+                            client.has_map = true;
+                        }
+                        // The rest of the events are handled by the running match
+                        if is_ready(&connected_clients) {
+                            break;
+                        }
+                    }
+                    _ => {
+                        warn!("Unexpected packet, match hasn't started");
+                    }
+                }
+            }
+            // Spectators alone don't keep the lobby open
+            if connected_clients.players().count() == 0 {
+                None
+            } else {
+                Some(is_ready(&connected_clients))
+            }
+        };
+        match ready {
+            Some(true) => self.start(now),
+            Some(false) => {}
+            None => {
+                info!("Session {}: every player has left the lobby", self.id);
+                let connected_clients = self.resources.get::<ConnectedClients>().unwrap();
+                for spectator in connected_clients.spectators() {
+                    self.send_kicked(spectator, "Every player has left the lobby");
+                }
+                drop(connected_clients);
+                self.close();
+            }
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        let network_mode = *self.resources.get::<NetworkMode>().unwrap();
+        {
+            let network = self.resources.get::<NetworkSocket>().unwrap();
+            let net_serilization = self.resources.get::<NetworkSerialization>().unwrap();
+            let mut connected_clients = self.resources.get_mut::<ConnectedClients>().unwrap();
+            info!(
+                "Session {}: all players connected, starting game with: {}",
+                self.id,
+                connected_clients
+                    .players()
+                    .map(|client| client.name.as_str())
+                    .join(", ")
+            );
+            let world_update = net_serilization.serialize_server_update(&ServerUpdate::World {
+                world: self.initial_state.clone(),
+            });
+            if self.lockstep {
+                // Lockstep clients can't join late, so spectators without the map are turned away
+                let (joining, late) = connected_clients
+                    .clients
+                    .drain(..)
+                    .partition(|client| client.has_map);
+                connected_clients.clients = joining;
+                for client in late {
+                    self.send_kicked(&client, "The match started before the map was downloaded");
+                }
+            }
+            let teams = self.teams;
+            let entities = world_entities(&self.world);
+            let joining = connected_clients
+                .clients
+                .par_iter_mut()
+                .filter(|client| client.has_map);
+            joining.for_each(|client| {
+                client.joined = true;
+                client.team = client.player.map(|player| team_of(player, teams));
+                let payload =
+                    net_serilization.serialize_server_update(&ServerUpdate::InitialState {
+                        player: client.player,
+                        network_mode,
+                    });
+                let packet = Packet::reliable_ordered(client.addr, payload, None);
+                network
+                    .sender
+                    .send(packet)
+                    .expect("failed to send start game packet");
+                // Compressed and sent in chunks by send_transfers
+                client.transfers.push(&world_update);
+                client.known_entities = entities.clone();
+            });
+        }
+        let map_hash = self.resources.get::<ServerMap>().unwrap().hash;
+        self.telemetry = self.telemetry_dir.as_ref().and_then(|dir| {
+            let started_at = unix_time();
+            let path = dir.join(format!("{}-session-{}.jsonl", started_at, self.id));
+            let players = self
+                .resources
+                .get::<ConnectedClients>()
+                .unwrap()
+                .players()
+                .filter_map(|client| {
+                    Some(TelemetryPlayer {
+                        player: client.player?,
+                        name: client.name.clone(),
+                        team: client.team?,
+                    })
+                })
+                .collect();
+            let started = TelemetryEvent::MatchStarted {
+                version: TELEMETRY_VERSION,
+                session: self.id,
+                map: self.resources.get::<ServerMap>().unwrap().name.clone(),
+                map_hash: format!("{:016x}", map_hash),
+                started_at,
+                tick_rate: self.control.tick_rate,
+                lockstep: self.lockstep,
+                players,
+            };
+            let telemetry = std::fs::create_dir_all(dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| MatchTelemetry::create(&path, started, now));
+            match telemetry {
+                Ok(telemetry) => {
+                    info!("Writing telemetry to: {}", path.display());
+                    Some(telemetry)
+                }
+                Err(err) => {
+                    error!("Failed to create telemetry {}: {:#}", path.display(), err);
+                    None
+                }
+            }
+        });
+        let initial_world = self.initial_state.clone();
+        self.replay_recorder = self.replay_path.as_ref().and_then(|path| {
+            let header = ReplayHeader {
+                version: REPLAY_VERSION,
+                map_hash,
+                initial_world,
+            };
+            match ReplayRecorder::create(path, &header) {
+                Ok(recorder) => {
+                    info!("Recording replay to: {}", path.display());
+                    Some(recorder)
+                }
+                Err(err) => {
+                    error!("Failed to create replay {}: {}", path.display(), err);
+                    None
+                }
+            }
+        });
+        info!(
+            "Session {}: game started! Running {} ticks and sending {} snapshots per second",
+            self.id, self.control.tick_rate, self.control.snapshot_rate
+        );
+        self.timestep.resume(now);
+        self.phase = SessionPhase::InGame;
+    }
+
+    /// Tells the clients the server is shutting down and closes the session
+    fn shut_down(&mut self) {
+        notify_clients(&self.resources, ServerNotice::ShuttingDown);
+        self.finish_telemetry(Instant::now(), EndReason::ServerShutdown);
+        self.close();
+    }
+
+    fn close(&mut self) {
+        if let Some(recorder) = self.replay_recorder.as_mut() {
+            if let Err(err) = recorder.flush() {
+                error!("Failed to flush the replay: {}", err);
+            }
+        }
+        self.phase = SessionPhase::Ended;
+    }
+
+    fn list(&self) {
+        let connected_clients = self.resources.get::<ConnectedClients>().unwrap();
+        info!(
+            "Session {} ({:?} at tick {}{}), {} clients:",
+            self.id,
+            self.phase,
+            self.timestep.tick(),
+            if self.is_paused() { ", paused" } else { "" },
+            connected_clients.clients.len()
+        );
+        for client in &connected_clients.clients {
+            info!(
+                "  {}: {} ({}){}",
+                client
+                    .player
+                    .map_or_else(|| "spectator".to_string(), |player| player.to_string()),
+                client.name,
+                client.addr,
+                if client.desynced { ", desynced" } else { "" }
+            );
+        }
+    }
+
+    /// Kicks the client with the given name or player id, returns its address
+    fn kick(&mut self, player: &str) -> Option<SocketAddr> {
+        let mut connected_clients = self.resources.get_mut::<ConnectedClients>().unwrap();
+        let index = connected_clients.clients.iter().position(|client| {
+            client.name == player || client.player.is_some_and(|id| player.parse() == Ok(id))
+        })?;
+        let client = connected_clients.clients.remove(index);
+        self.send_kicked(&client, "Kicked by the server admin");
+        Some(client.addr)
+    }
+
+    /// Tells a client that has been removed from the session why
+    fn send_kicked(&self, client: &ConnectedClient, reason: &str) {
+        let net_serilization = self.resources.get::<NetworkSerialization>().unwrap();
+        let payload =
+            net_serilization.serialize_server_update(&ServerUpdate::Notice(ServerNotice::Kicked {
+                reason: reason.to_string(),
+            }));
+        self.resources
+            .get::<NetworkSocket>()
+            .unwrap()
+            .sender
+            .send(Packet::reliable_unordered(client.addr, payload))
+            .unwrap();
+        info!(
+            "Session {}: kicked {} ({}): {}",
+            self.id, client.name, client.addr, reason
+        );
+    }
+
+    fn run_admin_command(&mut self, command: SessionCommand) {
+        if self.phase != SessionPhase::InGame {
+            warn!("Session {} isn't running a match", self.id);
+            return;
+        }
+        let (world, resources, control, timestep) = (
+            &mut self.world,
+            &self.resources,
+            &mut self.control,
+            &mut self.timestep,
+        );
+        let paused = resources.get::<GameTime>().unwrap().is_paused();
+        match command {
+            SessionCommand::Pause if !paused => {
+                resources.get_mut::<GameTime>().unwrap().set_paused(true);
+                notify_clients(resources, ServerNotice::Paused);
+                info!(
+                    "Session {}: paused the simulation at tick {}",
+                    self.id,
+                    timestep.tick()
+                );
+            }
+            SessionCommand::Resume if paused => {
+                resources.get_mut::<GameTime>().unwrap().set_paused(false);
+                timestep.resume(Instant::now());
+                notify_clients(resources, ServerNotice::Resumed);
+                info!("Session {}: resumed the simulation", self.id);
+            }
+            SessionCommand::Pause | SessionCommand::Resume => {
+                info!(
+                    "The simulation of session {} is already {}",
+                    self.id,
+                    if paused { "paused" } else { "running" }
+                );
+            }
+            SessionCommand::TickRate(_) if self.lockstep => {
+                warn!("The tick rate can't change in lockstep mode, the clients simulate with it");
+            }
+            SessionCommand::TickRate(tick_rate) if !is_valid_tick_rate(tick_rate) => {
+                warn!("The tick rate must be between 1 and {}", MAX_TICK_RATE)
+            }
+            SessionCommand::TickRate(tick_rate) => {
+                timestep.set_tick_rate(tick_rate);
+                control.tick_rate = tick_rate;
+                control.snapshot_rate = control.snapshot_rate.min(tick_rate);
+                info!(
+                    "Session {}: running {} ticks and sending {} snapshots per second",
+                    self.id, control.tick_rate, control.snapshot_rate
+                );
+            }
+            SessionCommand::Speed(_) if self.lockstep => {
+                warn!("The game speed can't change in lockstep mode, the clients simulate with it");
+            }
+            SessionCommand::Speed(scale) if !(scale.is_finite() && scale > 0.0) => {
+                warn!("The game speed must be positive")
+            }
+            SessionCommand::Speed(scale) => {
+                resources.get_mut::<GameTime>().unwrap().set_scale(scale);
+                info!("Session {}: running the game at {}x speed", self.id, scale);
+            }
+            SessionCommand::Spawn { .. } if self.lockstep => {
+                warn!("Entities can't be spawned in lockstep mode");
+            }
+            SessionCommand::Spawn { position, .. }
+                if !resources
+                    .get::<MapBounds>()
+                    .unwrap()
+                    .contains(position.into()) =>
+            {
+                warn!("Can't spawn at {}, it's outside of the map", position);
+            }
+            SessionCommand::Spawn {
+                entity_type,
+                position,
+                owner,
+            } => {
+                let entity = world.push((
+                    entity_type,
+                    Transform::new(position, Vec3::ONE, Quat::IDENTITY),
+                    Velocity {
+                        velocity: Vec3::ZERO,
+                    },
+                ));
+                if let Some(player) = owner {
+                    world.entry(entity).unwrap().add_component(Owner { player });
+                }
+                // Sent to the clients by send_state once it's relevant to them
+                info!(
+                    "Session {}: spawned {:?} {:?} at {}",
+                    self.id, entity_type, entity, position
+                );
+                if self.replay_recorder.is_some() {
+                    warn!(
+                        "Spawned entities aren't recorded, the replay will differ from the match"
+                    );
+                }
+            }
+            SessionCommand::Save(path) => {
+                let net_serilization = resources.get::<NetworkSerialization>().unwrap();
+                let world_bytes = net_serilization.serialize_world(&replicated_world(world), any());
+                match std::fs::write(&path, world_bytes) {
+                    Ok(()) => info!(
+                        "Session {}: saved the world at tick {} to {}",
+                        self.id,
+                        timestep.tick(),
+                        path.display()
+                    ),
+                    Err(err) => error!("Failed to save the world to {}: {}", path.display(), err),
+                }
+            }
+        }
+    }
+}
+
+/// Hosts the matches of the server. Routes the events of the shared socket to the session of
+/// the client by its address, opens lobbies as players connect and closes the sessions once
+/// their match has ended.
+pub struct SessionManager {
+    socket: NetworkSocket,
+    net_serilization: NetworkSerialization,
+    bad_packets: BadPacketLog,
+    config: ServerConfig,
+    server_map: ServerMap,
+    tilemap: TileMap,
+    sessions: BTreeMap<SessionId, Session>,
+    routes: HashMap<SocketAddr, SessionId>,
+    next_id: SessionId,
+    /// The session the admin commands apply to, the only one if there's just one
+    selected: Option<SessionId>,
+    /// The stats of every client when they were last logged
+    logged_stats: HashMap<SocketAddr, PeerStats>,
+    stats_logged_at: Instant,
+    metrics: ServerMetrics,
+}
+
+impl SessionManager {
+    pub fn new(
+        socket: NetworkSocket,
+        config: ServerConfig,
+        server_map: ServerMap,
+        tilemap: TileMap,
+        metrics: ServerMetrics,
+    ) -> Self {
+        SessionManager {
+            socket,
+            net_serilization: NetworkSerialization::authoritative(),
+            bad_packets: BadPacketLog::default(),
+            config,
+            server_map,
+            tilemap,
+            sessions: BTreeMap::new(),
+            routes: HashMap::new(),
+            next_id: 0,
+            selected: None,
+            logged_stats: HashMap::new(),
+            stats_logged_at: Instant::now(),
+            metrics,
+        }
+    }
+
+    /// Runs the sessions, closes the ended ones and routes the socket events that arrive
+    /// until the next tick of a session is due
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        let wait = self
+            .sessions
+            .values_mut()
+            .filter_map(|session| session.update(now))
+            .fold(IDLE_WAIT, Duration::min);
+        for session in self.sessions.values_mut() {
+            for addr in session.kick_slow_spectators(now) {
+                self.routes.remove(&addr);
+            }
+        }
+        self.close_ended_sessions();
+        self.log_network_stats(now);
+        self.metrics.sample_rates(now);
+        match self.socket.receiver.recv_timeout(wait) {
+            Ok(event) => self.route(event),
+            Err(RecvTimeoutError::Timeout) => return,
+            Err(RecvTimeoutError::Disconnected) => panic!("The server socket was closed"),
+        }
+        while let Ok(event) = self.socket.receiver.try_recv() {
+            self.route(event);
+        }
+    }
+
+    fn close_ended_sessions(&mut self) {
+        let before = self.sessions.len();
+        let metrics = &self.metrics;
+        self.sessions.retain(|id, session| {
+            let ended = session.phase == SessionPhase::Ended;
+            if ended {
+                info!("Session {} closed", id);
+                metrics.remove_session(*id);
+            }
+            !ended
+        });
+        if self.sessions.len() != before {
+            let sessions = &self.sessions;
+            self.routes.retain(|_, id| sessions.contains_key(id));
+        }
+    }
+
+    /// Logs the traffic of every client since the last summary, the traffic per stream
+    /// is logged at debug level
+    fn log_network_stats(&mut self, now: Instant) {
+        let interval = match self.config.network_stats_interval() {
+            Some(interval) if now - self.stats_logged_at >= interval => interval,
+            _ => return,
+        };
+        self.stats_logged_at = now;
+        let mut logged_stats = HashMap::with_capacity(self.routes.len());
+        for (addr, id) in self.routes.iter().sorted() {
+            let stats = match self.socket.stats.peer(*addr) {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let since = match self.logged_stats.get(addr) {
+                Some(logged) => stats.since(logged),
+                None => stats.clone(),
+            };
+            let (sent, received) = (since.total_sent(), since.total_received());
+            info!(
+                "Session {}, client {}: sent {} messages, {:.1} kB/s, {:.0} B avg; \
+                 received {} messages, {:.1} kB/s, {:.0} B avg; {} resends, {:.1}% loss",
+                id,
+                addr,
+                sent.packets,
+                sent.bytes as f32 / 1000.0 / interval.as_secs_f32(),
+                sent.average_size(),
+                received.packets,
+                received.bytes as f32 / 1000.0 / interval.as_secs_f32(),
+                received.average_size(),
+                since.resent.packets,
+                since.loss_estimate() * 100.0
+            );
+            let streams = since.sent.keys().chain(since.received.keys()).unique();
+            for stream in streams.sorted() {
+                let sent = since.sent.get(stream).copied().unwrap_or_default();
+                let received = since.received.get(stream).copied().unwrap_or_default();
+                debug!(
+                    "  {}: sent {} messages, {} B; received {} messages, {} B",
+                    stream_name(*stream),
+                    sent.packets,
+                    sent.bytes,
+                    received.packets,
+                    received.bytes
+                );
+            }
+            logged_stats.insert(*addr, stats);
+        }
+        self.logged_stats = logged_stats;
+    }
+
+    fn route(&mut self, event: SocketEvent) {
+        let addr = match &event {
+            SocketEvent::Packet(packet) => packet.addr(),
+            SocketEvent::Connect(addr)
+            | SocketEvent::Timeout(addr)
+            | SocketEvent::Disconnect(addr) => *addr,
+        };
+        if let Some(id) = self.routes.get(&addr).copied() {
+            if matches!(event, SocketEvent::Timeout(_) | SocketEvent::Disconnect(_)) {
+                self.routes.remove(&addr);
+            }
+            if let Some(session) = self.sessions.get_mut(&id) {
+                session.deliver(event);
+            }
+            return;
+        }
+        let packet = match event {
+            SocketEvent::Packet(packet) => packet,
+            event => {
+                debug!("{:?} of a client without a session", event);
+                return;
+            }
+        };
+        match self
+            .net_serilization
+            .deserialize_client_update(packet.payload())
+        {
+            Ok(ClientMessage {
+                update: ClientUpdate::StartGame { name, role },
+                ..
+            }) => match sanitize_name(&name)
+                .ok_or("The name can't be empty")
+                .and_then(|_| self.session_for(role))
+            {
+                Ok(id) => {
+                    self.routes.insert(addr, id);
+                    self.sessions
+                        .get_mut(&id)
+                        .unwrap()
+                        .deliver(SocketEvent::Packet(packet));
+                }
+                Err(reason) => {
+                    info!("Refused {} as {:?}: {}", addr, role, reason);
+                    let payload =
+                        self.net_serilization
+                            .serialize_server_update(&ServerUpdate::Notice(ServerNotice::Kicked {
+                                reason: reason.to_string(),
+                            }));
+                    self.socket
+                        .sender
+                        .send(Packet::reliable_ordered(addr, payload, Some(MAP_STREAM)))
+                        .unwrap();
+                }
+            },
+            Ok(_) => warn!("Ignoring packet from unknown address: {}", addr),
+            Err(err) => {
+                self.metrics.decode_errors.inc();
+                self.bad_packets.report(addr, &err);
+            }
+        }
+    }
+
+    /// The session a client connecting with the role joins. Players join the open lobby or
+    /// a new one, spectators watch the oldest match they can join.
+    fn session_for(&mut self, role: ClientRole) -> Result<SessionId, &'static str> {
+        let open = self.sessions.values().find(|session| match role {
+            ClientRole::Player => session.accepts_players(),
+            ClientRole::Spectator => session.accepts_spectators(),
+        });
+        match (open, role) {
+            (Some(session), _) => Ok(session.id),
+            (None, ClientRole::Spectator) => Err("There is no match with room for spectators"),
+            (None, ClientRole::Player)
+                if self.sessions.len() >= self.config.max_sessions as usize =>
+            {
+                Err("The server is full")
+            }
+            (None, ClientRole::Player) => Ok(self.open_session()),
+        }
+    }
+
+    fn open_session(&mut self) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let session = Session::new(
+            id,
+            &self.config,
+            self.server_map.clone(),
+            self.tilemap.clone(),
+            &self.socket,
+            &self.metrics,
+        );
+        info!(
+            "Session {}: opened a lobby for {} players",
+            id, self.config.players
+        );
+        self.sessions.insert(id, session);
+        id
+    }
+
+    pub fn session(&self, id: SessionId) -> Option<&Session> {
+        self.sessions.get(&id)
+    }
+
+    fn selected_session(&mut self) -> Option<&mut Session> {
+        match self.selected {
+            Some(id) => self.sessions.get_mut(&id),
+            None if self.sessions.len() == 1 => self.sessions.values_mut().next(),
+            None => None,
+        }
+    }
+
+    /// Runs an admin command, returns true if the server should shut down
+    pub fn run_admin_command(&mut self, command: AdminCommand) -> bool {
+        match command {
+            AdminCommand::List => {
+                info!("{} sessions", self.sessions.len());
+                self.sessions.values().for_each(Session::list);
+            }
+            AdminCommand::Select(id) if self.sessions.contains_key(&id) => {
+                info!("Admin commands apply to session {}", id);
+                self.selected = Some(id);
+            }
+            AdminCommand::Select(id) => warn!("There is no session {}", id),
+            AdminCommand::Kick(player) => {
+                // Player ids are only unique within a session
+                let kicked = match self.selected {
+                    Some(id) => self
+                        .sessions
+                        .get_mut(&id)
+                        .and_then(|session| session.kick(&player)),
+                    None => self
+                        .sessions
+                        .values_mut()
+                        .find_map(|session| session.kick(&player)),
+                };
+                match kicked {
+                    Some(addr) => {
+                        self.routes.remove(&addr);
+                    }
+                    None => warn!("No player named or with the id: {}", player),
+                }
+            }
+            AdminCommand::Session(command) => {
+                let sessions = self.sessions.len();
+                match self.selected_session() {
+                    Some(session) => session.run_admin_command(command),
+                    None => warn!(
+                        "There are {} sessions, select one with: session <id>",
+                        sessions
+                    ),
+                }
+            }
+            AdminCommand::Shutdown => return true,
+        }
+        false
+    }
+
+    pub fn shut_down(&mut self) {
+        info!("Shutting down");
+        self.sessions.values_mut().for_each(Session::shut_down);
+    }
+}
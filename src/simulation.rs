@@ -0,0 +1,86 @@
+use legion::{systems::CommandBuffer, world::SubWorld, *};
+
+use crate::{
+    components::{Transform, Velocity},
+    game_time::GameTime,
+    map_chunk::ChunkIndex,
+    navigation::{movement_impl, FlowField},
+    resources::Command,
+    tilemap::TileMap,
+};
+
+/// Commands accepted by the server that should be applied during the current tick.
+/// These must be cleared after each tick.
+#[derive(Debug, Default)]
+pub struct Orders {
+    pub commands: Vec<Command>,
+}
+
+/// Counters of the work done by the simulation systems, read by the server's metrics
+#[derive(Debug, Default)]
+pub struct SimulationStats {
+    /// Flow fields generated for move orders since the counter was last taken
+    pub flow_fields_generated: u64,
+    /// Entities following a flow field during the last tick
+    pub moving_entities: u32,
+}
+
+/// Adds the systems running the authoritative game simulation. Anything
+/// that fills in the Orders resource should be added before these systems.
+/// The systems need the Orders, SimulationStats, GameTime and TileMap resources.
+pub fn add_simulation_systems(builder: &mut systems::Builder) -> &mut systems::Builder {
+    builder
+        .add_system(apply_orders_system())
+        .add_system(movement_system())
+}
+
+#[system]
+#[read_component(Transform)]
+fn apply_orders(
+    world: &SubWorld,
+    command_buffer: &mut CommandBuffer,
+    #[resource] tilemap: &TileMap,
+    #[resource] orders: &Orders,
+    #[resource] stats: &mut SimulationStats,
+) {
+    for command in orders.commands.iter() {
+        match command {
+            Command::Move { entity, target } => {
+                if world.entry_ref(*entity).is_err() {
+                    warn!("Ignoring move order for unknown entity: {:?}", entity);
+                    continue;
+                }
+                match ChunkIndex::new(target.x as i32, target.z as i32) {
+                    Ok(target) => {
+                        command_buffer
+                            .add_component(*entity, FlowField::new(target, &tilemap.chunk));
+                        stats.flow_fields_generated += 1;
+                    }
+                    Err(err) => warn!("Ignoring move order: {}", err),
+                }
+            }
+        }
+    }
+}
+
+#[system]
+fn movement(
+    world: &mut SubWorld,
+    #[resource] tilemap: &TileMap,
+    #[resource] game_time: &GameTime,
+    #[resource] stats: &mut SimulationStats,
+    query: &mut Query<(Entity, &FlowField, &mut Transform, &mut Velocity)>,
+) {
+    stats.moving_entities = 0;
+    query.for_each_mut(world, |(_entity, flow_field, transform, velocity)| {
+        stats.moving_entities += 1;
+        // Movement along the flow field
+        movement_impl(
+            &tilemap.chunk,
+            flow_field,
+            transform,
+            velocity,
+            game_time.delta_seconds(),
+        );
+    });
+}
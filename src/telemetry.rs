@@ -0,0 +1,688 @@
+//! Structured log of a match written by the server as JSON Lines, one event per line.
+//! The log is flushed after every event so it survives the server crashing. The logs of
+//! several playtests can be summarised and compared with the telemetry command.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{chat::TeamId, components::PlayerId};
+
+pub const TELEMETRY_VERSION: u32 = 1;
+/// Time between the samples of the unit counts and orders
+pub const TELEMETRY_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryPlayer {
+    pub player: PlayerId,
+    pub name: String,
+    pub team: TeamId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    AllPlayersLeft,
+    ServerShutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Won,
+    Lost,
+    Draw,
+}
+
+/// A line of the log. `time` is the number of seconds since the match started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TelemetryEvent {
+    MatchStarted {
+        version: u32,
+        session: u32,
+        map: String,
+        /// Hex encoded since json numbers can't hold every u64
+        map_hash: String,
+        /// Seconds since the unix epoch
+        started_at: u64,
+        tick_rate: u32,
+        lockstep: bool,
+        players: Vec<TelemetryPlayer>,
+    },
+    /// Units owned by every player and the orders they issued since the previous sample
+    Sample {
+        tick: u64,
+        time: f64,
+        #[serde(with = "player_map")]
+        units: BTreeMap<PlayerId, u32>,
+        #[serde(with = "player_map")]
+        orders: BTreeMap<PlayerId, u64>,
+    },
+    PlayerLeft {
+        tick: u64,
+        time: f64,
+        player: PlayerId,
+    },
+    MatchEnded {
+        tick: u64,
+        time: f64,
+        reason: EndReason,
+        /// Orders issued by every player during the whole match
+        #[serde(with = "player_map")]
+        orders: BTreeMap<PlayerId, u64>,
+        #[serde(with = "player_map")]
+        outcomes: BTreeMap<PlayerId, Outcome>,
+    },
+}
+
+/// Json objects keyed by player. The keys are strings in json which serde_json only parses
+/// back into numbers when the map isn't buffered, as it is for the tagged TelemetryEvent.
+mod player_map {
+    use std::collections::BTreeMap;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::components::PlayerId;
+
+    pub fn serialize<S: Serializer, T: Serialize>(
+        map: &BTreeMap<PlayerId, T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        map.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<PlayerId, T>, D::Error> {
+        BTreeMap::<String, T>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(player, value)| {
+                let player = player
+                    .parse()
+                    .map_err(|_| D::Error::custom(format!("invalid player: {}", player)))?;
+                Ok((player, value))
+            })
+            .collect()
+    }
+}
+
+/// Records the events of a match as it's played. A team is defeated once all of its players
+/// have left or lost their units, the match is won by the last team that isn't defeated.
+pub struct MatchTelemetry<W: Write> {
+    writer: W,
+    started: Instant,
+    last_sample: Instant,
+    players: Vec<TelemetryPlayer>,
+    connected: BTreeSet<PlayerId>,
+    units: BTreeMap<PlayerId, u32>,
+    orders: BTreeMap<PlayerId, u64>,
+    orders_since_sample: BTreeMap<PlayerId, u64>,
+    /// Set once a single team is left standing
+    winner: Option<TeamId>,
+}
+
+impl MatchTelemetry<BufWriter<File>> {
+    pub fn create(path: &Path, started: TelemetryEvent, now: Instant) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create telemetry log {}", path.display()))?;
+        MatchTelemetry::new(BufWriter::new(file), started, now)
+    }
+}
+
+impl<W: Write> MatchTelemetry<W> {
+    /// Writes the MatchStarted event, which lists the players of the match
+    pub fn new(writer: W, started: TelemetryEvent, now: Instant) -> Result<Self> {
+        let players = match &started {
+            TelemetryEvent::MatchStarted { players, .. } => players.clone(),
+            event => return Err(anyhow!("Expected a MatchStarted event, got: {:?}", event)),
+        };
+        let mut telemetry = MatchTelemetry {
+            writer,
+            started: now,
+            last_sample: now,
+            connected: players.iter().map(|player| player.player).collect(),
+            players,
+            units: BTreeMap::new(),
+            orders: BTreeMap::new(),
+            orders_since_sample: BTreeMap::new(),
+            winner: None,
+        };
+        telemetry.write(&started)?;
+        Ok(telemetry)
+    }
+
+    fn write(&mut self, event: &TelemetryEvent) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    #[inline]
+    fn time(&self, now: Instant) -> f64 {
+        now.saturating_duration_since(self.started).as_secs_f64()
+    }
+
+    #[inline]
+    pub fn record_order(&mut self, player: PlayerId) {
+        *self.orders.entry(player).or_default() += 1;
+        *self.orders_since_sample.entry(player).or_default() += 1;
+    }
+
+    /// Samples the unit counts every TELEMETRY_SAMPLE_INTERVAL, `count_units`
+    /// is only called when a sample is due
+    pub fn record_tick(
+        &mut self,
+        tick: u64,
+        now: Instant,
+        count_units: impl FnOnce() -> BTreeMap<PlayerId, u32>,
+    ) -> Result<()> {
+        if now.saturating_duration_since(self.last_sample) < TELEMETRY_SAMPLE_INTERVAL {
+            return Ok(());
+        }
+        self.last_sample = now;
+        self.units = count_units();
+        let sample = TelemetryEvent::Sample {
+            tick,
+            time: self.time(now),
+            units: self.units.clone(),
+            orders: std::mem::take(&mut self.orders_since_sample),
+        };
+        self.write(&sample)?;
+        self.decide_winner();
+        Ok(())
+    }
+
+    /// Records the players that have left since the last call
+    pub fn update_connected(
+        &mut self,
+        tick: u64,
+        now: Instant,
+        connected: impl Iterator<Item = PlayerId>,
+    ) -> Result<()> {
+        let connected: BTreeSet<PlayerId> = connected.collect();
+        let left: Vec<PlayerId> = self.connected.difference(&connected).copied().collect();
+        if left.is_empty() {
+            return Ok(());
+        }
+        self.connected = connected;
+        for player in left {
+            let event = TelemetryEvent::PlayerLeft {
+                tick,
+                time: self.time(now),
+                player,
+            };
+            self.write(&event)?;
+        }
+        self.decide_winner();
+        Ok(())
+    }
+
+    /// The teams with a connected player that has units, every player is
+    /// assumed to have units until the first sample
+    fn teams_standing(&self) -> BTreeSet<TeamId> {
+        self.players
+            .iter()
+            .filter(|player| self.connected.contains(&player.player))
+            .filter(|player| {
+                self.units.is_empty()
+                    || self
+                        .units
+                        .get(&player.player)
+                        .is_some_and(|&units| units > 0)
+            })
+            .map(|player| player.team)
+            .collect()
+    }
+
+    fn decide_winner(&mut self) {
+        let teams: BTreeSet<TeamId> = self.players.iter().map(|player| player.team).collect();
+        let standing = self.teams_standing();
+        // A match with a single team can't be won
+        if self.winner.is_none() && teams.len() > 1 && standing.len() == 1 {
+            self.winner = standing.into_iter().next();
+        }
+    }
+
+    /// The winning team's players have won and everyone else has lost. Without a winner the
+    /// players of the teams still standing draw.
+    pub fn outcomes(&self) -> BTreeMap<PlayerId, Outcome> {
+        let standing = self.teams_standing();
+        self.players
+            .iter()
+            .map(|player| {
+                let outcome = match self.winner {
+                    Some(winner) if winner == player.team => Outcome::Won,
+                    Some(_) => Outcome::Lost,
+                    None if standing.contains(&player.team) => Outcome::Draw,
+                    None if standing.is_empty() => Outcome::Draw,
+                    None => Outcome::Lost,
+                };
+                (player.player, outcome)
+            })
+            .collect()
+    }
+
+    /// Writes the result of the match
+    pub fn finish(&mut self, tick: u64, now: Instant, reason: EndReason) -> Result<()> {
+        let ended = TelemetryEvent::MatchEnded {
+            tick,
+            time: self.time(now),
+            reason,
+            orders: self.orders.clone(),
+            outcomes: self.outcomes(),
+        };
+        self.write(&ended)
+    }
+}
+
+/// Seconds since the unix epoch, for MatchStarted
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PlayerSummary {
+    pub name: String,
+    pub team: TeamId,
+    /// None if the log ends before the match did
+    pub outcome: Option<Outcome>,
+    pub orders: u64,
+    pub first_units: Option<u32>,
+    pub peak_units: u32,
+    pub last_units: Option<u32>,
+    /// Seconds into the match the player left
+    pub left_at: Option<f64>,
+}
+
+impl PlayerSummary {
+    pub fn orders_per_minute(&self, duration: f64) -> f64 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        self.orders as f64 * 60.0 / duration
+    }
+}
+
+/// What happened in a match according to its log
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MatchSummary {
+    pub map: String,
+    pub map_hash: String,
+    pub started_at: u64,
+    /// Seconds from the start to the last event
+    pub duration: f64,
+    pub ticks: u64,
+    pub reason: Option<EndReason>,
+    pub players: BTreeMap<PlayerId, PlayerSummary>,
+}
+
+impl MatchSummary {
+    /// Reads a match log, `name` is only used in the errors
+    pub fn read(reader: impl BufRead, name: &str) -> Result<Self> {
+        let mut summary: Option<MatchSummary> = None;
+        // Counted from the samples in case the log ends before the match did
+        let mut sampled_orders: BTreeMap<PlayerId, u64> = BTreeMap::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: TelemetryEvent = serde_json::from_str(&line)
+                .with_context(|| format!("{}:{}: invalid event", name, number + 1))?;
+            let summary = match (&mut summary, event) {
+                (
+                    None,
+                    TelemetryEvent::MatchStarted {
+                        version,
+                        map,
+                        map_hash,
+                        started_at,
+                        players,
+                        ..
+                    },
+                ) => {
+                    if version != TELEMETRY_VERSION {
+                        return Err(anyhow!(
+                            "{}: unsupported telemetry version: {}, expected: {}",
+                            name,
+                            version,
+                            TELEMETRY_VERSION
+                        ));
+                    }
+                    let players = players
+                        .into_iter()
+                        .map(|player| {
+                            let summary = PlayerSummary {
+                                name: player.name,
+                                team: player.team,
+                                ..Default::default()
+                            };
+                            (player.player, summary)
+                        })
+                        .collect();
+                    summary = Some(MatchSummary {
+                        map,
+                        map_hash,
+                        started_at,
+                        players,
+                        ..Default::default()
+                    });
+                    continue;
+                }
+                (None, _) => return Err(anyhow!("{}: the log doesn't start with the match", name)),
+                (Some(summary), event) => (summary, event),
+            };
+            match summary {
+                (
+                    summary,
+                    TelemetryEvent::Sample {
+                        tick,
+                        time,
+                        units,
+                        orders,
+                    },
+                ) => {
+                    summary.ticks = tick;
+                    summary.duration = time;
+                    for (player, units) in units {
+                        if let Some(player) = summary.players.get_mut(&player) {
+                            player.first_units.get_or_insert(units);
+                            player.peak_units = player.peak_units.max(units);
+                            player.last_units = Some(units);
+                        }
+                    }
+                    for (player, orders) in orders {
+                        *sampled_orders.entry(player).or_default() += orders;
+                    }
+                }
+                (summary, TelemetryEvent::PlayerLeft { tick, time, player }) => {
+                    summary.ticks = tick;
+                    summary.duration = time;
+                    if let Some(player) = summary.players.get_mut(&player) {
+                        player.left_at = Some(time);
+                    }
+                }
+                (
+                    summary,
+                    TelemetryEvent::MatchEnded {
+                        tick,
+                        time,
+                        reason,
+                        orders,
+                        outcomes,
+                    },
+                ) => {
+                    summary.ticks = tick;
+                    summary.duration = time;
+                    summary.reason = Some(reason);
+                    sampled_orders = orders;
+                    for (player, outcome) in outcomes {
+                        if let Some(player) = summary.players.get_mut(&player) {
+                            player.outcome = Some(outcome);
+                        }
+                    }
+                }
+                (_, TelemetryEvent::MatchStarted { .. }) => {
+                    return Err(anyhow!("{}:{}: the match started twice", name, number + 1))
+                }
+            }
+        }
+        let mut summary = summary.ok_or_else(|| anyhow!("{}: the log is empty", name))?;
+        for (player, orders) in sampled_orders {
+            if let Some(player) = summary.players.get_mut(&player) {
+                player.orders = orders;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+impl fmt::Display for MatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = match self.reason {
+            Some(EndReason::AllPlayersLeft) => "ended when all players left",
+            Some(EndReason::ServerShutdown) => "ended by the server shutting down",
+            None => "didn't end",
+        };
+        writeln!(
+            f,
+            "Map {} ({}), {:.0}s over {} ticks, {}",
+            self.map, self.map_hash, self.duration, self.ticks, result
+        )?;
+        for (id, player) in &self.players {
+            let outcome = player
+                .outcome
+                .map_or_else(|| "unknown".to_string(), |outcome| format!("{:?}", outcome));
+            write!(
+                f,
+                "  Player {} {} (team {}): {}, {} orders ({:.1}/min), units {}/{}/{} first/peak/last",
+                id,
+                player.name,
+                player.team,
+                outcome,
+                player.orders,
+                player.orders_per_minute(self.duration),
+                player.first_units.unwrap_or(0),
+                player.peak_units,
+                player.last_units.unwrap_or(0)
+            )?;
+            match player.left_at {
+                Some(left_at) => writeln!(f, ", left after {:.0}s", left_at)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outcomes of a player slot over several matches
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SlotRecord {
+    pub won: u32,
+    pub lost: u32,
+    pub draw: u32,
+}
+
+/// Matches played on a map summed up. Since the players start at fixed positions,
+/// the record of each player slot shows whether the map favors a position.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MapSummary {
+    pub matches: u32,
+    pub total_duration: f64,
+    pub total_orders: u64,
+    pub slots: BTreeMap<PlayerId, SlotRecord>,
+}
+
+impl MapSummary {
+    pub fn add(&mut self, summary: &MatchSummary) {
+        self.matches += 1;
+        self.total_duration += summary.duration;
+        for (id, player) in &summary.players {
+            self.total_orders += player.orders;
+            let record = self.slots.entry(*id).or_default();
+            match player.outcome {
+                Some(Outcome::Won) => record.won += 1,
+                Some(Outcome::Lost) => record.lost += 1,
+                Some(Outcome::Draw) => record.draw += 1,
+                None => {}
+            }
+        }
+    }
+
+    pub fn average_duration(&self) -> f64 {
+        self.total_duration / self.matches.max(1) as f64
+    }
+}
+
+impl fmt::Display for MapSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let orders_per_minute = if self.total_duration > 0.0 {
+            self.total_orders as f64 * 60.0 / self.total_duration
+        } else {
+            0.0
+        };
+        writeln!(
+            f,
+            "{} matches, {:.0}s on average, {:.1} orders/min",
+            self.matches,
+            self.average_duration(),
+            orders_per_minute
+        )?;
+        for (slot, record) in &self.slots {
+            let decided = record.won + record.lost;
+            let win_rate = if decided > 0 {
+                record.won as f64 * 100.0 / decided as f64
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "  Player {}: {} won, {} lost, {} draws ({:.0}% of decided matches won)",
+                slot, record.won, record.lost, record.draw, win_rate
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(players: &[(PlayerId, TeamId)]) -> TelemetryEvent {
+        TelemetryEvent::MatchStarted {
+            version: TELEMETRY_VERSION,
+            session: 0,
+            map: "test.map".to_string(),
+            map_hash: format!("{:016x}", u64::MAX),
+            started_at: 0,
+            tick_rate: 60,
+            lockstep: false,
+            players: players
+                .iter()
+                .map(|&(player, team)| TelemetryPlayer {
+                    player,
+                    name: format!("Player {}", player),
+                    team,
+                })
+                .collect(),
+        }
+    }
+
+    fn units(counts: &[(PlayerId, u32)]) -> BTreeMap<PlayerId, u32> {
+        counts.iter().copied().collect()
+    }
+
+    #[test]
+    fn last_team_standing_wins() {
+        let start = Instant::now();
+        let mut log = Vec::new();
+        let mut telemetry =
+            MatchTelemetry::new(&mut log, started(&[(0, 0), (1, 1), (2, 0)]), start).unwrap();
+        telemetry.record_order(0);
+        telemetry.record_order(1);
+        telemetry.record_order(1);
+        // Not due yet
+        telemetry
+            .record_tick(10, start, || panic!("Sampled too early"))
+            .unwrap();
+        let later = start + TELEMETRY_SAMPLE_INTERVAL;
+        telemetry
+            .record_tick(300, later, || units(&[(0, 3), (1, 2), (2, 0)]))
+            .unwrap();
+        assert_eq!(telemetry.winner, None);
+        telemetry
+            .update_connected(400, later, [0, 2].iter().copied())
+            .unwrap();
+        assert_eq!(telemetry.winner, Some(0));
+        // The winner stays decided after everyone has left
+        telemetry
+            .update_connected(500, later, std::iter::empty())
+            .unwrap();
+        telemetry
+            .finish(500, later, EndReason::AllPlayersLeft)
+            .unwrap();
+        drop(telemetry);
+
+        let lines = std::str::from_utf8(&log).unwrap().lines().count();
+        // Started, sample, 3 players left and ended
+        assert_eq!(lines, 6);
+        let summary = MatchSummary::read(log.as_slice(), "test").unwrap();
+        assert_eq!(summary.map_hash, "ffffffffffffffff");
+        assert_eq!(summary.ticks, 500);
+        assert_eq!(summary.reason, Some(EndReason::AllPlayersLeft));
+        let outcomes: Vec<_> = summary
+            .players
+            .values()
+            .map(|player| player.outcome.unwrap())
+            .collect();
+        assert_eq!(outcomes, vec![Outcome::Won, Outcome::Lost, Outcome::Won]);
+        assert_eq!(summary.players[&1].orders, 2);
+        assert_eq!(summary.players[&1].left_at, Some(summary.duration));
+        assert_eq!(summary.players[&0].last_units, Some(3));
+    }
+
+    #[test]
+    fn standing_teams_draw_without_a_winner() {
+        let start = Instant::now();
+        let mut log = Vec::new();
+        let mut telemetry =
+            MatchTelemetry::new(&mut log, started(&[(0, 0), (1, 1)]), start).unwrap();
+        telemetry
+            .finish(100, start, EndReason::ServerShutdown)
+            .unwrap();
+        drop(telemetry);
+        let summary = MatchSummary::read(log.as_slice(), "test").unwrap();
+        assert!(summary
+            .players
+            .values()
+            .all(|player| player.outcome == Some(Outcome::Draw)));
+
+        let mut map = MapSummary::default();
+        map.add(&summary);
+        map.add(&summary);
+        assert_eq!(map.matches, 2);
+        assert_eq!(
+            map.slots[&0],
+            SlotRecord {
+                won: 0,
+                lost: 0,
+                draw: 2
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_logs_are_summarised() {
+        let start = Instant::now();
+        let mut log = Vec::new();
+        let mut telemetry =
+            MatchTelemetry::new(&mut log, started(&[(0, 0), (1, 1)]), start).unwrap();
+        telemetry.record_order(1);
+        telemetry
+            .record_tick(300, start + TELEMETRY_SAMPLE_INTERVAL, || {
+                units(&[(0, 1), (1, 1)])
+            })
+            .unwrap();
+        drop(telemetry);
+        let summary = MatchSummary::read(log.as_slice(), "test").unwrap();
+        assert_eq!(summary.reason, None);
+        assert_eq!(summary.players[&1].orders, 1);
+        assert_eq!(summary.players[&1].outcome, None);
+
+        assert!(MatchSummary::read(&b""[..], "empty").is_err());
+        let sample_first = b"{\"event\":\"player_left\",\"tick\":1,\"time\":0.0,\"player\":0}\n";
+        assert!(MatchSummary::read(&sample_first[..], "no start").is_err());
+    }
+}
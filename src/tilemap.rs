@@ -579,6 +579,16 @@ impl<'a> LoadableMap<'a> {
         Ok(())
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        use bincode::Options;
+        // Same options as bincode::deserialize but the map can't claim more memory than its size
+        Ok(bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(bytes.len() as u64)
+            .allow_trailing_bytes()
+            .deserialize(bytes)?)
+    }
+
     pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
         let now = std::time::Instant::now();
         let map_file = std::fs::File::open(path)?;
@@ -591,3 +601,23 @@ impl<'a> LoadableMap<'a> {
         Ok(loaded_map)
     }
 }
+
+/// Hash of the map file content used to verify that the server and
+/// clients play on the same map
+#[inline]
+pub fn map_hash(map_bytes: &[u8]) -> u64 {
+    fxhash::hash64(map_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn loading_random_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = LoadableMap::from_bytes(&bytes);
+        }
+    }
+}
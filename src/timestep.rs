@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+/// Highest supported tick rate, the tick duration has to stay well above a nanosecond
+pub const MAX_TICK_RATE: u32 = 1000;
+
+/// Whether the simulation can run at the tick rate
+#[inline]
+pub fn is_valid_tick_rate(tick_rate: u32) -> bool {
+    (1..=MAX_TICK_RATE).contains(&tick_rate)
+}
+
+/// Runs the simulation in fixed size ticks independently of how often the loop driving it
+/// gets to run. Elapsed time is accumulated and consumed one tick at a time.
+/// See: https://gafferongames.com/post/fix_your_timestep/
+#[derive(Debug)]
+pub struct FixedTimestep {
+    tick_duration: Duration,
+    /// Max number of ticks run to catch up after falling behind. Time beyond that is dropped
+    /// so a slow server doesn't end up spending all its time catching up.
+    max_catch_up_ticks: u32,
+    accumulator: Duration,
+    last_update: Instant,
+    tick: u64,
+    skipped_ticks: u64,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32, max_catch_up_ticks: u32, now: Instant) -> Self {
+        assert!(
+            is_valid_tick_rate(tick_rate),
+            "The tick rate must be between 1 and {}",
+            MAX_TICK_RATE
+        );
+        FixedTimestep {
+            tick_duration: Duration::from_secs(1) / tick_rate,
+            max_catch_up_ticks: max_catch_up_ticks.max(1),
+            accumulator: Duration::ZERO,
+            last_update: now,
+            tick: 0,
+            skipped_ticks: 0,
+        }
+    }
+
+    #[inline]
+    pub fn tick_duration(&self) -> Duration {
+        self.tick_duration
+    }
+
+    /// Number of the last tick that was run, starting from 1
+    #[inline]
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Total number of ticks dropped because the catch-up limit was reached
+    #[inline]
+    pub fn skipped_ticks(&self) -> u64 {
+        self.skipped_ticks
+    }
+
+    /// Changes the tick rate, the tick count and accumulated time carry over
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        assert!(
+            is_valid_tick_rate(tick_rate),
+            "The tick rate must be between 1 and {}",
+            MAX_TICK_RATE
+        );
+        self.tick_duration = Duration::from_secs(1) / tick_rate;
+    }
+
+    /// Drops the time elapsed since the last update so the ticks missed
+    /// while the simulation was paused aren't run
+    pub fn resume(&mut self, now: Instant) {
+        self.accumulator = Duration::ZERO;
+        self.last_update = now;
+    }
+
+    /// Adds the time elapsed since the last call. Returns the number of ticks dropped
+    /// because of the catch-up limit.
+    pub fn accumulate(&mut self, now: Instant) -> u64 {
+        self.accumulator += now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        let max_accumulated = self.tick_duration * self.max_catch_up_ticks;
+        if self.accumulator < max_accumulated + self.tick_duration {
+            return 0;
+        }
+        let excess = self.accumulator - max_accumulated;
+        let skipped = (excess.as_nanos() / self.tick_duration.as_nanos()) as u64;
+        self.accumulator -= self.tick_duration * skipped as u32;
+        self.skipped_ticks += skipped;
+        skipped
+    }
+
+    /// Consumes one tick worth of accumulated time and returns the new tick number,
+    /// or None if there isn't enough time accumulated to run a tick
+    pub fn next_tick(&mut self) -> Option<u64> {
+        if self.accumulator < self.tick_duration {
+            return None;
+        }
+        self.accumulator -= self.tick_duration;
+        self.tick += 1;
+        Some(self.tick)
+    }
+
+    /// Time left until the next tick can run
+    pub fn time_until_next_tick(&self, now: Instant) -> Duration {
+        let accumulated = self.accumulator + now.saturating_duration_since(self.last_update);
+        self.tick_duration.saturating_sub(accumulated)
+    }
+}
+
+/// Whether a snapshot should be sent after the given tick to send `snapshot_rate`
+/// snapshots per second, spread as evenly as possible over the ticks
+#[inline]
+pub fn is_snapshot_tick(tick: u64, tick_rate: u32, snapshot_rate: u32) -> bool {
+    let snapshots_before = tick.saturating_sub(1) * snapshot_rate as u64 / tick_rate as u64;
+    tick * snapshot_rate as u64 / tick_rate as u64 > snapshots_before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_ticks_for_elapsed_time() {
+        let start = Instant::now();
+        let mut timestep = FixedTimestep::new(50, 5, start);
+        assert_eq!(timestep.tick_duration(), Duration::from_millis(20));
+        timestep.accumulate(start + Duration::from_millis(15));
+        assert_eq!(timestep.next_tick(), None);
+        assert_eq!(
+            timestep.time_until_next_tick(start + Duration::from_millis(15)),
+            Duration::from_millis(5)
+        );
+        // The remainder carries over to the next update
+        timestep.accumulate(start + Duration::from_millis(65));
+        assert_eq!(timestep.next_tick(), Some(1));
+        assert_eq!(timestep.next_tick(), Some(2));
+        assert_eq!(timestep.next_tick(), Some(3));
+        assert_eq!(timestep.next_tick(), None);
+        assert_eq!(
+            timestep.time_until_next_tick(start + Duration::from_millis(65)),
+            Duration::from_millis(15)
+        );
+        assert_eq!(timestep.tick(), 3);
+    }
+
+    #[test]
+    fn limits_catch_up() {
+        let start = Instant::now();
+        let mut timestep = FixedTimestep::new(50, 5, start);
+        let skipped = timestep.accumulate(start + Duration::from_millis(1010));
+        assert_eq!(skipped, 45);
+        assert_eq!(timestep.skipped_ticks(), 45);
+        let ticks = std::iter::from_fn(|| timestep.next_tick()).count();
+        assert_eq!(ticks, 5);
+        // Ticks keep counting from where they were after skipping
+        timestep.accumulate(start + Duration::from_millis(1030));
+        assert_eq!(timestep.next_tick(), Some(6));
+    }
+
+    #[test]
+    fn resumes_without_catching_up() {
+        let start = Instant::now();
+        let mut timestep = FixedTimestep::new(50, 5, start);
+        timestep.accumulate(start + Duration::from_millis(20));
+        assert_eq!(timestep.next_tick(), Some(1));
+        // Paused for a second
+        timestep.resume(start + Duration::from_millis(1020));
+        timestep.accumulate(start + Duration::from_millis(1030));
+        assert_eq!(timestep.next_tick(), None);
+        assert_eq!(timestep.skipped_ticks(), 0);
+
+        timestep.set_tick_rate(100);
+        assert_eq!(timestep.tick_duration(), Duration::from_millis(10));
+        assert_eq!(timestep.next_tick(), Some(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_tick_rates_too_high_to_time() {
+        FixedTimestep::new(2_000_000_000, 5, Instant::now());
+    }
+
+    #[test]
+    fn snapshots_spread_over_ticks() {
+        let snapshots = |tick_rate, snapshot_rate| {
+            (1..=tick_rate as u64)
+                .filter(|tick| is_snapshot_tick(*tick, tick_rate, snapshot_rate))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(snapshots(60, 60).len(), 60);
+        assert_eq!(
+            snapshots(60, 30),
+            (1..=30).map(|i| i * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(snapshots(60, 20).len(), 20);
+        assert_eq!(snapshots(60, 25).len(), 25);
+    }
+}
@@ -0,0 +1,356 @@
+//! Server updates too large for a single packet, like the initial world, sent compressed
+//! in chunks over a reliable ordered stream. The receiver acknowledges the chunks it has
+//! so that no more than TRANSFER_WINDOW chunks are in flight at once.
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+/// Size in bytes of the compressed data in each chunk
+pub const TRANSFER_CHUNK_SIZE: usize = 1024;
+/// Max number of chunks sent before they are acknowledged
+pub const TRANSFER_WINDOW: u32 = 64;
+/// Number of received chunks acknowledged at once, the last chunk is always acknowledged
+pub const TRANSFER_ACK_INTERVAL: u32 = 16;
+/// Max size in bytes of a decompressed transfer. This protects against transfers
+/// announcing huge sizes or compressed data expanding to far more than announced.
+pub const MAX_TRANSFER_SIZE: u64 = 64 * 1024 * 1024;
+
+pub type TransferId = u32;
+
+/// Part of the compressed data of a transfer starting at byte `index * TRANSFER_CHUNK_SIZE`.
+/// The sizes are repeated in every chunk so no separate message has to announce the transfer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransferChunk {
+    pub transfer: TransferId,
+    pub index: u32,
+    /// Size of the transferred data after decompression
+    pub size: u64,
+    pub compressed_size: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// The receiver has the first `received` chunks of the transfer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferAck {
+    pub transfer: TransferId,
+    pub received: u32,
+}
+
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(bytes)
+        .expect("Writing to a vec can't fail");
+    encoder.finish().expect("Writing to a vec can't fail")
+}
+
+/// Decompresses the data and verifies it matches the expected size
+pub fn decompress(bytes: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::with_capacity(size.min(MAX_TRANSFER_SIZE) as usize);
+    // One byte more than expected to detect data that's larger than announced
+    DeflateDecoder::new(bytes)
+        .take(size + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 != size {
+        return Err(anyhow!(
+            "Transfer decompressed to {} bytes, expected: {}",
+            decompressed.len(),
+            size
+        ));
+    }
+    Ok(decompressed)
+}
+
+#[inline]
+fn chunk_count(compressed_size: usize) -> u32 {
+    compressed_size.div_ceil(TRANSFER_CHUNK_SIZE) as u32
+}
+
+#[derive(Debug)]
+struct OutgoingTransfer {
+    id: TransferId,
+    size: u64,
+    compressed: Vec<u8>,
+    sent: u32,
+    acknowledged: u32,
+}
+
+impl OutgoingTransfer {
+    #[inline]
+    fn chunks(&self) -> u32 {
+        chunk_count(self.compressed.len())
+    }
+}
+
+/// The transfers queued for a single receiver. They are sent one after the other,
+/// the next one starts once the receiver has acknowledged every chunk of the previous.
+#[derive(Debug, Default)]
+pub struct OutgoingTransfers {
+    next_id: TransferId,
+    queue: VecDeque<OutgoingTransfer>,
+}
+
+impl OutgoingTransfers {
+    /// Compresses the data and queues it to be sent, returns the id of the transfer
+    pub fn push(&mut self, data: &[u8]) -> TransferId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.queue.push_back(OutgoingTransfer {
+            id,
+            size: data.len() as u64,
+            compressed: compress(data),
+            sent: 0,
+            acknowledged: 0,
+        });
+        id
+    }
+
+    pub fn acknowledge(&mut self, ack: TransferAck) {
+        if let Some(transfer) = self
+            .queue
+            .front_mut()
+            .filter(|transfer| transfer.id == ack.transfer)
+        {
+            transfer.acknowledged = ack.received.clamp(transfer.acknowledged, transfer.sent);
+            if transfer.acknowledged == transfer.chunks() {
+                self.queue.pop_front();
+            }
+        }
+    }
+
+    /// The chunks that can be sent without exceeding the window, they are
+    /// expected to be sent right away and reliably
+    pub fn next_chunks(&mut self) -> Vec<TransferChunk> {
+        let transfer = match self.queue.front_mut() {
+            Some(transfer) => transfer,
+            None => return Vec::new(),
+        };
+        let end = transfer
+            .chunks()
+            .min(transfer.acknowledged + TRANSFER_WINDOW);
+        let chunks = (transfer.sent..end)
+            .map(|index| {
+                let start = index as usize * TRANSFER_CHUNK_SIZE;
+                let end = (start + TRANSFER_CHUNK_SIZE).min(transfer.compressed.len());
+                TransferChunk {
+                    transfer: transfer.id,
+                    index,
+                    size: transfer.size,
+                    compressed_size: transfer.compressed.len() as u64,
+                    bytes: transfer.compressed[start..end].to_vec(),
+                }
+            })
+            .collect();
+        transfer.sent = transfer.sent.max(end);
+        chunks
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Whether the transfer hasn't been fully acknowledged yet
+    pub fn is_queued(&self, id: TransferId) -> bool {
+        self.queue.iter().any(|transfer| transfer.id == id)
+    }
+}
+
+#[derive(Debug)]
+struct IncomingTransfer {
+    id: TransferId,
+    size: u64,
+    compressed_size: u64,
+    bytes: Vec<u8>,
+    received: u32,
+}
+
+/// Reassembles the transfers from a single sender, the chunks are expected in order
+#[derive(Debug, Default)]
+pub struct IncomingTransfers {
+    current: Option<IncomingTransfer>,
+}
+
+impl IncomingTransfers {
+    /// Adds the chunk to its transfer. Returns the acknowledgement to send, if it's time
+    /// for one, and the decompressed data once the transfer is complete.
+    pub fn receive(
+        &mut self,
+        chunk: TransferChunk,
+    ) -> Result<(Option<TransferAck>, Option<Vec<u8>>)> {
+        if chunk.size > MAX_TRANSFER_SIZE || chunk.compressed_size > MAX_TRANSFER_SIZE {
+            return Err(anyhow!(
+                "Transfer of {} bytes exceeds the max transfer size",
+                chunk.size.max(chunk.compressed_size)
+            ));
+        }
+        if chunk.bytes.len() > TRANSFER_CHUNK_SIZE {
+            return Err(anyhow!("Transfer chunk exceeds the chunk size"));
+        }
+        let is_current = self
+            .current
+            .as_ref()
+            .is_some_and(|transfer| transfer.id == chunk.transfer);
+        if !is_current {
+            if chunk.index != 0 {
+                return Err(anyhow!(
+                    "Transfer {} started at chunk {}",
+                    chunk.transfer,
+                    chunk.index
+                ));
+            }
+            // The sender only starts a transfer once the previous one is complete
+            self.current = Some(IncomingTransfer {
+                id: chunk.transfer,
+                size: chunk.size,
+                compressed_size: chunk.compressed_size,
+                bytes: Vec::new(),
+                received: 0,
+            });
+        }
+        let transfer = self.current.as_mut().unwrap();
+        if chunk.index != transfer.received {
+            return Err(anyhow!(
+                "Unexpected transfer chunk: {}, expected: {}",
+                chunk.index,
+                transfer.received
+            ));
+        }
+        if (transfer.bytes.len() + chunk.bytes.len()) as u64 > transfer.compressed_size {
+            return Err(anyhow!(
+                "Transfer chunk exceeds the announced transfer size"
+            ));
+        }
+        transfer.bytes.extend_from_slice(&chunk.bytes);
+        transfer.received += 1;
+        let is_complete = transfer.bytes.len() as u64 == transfer.compressed_size;
+        let ack = (is_complete || transfer.received.is_multiple_of(TRANSFER_ACK_INTERVAL))
+            .then_some(TransferAck {
+                transfer: transfer.id,
+                received: transfer.received,
+            });
+        if !is_complete {
+            return Ok((ack, None));
+        }
+        let transfer = self.current.take().unwrap();
+        let data = decompress(&transfer.bytes, transfer.size)?;
+        Ok((ack, Some(data)))
+    }
+
+    /// Fraction of the transfer in progress that has been received
+    pub fn progress(&self) -> Option<f32> {
+        self.current
+            .as_ref()
+            .map(|transfer| transfer.bytes.len() as f32 / transfer.compressed_size.max(1) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::*;
+
+    /// Data that doesn't compress so it takes up many chunks
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        StdRng::seed_from_u64(0).fill_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Delivers the chunks one by one and acknowledges them like a client would
+    fn transfer(sender: &mut OutgoingTransfers, receiver: &mut IncomingTransfers) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        loop {
+            let chunks = sender.next_chunks();
+            if chunks.is_empty() {
+                return received;
+            }
+            assert!(chunks.len() as u32 <= TRANSFER_WINDOW);
+            for chunk in chunks {
+                let (ack, data) = receiver.receive(chunk).unwrap();
+                if let Some(ack) = ack {
+                    sender.acknowledge(ack);
+                }
+                received.extend(data);
+            }
+        }
+    }
+
+    #[test]
+    fn transfers_arrive_in_order() {
+        let world = random_bytes(200_000);
+        let resync = vec![42; 10_000];
+        let mut sender = OutgoingTransfers::default();
+        sender.push(&world);
+        let resync_id = sender.push(&resync);
+        assert!(sender.is_queued(resync_id));
+        let mut receiver = IncomingTransfers::default();
+        assert_eq!(transfer(&mut sender, &mut receiver), vec![world, resync]);
+        assert!(sender.is_empty());
+        assert!(!sender.is_queued(resync_id));
+        assert_eq!(receiver.progress(), None);
+    }
+
+    #[test]
+    fn window_limits_unacknowledged_chunks() {
+        let mut sender = OutgoingTransfers::default();
+        sender.push(&random_bytes(
+            TRANSFER_CHUNK_SIZE * TRANSFER_WINDOW as usize * 2,
+        ));
+        let chunks = sender.next_chunks();
+        assert_eq!(chunks.len() as u32, TRANSFER_WINDOW);
+        assert!(sender.next_chunks().is_empty());
+        sender.acknowledge(TransferAck {
+            transfer: 0,
+            received: TRANSFER_ACK_INTERVAL,
+        });
+        let next = sender.next_chunks();
+        assert_eq!(next.len() as u32, TRANSFER_ACK_INTERVAL);
+        assert_eq!(next[0].index, TRANSFER_WINDOW);
+
+        let mut receiver = IncomingTransfers::default();
+        let acks: Vec<_> = chunks
+            .into_iter()
+            .filter_map(|chunk| receiver.receive(chunk).unwrap().0)
+            .collect();
+        assert_eq!(acks.len() as u32, TRANSFER_WINDOW / TRANSFER_ACK_INTERVAL);
+        let progress = receiver.progress().unwrap();
+        assert!(progress > 0.4 && progress < 0.6);
+    }
+
+    #[test]
+    fn bad_chunks_are_rejected() {
+        let mut sender = OutgoingTransfers::default();
+        sender.push(&random_bytes(TRANSFER_CHUNK_SIZE * 4));
+        let chunks = sender.next_chunks();
+        let mut receiver = IncomingTransfers::default();
+        receiver.receive(chunks[0].clone()).unwrap();
+        // Skipped chunk
+        assert!(receiver.receive(chunks[2].clone()).is_err());
+        // Transfer that doesn't start at the first chunk
+        let mut not_started = chunks[1].clone();
+        not_started.transfer = 1;
+        assert!(receiver.receive(not_started).is_err());
+        // More data than announced
+        let mut oversized = chunks[0].clone();
+        oversized.transfer = 2;
+        oversized.compressed_size = 10;
+        assert!(receiver.receive(oversized).is_err());
+        // Huge announced size
+        let mut huge = chunks[0].clone();
+        huge.transfer = 3;
+        huge.size = u64::MAX;
+        assert!(receiver.receive(huge).is_err());
+        // Data decompressing to a different size than announced
+        let compressed = compress(&[3; 64]);
+        assert_eq!(decompress(&compressed, 64).unwrap(), vec![3; 64]);
+        assert!(decompress(&compressed, 63).is_err());
+        assert!(decompress(&compressed, 65).is_err());
+    }
+}
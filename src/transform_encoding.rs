@@ -0,0 +1,311 @@
+//! Compact wire format for unit transforms. Units only move on the map and rotate around Y,
+//! so a transform is sent as a quantized position within the map bounds and a 16 bit yaw.
+//! The scale is only included when it isn't 1.
+use std::{convert::TryInto, f32::consts::TAU};
+
+use anyhow::{anyhow, Result};
+use glam::{Affine3A, Quat, Vec2, Vec3, Vec3A};
+
+use crate::{
+    components::Transform,
+    map_chunk::CHUNK_SIZE,
+    tilemap::{TileMap, TILE_HEIGHT, TILE_WIDTH},
+};
+
+/// Area of the map along x and z that positions are quantized within. Positions outside
+/// of it are clamped to its edges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapBounds {
+    /// Corner with the smallest x and z
+    pub min: Vec2,
+    pub size: Vec2,
+}
+
+impl MapBounds {
+    /// The area covered by the map, placed and scaled by the map's transform
+    pub fn of(tilemap: &TileMap) -> Self {
+        let (scale, _, translation) = tilemap
+            .chunk
+            .transform()
+            .matrix
+            .to_scale_rotation_translation();
+        MapBounds {
+            min: Vec2::new(translation.x, translation.z),
+            size: Vec2::new(
+                CHUNK_SIZE as f32 * TILE_WIDTH * scale.x.abs(),
+                CHUNK_SIZE as f32 * TILE_HEIGHT * scale.z.abs(),
+            ),
+        }
+    }
+
+    #[inline]
+    pub fn max(&self) -> Vec2 {
+        self.min + self.size
+    }
+
+    /// Whether the position is on the map, the height isn't checked
+    pub fn contains(&self, position: Vec3A) -> bool {
+        let max = self.max();
+        (self.min.x..max.x).contains(&position.x) && (self.min.y..max.y).contains(&position.z)
+    }
+
+    /// Largest error of a position along x and z after a round trip
+    pub fn max_position_error(&self) -> Vec2 {
+        self.size / u16::MAX as f32 / 2.0
+    }
+}
+
+/// The area of a map with the default transform
+impl Default for MapBounds {
+    fn default() -> Self {
+        MapBounds {
+            min: Vec2::ZERO,
+            size: Vec2::new(
+                CHUNK_SIZE as f32 * TILE_WIDTH,
+                CHUNK_SIZE as f32 * TILE_HEIGHT,
+            ),
+        }
+    }
+}
+
+/// Heights are quantized in steps of 1 / HEIGHT_STEPS within the range of an i16
+pub const HEIGHT_STEPS: f32 = 256.0;
+/// Largest error of the yaw in radians after a round trip
+pub const MAX_YAW_ERROR: f32 = TAU / 65536.0 / 2.0;
+/// Scales this close to 1 are treated as 1 and not sent
+const SCALE_EPSILON: f32 = 1e-4;
+
+const FLAG_SCALE: u8 = 1;
+/// Size in bytes of an encoded transform without scale
+pub const MIN_ENCODED_SIZE: usize = 9;
+
+/// Writes an unsigned LEB128 varint
+pub fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 varint from the start of the bytes and advances past it
+pub fn read_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("Unexpected end of varint"))?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Varint is longer than 64 bits"))
+}
+
+fn read_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+    if bytes.len() < N {
+        return Err(anyhow!("Unexpected end of transform"));
+    }
+    let (array, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(array.try_into().unwrap())
+}
+
+fn quantize_unit(value: f32, min: f32, size: f32) -> u16 {
+    (((value - min) / size).clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+fn dequantize_unit(value: u16, min: f32, size: f32) -> f32 {
+    min + value as f32 / u16::MAX as f32 * size
+}
+
+/// Rotation around Y of the transform's forward (+Z) axis, see `navigation::look_at`
+fn yaw(transform: &Transform) -> f32 {
+    let forward = transform.matrix.matrix3.z_axis;
+    forward.x.atan2(forward.z)
+}
+
+pub fn encode_transform(transform: &Transform, bounds: &MapBounds, out: &mut Vec<u8>) {
+    let (scale, _, translation) = transform.matrix.to_scale_rotation_translation();
+    let has_scale = (scale - Vec3::ONE).abs().max_element() > SCALE_EPSILON;
+    out.push(if has_scale { FLAG_SCALE } else { 0 });
+    let x = quantize_unit(translation.x, bounds.min.x, bounds.size.x);
+    let z = quantize_unit(translation.z, bounds.min.y, bounds.size.y);
+    out.extend_from_slice(&x.to_le_bytes());
+    out.extend_from_slice(&z.to_le_bytes());
+    let height = (translation.y * HEIGHT_STEPS)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    out.extend_from_slice(&height.to_le_bytes());
+    let yaw = ((yaw(transform) / TAU).rem_euclid(1.0) * 65536.0).round() as u32 as u16;
+    out.extend_from_slice(&yaw.to_le_bytes());
+    if has_scale {
+        scale
+            .to_array()
+            .iter()
+            .for_each(|value| out.extend_from_slice(&value.to_le_bytes()));
+    }
+}
+
+/// Decodes a transform from the start of the bytes and advances past it
+pub fn decode_transform(bytes: &mut &[u8], bounds: &MapBounds) -> Result<Transform> {
+    let [flags] = read_array(bytes)?;
+    if flags & !FLAG_SCALE != 0 {
+        return Err(anyhow!("Unknown transform flags: {:#x}", flags));
+    }
+    let x = dequantize_unit(
+        u16::from_le_bytes(read_array(bytes)?),
+        bounds.min.x,
+        bounds.size.x,
+    );
+    let z = dequantize_unit(
+        u16::from_le_bytes(read_array(bytes)?),
+        bounds.min.y,
+        bounds.size.y,
+    );
+    let y = i16::from_le_bytes(read_array(bytes)?) as f32 / HEIGHT_STEPS;
+    let yaw = u16::from_le_bytes(read_array(bytes)?) as f32 / 65536.0 * TAU;
+    let scale = if flags & FLAG_SCALE != 0 {
+        Vec3::new(
+            f32::from_le_bytes(read_array(bytes)?),
+            f32::from_le_bytes(read_array(bytes)?),
+            f32::from_le_bytes(read_array(bytes)?),
+        )
+    } else {
+        Vec3::ONE
+    };
+    let mut matrix =
+        Affine3A::from_scale_rotation_translation(scale, Quat::from_rotation_y(yaw), Vec3::ZERO);
+    matrix.translation = Vec3A::new(x, y, z);
+    Ok(Transform { matrix })
+}
+
+/// The transform as the receiver sees it after encoding and decoding it
+pub fn quantize(transform: &Transform, bounds: &MapBounds) -> Transform {
+    let mut bytes = Vec::with_capacity(MIN_ENCODED_SIZE);
+    encode_transform(transform, bounds, &mut bytes);
+    decode_transform(&mut bytes.as_slice(), bounds).expect("Encoded transform to be decodable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navigation::look_at;
+    use proptest::prelude::*;
+
+    fn round_trip(transform: &Transform) -> (Transform, usize) {
+        round_trip_within(transform, &MapBounds::default())
+    }
+
+    fn round_trip_within(transform: &Transform, bounds: &MapBounds) -> (Transform, usize) {
+        let mut bytes = Vec::new();
+        encode_transform(transform, bounds, &mut bytes);
+        let mut slice = bytes.as_slice();
+        let decoded = decode_transform(&mut slice, bounds).unwrap();
+        assert!(slice.is_empty());
+        (decoded, bytes.len())
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(value, &mut bytes);
+            let mut slice = bytes.as_slice();
+            assert_eq!(read_varint(&mut slice).unwrap(), value);
+            assert!(slice.is_empty());
+        }
+        let mut bytes = Vec::new();
+        write_varint(100, &mut bytes);
+        assert_eq!(bytes.len(), 1);
+        assert!(read_varint(&mut [0x80u8, 0x80].as_slice()).is_err());
+        assert!(read_varint(&mut [0xffu8; 11].as_slice()).is_err());
+    }
+
+    #[test]
+    fn scale_is_only_sent_when_not_one() {
+        let unscaled = Transform::from_position(Vec3::new(3.0, 1.0, 4.0));
+        assert_eq!(round_trip(&unscaled).1, MIN_ENCODED_SIZE);
+        let scaled = Transform::new(Vec3::new(3.0, 1.0, 4.0), Vec3::splat(2.0), Quat::IDENTITY);
+        let (decoded, size) = round_trip(&scaled);
+        assert_eq!(size, MIN_ENCODED_SIZE + 12);
+        let (scale, _, _) = decoded.matrix.to_scale_rotation_translation();
+        assert!((scale - Vec3::splat(2.0)).abs().max_element() < 1e-5);
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_map() {
+        let bounds = MapBounds::default();
+        let outside = Transform::from_position(Vec3::new(-5.0, 0.0, bounds.size.y + 10.0));
+        let (decoded, _) = round_trip(&outside);
+        assert_eq!(decoded.matrix.translation.x, 0.0);
+        assert_eq!(decoded.matrix.translation.z, bounds.size.y);
+    }
+
+    #[test]
+    fn bounds_follow_the_map_transform() {
+        let transform = Transform::new(
+            Vec3::new(-20.0, 0.0, 100.0),
+            Vec3::splat(2.0),
+            Quat::IDENTITY,
+        );
+        let bounds = MapBounds::of(&TileMap::new("moved".to_string(), transform));
+        let default = MapBounds::default();
+        assert_eq!(bounds.min, Vec2::new(-20.0, 100.0));
+        assert_eq!(bounds.size, default.size * 2.0);
+        assert_eq!(
+            MapBounds::of(&TileMap::new("default".to_string(), Transform::default())),
+            default
+        );
+        // Positions of the moved map survive the round trip instead of being clamped
+        let position = Vec3::new(-10.0, 0.0, 150.0);
+        let (decoded, _) = round_trip_within(&Transform::from_position(position), &bounds);
+        let error = (decoded.matrix.translation - Vec3A::from(position)).abs();
+        let max_error = bounds.max_position_error();
+        assert!(error.x <= max_error.x + 1e-4 && error.z <= max_error.y + 1e-4);
+        assert!(bounds.contains(position.into()));
+        assert!(!default.contains(position.into()));
+    }
+
+    #[test]
+    fn truncated_transform_is_an_error() {
+        let mut bytes = Vec::new();
+        let bounds = MapBounds::default();
+        encode_transform(&Transform::default(), &bounds, &mut bytes);
+        assert!(decode_transform(&mut &bytes[..bytes.len() - 1], &bounds).is_err());
+        bytes[0] = 0x80;
+        assert!(decode_transform(&mut bytes.as_slice(), &bounds).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_error_is_bounded(
+            x in 0.0..CHUNK_SIZE as f32 * TILE_WIDTH,
+            y in -8.0f32..8.0,
+            z in 0.0..CHUNK_SIZE as f32 * TILE_HEIGHT,
+            yaw in -std::f32::consts::PI..std::f32::consts::PI,
+        ) {
+            let direction = Vec3A::new(yaw.sin(), 0.0, yaw.cos());
+            let transform = Transform::new(Vec3::new(x, y, z), Vec3::ONE, look_at(direction));
+            let (decoded, _) = round_trip(&transform);
+            let error = (decoded.matrix.translation - transform.matrix.translation).abs();
+            // Float rounding in the conversions adds a tiny bit on top of the quantization
+            let max_error = MapBounds::default().max_position_error();
+            prop_assert!(error.x <= max_error.x + 1e-4);
+            prop_assert!(error.z <= max_error.y + 1e-4);
+            prop_assert!(error.y <= 0.5 / HEIGHT_STEPS + 1e-4);
+            // look_at snaps directions almost opposite to +Z, compare with its result
+            let forward = transform.matrix.matrix3.z_axis;
+            let angle = forward.dot(decoded.matrix.matrix3.z_axis).clamp(-1.0, 1.0).acos();
+            prop_assert!(angle <= MAX_YAW_ERROR + 1e-3, "angle error: {}", angle);
+        }
+
+        #[test]
+        fn decoding_random_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = decode_transform(&mut bytes.as_slice(), &MapBounds::default());
+            let _ = read_varint(&mut bytes.as_slice());
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Runs a server and simulated clients in the test process over loopback sockets. The clients
+//! do the same handshake as the real ones, except that they never load the map, and keep the
+//! replicated state of the world so tests can script orders and check what the clients see.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use glam::{Affine3A, Vec3A};
+use laminar::{Config, Packet, SocketEvent};
+use legion::*;
+use unnamed_rts::{
+    commands::CommandBatcher,
+    components::{Owner, PlayerId, Transform},
+    game_time::GameTime,
+    lockstep::NetworkMode,
+    resources::{
+        ClientRole, ClientUpdate, Command, NetworkSerialization, NetworkSocket, ServerNotice,
+        ServerUpdate, SessionToken,
+    },
+    server::{AdminCommand, Server, ServerConfig, ServerMap, SessionCommand, SessionId},
+    tilemap::{LoadableMap, TileMap},
+    transfer::IncomingTransfers,
+    transform_encoding::{quantize, MapBounds},
+};
+
+/// Time a test waits for something to happen before failing
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A flat map generated in memory
+/// The map has the default transform, so positions are quantized within `MapBounds::default()`
+pub fn flat_map() -> (ServerMap, TileMap) {
+    let tilemap = TileMap::new("test".to_string(), Transform::default());
+    let bytes = bincode::serialize(&LoadableMap::new(&tilemap, Vec::new())).unwrap();
+    ServerMap::from_bytes(Some("test.map".to_string()), bytes).unwrap()
+}
+
+/// Server config for a match of the given number of players on a random local port. A
+/// snapshot is sent every tick so the clients see the state of every tick.
+pub fn test_config(players: u8) -> ServerConfig {
+    ServerConfig {
+        bind: [127, 0, 0, 1].into(),
+        port: 0,
+        players,
+        snapshot_rate: 60,
+        tick_rate: 60,
+        network_stats_interval: 0,
+        ..ServerConfig::default()
+    }
+}
+
+/// A client that has joined a match
+pub struct TestClient {
+    pub name: String,
+    socket: NetworkSocket,
+    server_addr: SocketAddr,
+    net_serialization: NetworkSerialization,
+    transfers: IncomingTransfers,
+    batcher: Option<CommandBatcher>,
+    pub token: Option<SessionToken>,
+    /// None for spectators and until the match has started
+    pub player: Option<PlayerId>,
+    pub joined: bool,
+    /// Holds back the MapReady, like a client that is still downloading the map
+    pub downloading_map: bool,
+    /// The replicated world, None until it has arrived
+    pub world: Option<World>,
+    /// Tick of the last state that was applied to the world
+    pub tick: u64,
+    /// The match clock of the last state
+    pub game_time: GameTime,
+    pub notices: Vec<ServerNotice>,
+}
+
+impl TestClient {
+    pub fn connect(name: &str, role: ClientRole, server_addr: SocketAddr) -> Self {
+        // The clients send nothing but orders, the heartbeats keep them connected
+        let config = Config {
+            heartbeat_interval: Some(Duration::from_millis(1000)),
+            ..Default::default()
+        };
+        let socket = NetworkSocket::bind_localhost_with_config(config, None);
+        let client = TestClient {
+            name: name.to_string(),
+            socket,
+            server_addr,
+            net_serialization: NetworkSerialization::default(),
+            transfers: IncomingTransfers::default(),
+            batcher: None,
+            token: None,
+            player: None,
+            joined: false,
+            downloading_map: false,
+            world: None,
+            tick: 0,
+            game_time: GameTime::default(),
+            notices: Vec::new(),
+        };
+        client.send(
+            &ClientUpdate::StartGame {
+                name: name.to_string(),
+                role,
+            },
+            true,
+        );
+        client
+    }
+
+    /// Sends the update with the client's token once it has been welcomed
+    pub fn send(&self, update: &ClientUpdate, reliable: bool) {
+        self.send_with_token(self.token, update, reliable);
+    }
+
+    pub fn send_with_token(
+        &self,
+        token: Option<SessionToken>,
+        update: &ClientUpdate,
+        reliable: bool,
+    ) {
+        let payload = match token {
+            Some(token) => self
+                .net_serialization
+                .serialize_session_update(token, update),
+            None => self.net_serialization.serialize_client_update(update),
+        };
+        self.send_payload(payload, reliable);
+    }
+
+    fn send_payload(&self, payload: Vec<u8>, reliable: bool) {
+        let packet = if reliable {
+            Packet::reliable_unordered(self.server_addr, payload)
+        } else {
+            Packet::unreliable(self.server_addr, payload)
+        };
+        self.socket.sender.send(packet).unwrap();
+    }
+
+    /// Handles the packets that have arrived and sends the queued orders
+    pub fn poll(&mut self) {
+        while let Ok(event) = self.socket.receiver.try_recv() {
+            match event {
+                SocketEvent::Packet(packet) => {
+                    let update = self
+                        .net_serialization
+                        .deserialize_server_update(packet.payload())
+                        .unwrap_or_else(|err| panic!("{} got a bad packet: {}", self.name, err));
+                    self.handle(update);
+                }
+                SocketEvent::Timeout(_) | SocketEvent::Disconnect(_) => {
+                    panic!("{} lost the connection to the server", self.name)
+                }
+                SocketEvent::Connect(_) => {}
+            }
+        }
+        if let (Some(batcher), Some(token)) = (self.batcher.as_mut(), self.token) {
+            let net_serialization = &self.net_serialization;
+            let payloads = batcher.flush(Instant::now(), |batch| {
+                net_serialization.serialize_session_update(token, batch)
+            });
+            for payload in payloads {
+                self.send_payload(payload, false);
+            }
+        }
+    }
+
+    fn handle(&mut self, update: ServerUpdate) {
+        match update {
+            ServerUpdate::Welcome { token } => {
+                self.token = Some(token);
+                self.batcher = Some(CommandBatcher::default());
+            }
+            // The map is never loaded
+            ServerUpdate::MapInfo { .. } if !self.downloading_map => {
+                self.send(&ClientUpdate::MapReady, true)
+            }
+            ServerUpdate::MapInfo { .. } => {}
+            ServerUpdate::InitialState {
+                player,
+                network_mode,
+            } => {
+                assert_eq!(network_mode, NetworkMode::Snapshots);
+                self.player = player;
+                self.joined = true;
+            }
+            ServerUpdate::Transfer(chunk) => {
+                let (ack, data) = self.transfers.receive(chunk).unwrap();
+                if let Some(ack) = ack {
+                    self.send(&ClientUpdate::TransferAck(ack), true);
+                }
+                if let Some(data) = data {
+                    match self
+                        .net_serialization
+                        .deserialize_server_update(&data)
+                        .unwrap()
+                    {
+                        ServerUpdate::World { world } => {
+                            self.world = Some(
+                                self.net_serialization
+                                    .deserialize_new_world(&world)
+                                    .unwrap(),
+                            )
+                        }
+                        update => panic!("{} got an unexpected transfer: {:?}", self.name, update),
+                    }
+                }
+            }
+            ServerUpdate::State {
+                tick,
+                game_time,
+                transforms,
+                ..
+            } => {
+                if let Some(batcher) = self.batcher.as_mut() {
+                    batcher.set_server_tick(tick);
+                }
+                // States from before the world arrived are already part of it
+                let world = match self.world.as_mut() {
+                    Some(world) if tick > self.tick => world,
+                    _ => return,
+                };
+                let transforms = self
+                    .net_serialization
+                    .decode_transforms(&transforms, &MapBounds::default())
+                    .unwrap();
+                for (entity, transform) in transforms {
+                    if let Ok(mut entry) = world.entry_mut(entity) {
+                        *entry.get_component_mut::<Transform>().unwrap() = transform;
+                    }
+                }
+                self.tick = tick;
+                self.game_time.synchronize(&game_time);
+            }
+            ServerUpdate::Spawned { entities } => {
+                let world = self.world.as_mut().expect("Spawned before the world");
+                for state in entities {
+                    world.push_with_id(state.entity, (state.entity_type, state.transform));
+                    if let Some(owner) = state.owner {
+                        world.entry(state.entity).unwrap().add_component(owner);
+                    }
+                }
+            }
+            ServerUpdate::CommandsAck { sequence } => {
+                if let Some(batcher) = self.batcher.as_mut() {
+                    batcher.acknowledge(sequence);
+                }
+            }
+            ServerUpdate::Notice(notice) => self.notices.push(notice),
+            _ => {}
+        }
+    }
+
+    /// Queues a move order, it's sent with the next poll
+    pub fn order_move(&mut self, entity: Entity, target: Vec3A) {
+        self.batcher
+            .as_mut()
+            .expect("Ordered before the welcome")
+            .push(Command::Move { entity, target });
+    }
+
+    /// The client's view of the units of every player
+    pub fn units(&self) -> HashMap<PlayerId, Vec<(Entity, Transform)>> {
+        let mut units: HashMap<PlayerId, Vec<(Entity, Transform)>> = HashMap::new();
+        if let Some(world) = &self.world {
+            for (entity, owner, transform) in <(Entity, &Owner, &Transform)>::query().iter(world) {
+                units
+                    .entry(owner.player)
+                    .or_default()
+                    .push((*entity, *transform));
+            }
+        }
+        units
+    }
+
+    /// The first unit of the client's player
+    pub fn own_unit(&self) -> (Entity, Transform) {
+        let player = self.player.expect("Spectators don't own units");
+        self.units()[&player][0]
+    }
+}
+
+/// A server with its clients, stepped together on the test thread
+pub struct Harness {
+    pub server: Server,
+    pub clients: Vec<TestClient>,
+}
+
+impl Harness {
+    pub fn new(config: ServerConfig) -> Self {
+        let (server_map, tilemap) = flat_map();
+        Harness {
+            server: Server::with_map(config, server_map, tilemap).unwrap(),
+            clients: Vec::new(),
+        }
+    }
+
+    /// Starts a match with the given number of players and waits until they have the world
+    pub fn with_players(players: u8) -> Self {
+        let mut harness = Harness::new(test_config(players));
+        for player in 0..players {
+            harness.connect(&format!("Player {}", player), ClientRole::Player);
+        }
+        harness.run_until("the players have the world", |harness| {
+            harness.clients.iter().all(|client| client.world.is_some())
+        });
+        harness
+    }
+
+    /// Returns the index of the client
+    pub fn connect(&mut self, name: &str, role: ClientRole) -> usize {
+        let client = TestClient::connect(name, role, self.server.local_addr());
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    /// Runs the server until it's idle or a tick is due and lets the clients handle
+    /// what they received
+    pub fn step(&mut self) {
+        self.server.update();
+        self.clients.iter_mut().for_each(TestClient::poll);
+    }
+
+    /// Steps until the condition holds, panics after TIMEOUT
+    pub fn run_until(&mut self, description: &str, condition: impl Fn(&Harness) -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition(self) {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting until {}",
+                description
+            );
+            self.step();
+        }
+    }
+
+    /// Steps until every client in the match has seen the state of `ticks` more ticks
+    pub fn run_ticks(&mut self, ticks: u64) {
+        let joined = |client: &&TestClient| client.world.is_some();
+        let target = self
+            .clients
+            .iter()
+            .filter(joined)
+            .map(|client| client.tick)
+            .max();
+        let target = target.unwrap_or(0) + ticks;
+        self.run_until(&format!("tick {}", target), |harness| {
+            harness
+                .clients
+                .iter()
+                .filter(joined)
+                .all(|client| client.tick >= target)
+        });
+    }
+
+    /// Pauses the simulation and waits until the clients have the state of the last tick,
+    /// after which the clients' state can be compared with the server's
+    pub fn pause(&mut self, session: SessionId) {
+        self.server.run_admin_command(AdminCommand::Select(session));
+        self.server
+            .run_admin_command(AdminCommand::Session(SessionCommand::Pause));
+        let tick = self.server.session(session).unwrap().tick();
+        self.run_until("the clients have the last tick", |harness| {
+            harness
+                .clients
+                .iter()
+                .filter(|client| client.world.is_some())
+                .all(|client| client.tick == tick)
+        });
+    }
+
+    /// The server's units of the player in the session, as the clients see them
+    pub fn server_units(&self, session: SessionId, player: PlayerId) -> Vec<Affine3A> {
+        let world = self.server.session(session).unwrap().world();
+        <(&Owner, &Transform)>::query()
+            .iter(world)
+            .filter(|(owner, _)| owner.player == player)
+            .map(|(_, transform)| quantize(transform, &MapBounds::default()).matrix)
+            .collect()
+    }
+}
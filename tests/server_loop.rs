@@ -0,0 +1,288 @@
+//! Tests of the client server loop, run with `cargo test --no-default-features`
+//! to leave out the graphics
+mod harness;
+
+use glam::{Vec2, Vec3, Vec3A};
+use harness::{test_config, Harness};
+use unnamed_rts::{
+    components::EntityType,
+    relevancy::AreaOfInterest,
+    resources::{ClientRole, ClientUpdate, Command, ServerNotice},
+    server::{AdminCommand, SessionCommand, SessionPhase},
+};
+
+#[test]
+fn players_join_and_get_the_world() {
+    let harness = Harness::with_players(2);
+    let session = harness.server.session(0).unwrap();
+    assert_eq!(session.phase(), SessionPhase::InGame);
+    let mut players: Vec<_> = harness
+        .clients
+        .iter()
+        .map(|client| client.player.unwrap())
+        .collect();
+    players.sort_unstable();
+    assert_eq!(players, vec![0, 1]);
+    for client in &harness.clients {
+        let units = client.units();
+        assert_eq!(units.len(), 2, "{} sees every player's units", client.name);
+        assert_eq!(units[&client.player.unwrap()].len(), 1);
+    }
+    let tokens: Vec<_> = harness.clients.iter().map(|client| client.token).collect();
+    assert_ne!(tokens[0], tokens[1]);
+}
+
+#[test]
+fn move_orders_are_replicated_to_every_client() {
+    let mut harness = Harness::with_players(2);
+    harness.run_ticks(5);
+    let (entity, start) = harness.clients[0].own_unit();
+    let start = start.matrix.translation;
+    let player = harness.clients[0].player.unwrap();
+    let target = Vec3A::new(20.0, 0.0, 20.0);
+    harness.clients[0].order_move(entity, target);
+    harness.run_ticks(60);
+
+    let distance = |translation: Vec3A| translation.distance(target);
+    let moved = harness.clients[0].own_unit().1.matrix.translation;
+    assert!(
+        distance(moved) < distance(start) - 1.0,
+        "The unit moved from {} to {}",
+        start,
+        moved
+    );
+    // The other player's unit stays in place
+    let other = harness.clients[1].own_unit();
+    harness.run_ticks(5);
+    assert_eq!(harness.clients[1].own_unit().1.matrix, other.1.matrix);
+
+    harness.pause(0);
+    let server_units = harness.server_units(0, player);
+    for client in &harness.clients {
+        let seen: Vec<_> = client.units()[&player]
+            .iter()
+            .map(|(_, transform)| transform.matrix)
+            .collect();
+        assert_eq!(
+            seen, server_units,
+            "{} sees the server's state",
+            client.name
+        );
+    }
+}
+
+#[test]
+fn the_match_clock_is_scaled_and_replicated() {
+    let mut harness = Harness::with_players(1);
+    harness.run_ticks(5);
+    harness.server.run_admin_command(AdminCommand::Select(0));
+    harness
+        .server
+        .run_admin_command(AdminCommand::Session(SessionCommand::Speed(2.0)));
+    harness.run_ticks(30);
+    harness.pause(0);
+    let server_time = harness.server.session(0).unwrap().game_time();
+    assert!(server_time.is_paused());
+    assert_eq!(server_time.scale(), 2.0);
+    // The ticks at double speed count twice, the clients may lag a few ticks behind
+    // the server so not all of the 30 ticks waited for ran after the change
+    let tick_rate = test_config(1).tick_rate as f64;
+    assert!(server_time.elapsed_seconds() > (server_time.tick() + 20) as f64 / tick_rate);
+    let client_time = harness.clients[0].game_time;
+    assert_eq!(client_time.tick(), server_time.tick());
+    assert_eq!(client_time.elapsed(), server_time.elapsed());
+    assert_eq!(client_time.scale(), 2.0);
+
+    // The clock stands still while paused
+    for _ in 0..10 {
+        harness.step();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    assert_eq!(harness.server.session(0).unwrap().game_time(), server_time);
+}
+
+#[test]
+fn updates_with_the_wrong_token_are_rejected() {
+    let mut harness = Harness::with_players(1);
+    let (entity, start) = harness.clients[0].own_unit();
+    let token = harness.clients[0].token.unwrap();
+    let orders = ClientUpdate::ClientCommands {
+        tick: 0,
+        sequence: 0,
+        commands: vec![Command::Move {
+            entity,
+            target: Vec3A::new(20.0, 0.0, 20.0),
+        }],
+    };
+    harness.clients[0].send_with_token(Some(token.wrapping_add(1)), &orders, true);
+    harness.clients[0].send_with_token(None, &orders, true);
+    harness.run_ticks(30);
+    assert_eq!(harness.clients[0].own_unit().1.matrix, start.matrix);
+}
+
+#[test]
+fn spectators_can_join_a_running_match() {
+    let mut harness = Harness::with_players(1);
+    let (entity, _) = harness.clients[0].own_unit();
+    harness.clients[0].order_move(entity, Vec3A::new(20.0, 0.0, 20.0));
+    harness.run_ticks(30);
+    let spectator = harness.connect("Spectator", ClientRole::Spectator);
+    harness.run_until("the spectator has the world", |harness| {
+        harness.clients[spectator].world.is_some()
+    });
+    assert_eq!(harness.clients[spectator].player, None);
+    // The world doesn't say which tick it's from
+    harness.run_ticks(5);
+    harness.pause(0);
+    let seen: Vec<_> = harness.clients[spectator].units()[&0]
+        .iter()
+        .map(|(_, transform)| transform.matrix)
+        .collect();
+    assert_eq!(seen, harness.server_units(0, 0));
+}
+
+#[test]
+fn players_are_refused_once_every_session_has_started() {
+    let mut config = test_config(1);
+    config.max_sessions = 1;
+    let mut harness = Harness::new(config);
+    harness.connect("Player", ClientRole::Player);
+    harness.run_until("the match has started", |harness| {
+        harness.clients[0].world.is_some()
+    });
+    let late = harness.connect("Late player", ClientRole::Player);
+    harness.run_until("the late player is refused", |harness| {
+        !harness.clients[late].notices.is_empty()
+    });
+    assert!(matches!(
+        harness.clients[late].notices[0],
+        ServerNotice::Kicked { .. }
+    ));
+    assert!(!harness.clients[late].joined);
+}
+
+#[test]
+fn spectators_getting_the_map_do_not_hold_back_the_start() {
+    let mut config = test_config(1);
+    config.max_spectators = 1;
+    let mut harness = Harness::new(config);
+    let player = harness.connect("Player", ClientRole::Player);
+    harness.clients[player].downloading_map = true;
+    harness.run_until("the lobby is open", |harness| {
+        harness.clients[player].token.is_some()
+    });
+    let spectator = harness.connect("Spectator", ClientRole::Spectator);
+    harness.clients[spectator].downloading_map = true;
+    harness.run_until("the spectator is in the lobby", |harness| {
+        harness.clients[spectator].token.is_some()
+    });
+    harness.clients[player].send(&ClientUpdate::MapReady, true);
+    harness.run_until("the match has started", |harness| {
+        harness.clients[player].world.is_some()
+    });
+    assert!(!harness.clients[spectator].joined);
+    // The match is full of spectators
+    let refused = harness.connect("Second spectator", ClientRole::Spectator);
+    harness.run_until("the second spectator is refused", |harness| {
+        !harness.clients[refused].notices.is_empty()
+    });
+    assert!(matches!(
+        harness.clients[refused].notices[0],
+        ServerNotice::Kicked { .. }
+    ));
+    // Once it has the map the spectator joins the running match
+    harness.clients[spectator].send(&ClientUpdate::MapReady, true);
+    harness.run_until("the spectator has the world", |harness| {
+        harness.clients[spectator].world.is_some()
+    });
+}
+
+#[test]
+fn spectators_are_kicked_when_they_take_too_long_to_get_the_map() {
+    let mut config = test_config(1);
+    config.spectator_map_timeout = 0;
+    let mut harness = Harness::new(config);
+    let player = harness.connect("Player", ClientRole::Player);
+    harness.clients[player].downloading_map = true;
+    harness.run_until("the lobby is open", |harness| {
+        harness.clients[player].token.is_some()
+    });
+    let spectator = harness.connect("Spectator", ClientRole::Spectator);
+    harness.clients[spectator].downloading_map = true;
+    harness.run_until("the spectator is kicked", |harness| {
+        !harness.clients[spectator].notices.is_empty()
+    });
+    assert!(matches!(
+        harness.clients[spectator].notices[0],
+        ServerNotice::Kicked { .. }
+    ));
+    // Players are waited for
+    assert!(harness.clients[player].notices.is_empty());
+    assert_eq!(
+        harness.server.session(0).unwrap().phase(),
+        SessionPhase::Lobby
+    );
+}
+
+#[test]
+fn spawned_entities_are_sent_once_they_become_relevant() {
+    let mut harness = Harness::with_players(2);
+    let far_player = harness.clients[0].player.unwrap();
+    let owner = harness.clients[1].player.unwrap();
+    harness.server.run_admin_command(AdminCommand::Select(0));
+    harness
+        .server
+        .run_admin_command(AdminCommand::Session(SessionCommand::Spawn {
+            entity_type: EntityType::BasicUnit,
+            position: Vec3::new(60.0, 0.0, 60.0),
+            owner: Some(owner),
+        }));
+    harness.run_until("the owner has the spawned unit", |harness| {
+        harness.clients[1].units()[&owner].len() == 2
+    });
+    harness.run_ticks(5);
+    assert_eq!(harness.clients[0].units()[&owner].len(), 1);
+    // Looking at the unit makes it relevant to the other player
+    harness.clients[0].send(
+        &ClientUpdate::AreaOfInterest(AreaOfInterest {
+            min: Vec2::new(50.0, 50.0),
+            max: Vec2::new(70.0, 70.0),
+        }),
+        true,
+    );
+    harness.run_until("the other player has the spawned unit", |harness| {
+        harness.clients[0].units()[&owner].len() == 2
+    });
+    assert_eq!(harness.clients[0].units()[&far_player].len(), 1);
+}
+
+#[test]
+fn clients_without_a_name_are_refused() {
+    let mut harness = Harness::new(test_config(1));
+    let nameless = harness.connect(" \n", ClientRole::Player);
+    harness.run_until("the client is refused", |harness| {
+        !harness.clients[nameless].notices.is_empty()
+    });
+    assert!(matches!(
+        harness.clients[nameless].notices[0],
+        ServerNotice::Kicked { .. }
+    ));
+    assert!(harness.server.session(0).is_none());
+}
+
+#[test]
+fn spectators_joining_a_paused_match_are_told_it_is_paused() {
+    let mut harness = Harness::with_players(1);
+    harness.pause(0);
+    let spectator = harness.connect("Spectator", ClientRole::Spectator);
+    harness.run_until("the spectator has the world", |harness| {
+        harness.clients[spectator].world.is_some()
+    });
+    harness.run_until("the spectator knows about the pause", |harness| {
+        !harness.clients[spectator].notices.is_empty()
+    });
+    assert_eq!(
+        harness.clients[spectator].notices,
+        vec![ServerNotice::Paused]
+    );
+}